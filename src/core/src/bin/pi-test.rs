@@ -161,6 +161,7 @@ fn mk_snapshot(location: Location, dimensions: Location) -> (DropletId, Snapshot
     let snapshot = Snapshot {
         droplets: droplets,
         commands_to_finalize: vec![],
+        holds: vec![],
     };
     (id, snapshot)
 }
@@ -0,0 +1,115 @@
+extern crate env_logger;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+extern crate structopt;
+
+extern crate puddle_core;
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter};
+
+use structopt::StructOpt;
+
+use puddle_core::util::{collections::Map, seconds_duration};
+use puddle_core::{DropletId, DropletInfo, Grid, Manager, Protocol, Step};
+
+/// Runs a protocol on a simulated board, without starting a server.
+/// Exits with an error if any step of the protocol fails.
+#[derive(StructOpt)]
+struct PuddleRun {
+    #[structopt(long = "arch")]
+    arch_file: String,
+    /// Either a list of steps to run in the order they're listed, or
+    /// `{"steps": [...]}` to plan them all at once like `submit_protocol`
+    protocol_file: String,
+    /// Writes the droplets at every tick to this file as JSON
+    #[structopt(long)]
+    trace: Option<String>,
+    /// Seconds a command may wait for room on the board before failing.
+    /// Without it, commands that can't be placed fail right away.
+    #[structopt(long = "queue-timeout")]
+    queue_timeout: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum ProtocolFile {
+    Listed(Vec<Step>),
+    Dag(Protocol),
+}
+
+macro_rules! exit {
+    ($($arg:tt)*) => ({
+        eprintln!($($arg)*);
+        ::std::process::exit(1);
+    })
+}
+
+impl PuddleRun {
+    fn run(&self) -> Result<(), Box<::std::error::Error>> {
+        let grid = Grid::from_reader(File::open(&self.arch_file)?)?;
+        let reader = BufReader::new(File::open(&self.protocol_file)?);
+        let protocol_file: ProtocolFile = serde_json::from_reader(reader)?;
+
+        let manager = Manager::new(false, grid);
+        manager.set_queue_timeout(self.queue_timeout.map(seconds_duration));
+        let p = manager.get_new_process("puddle-run");
+
+        let result = match protocol_file {
+            ProtocolFile::Listed(steps) => p.run_protocol(&Protocol { steps }),
+            ProtocolFile::Dag(protocol) => p
+                .submit_protocol(protocol)
+                .and_then(|report| p.wait_protocol(report.handle).map(|_| report.droplets)),
+        };
+        // flush even if a step failed, to see what the steps before it did
+        let droplets = match p.flush() {
+            Ok(droplets) => droplets,
+            Err(e) => exit!("error: couldn't flush: {:?}", e),
+        };
+        let names = result.as_ref().ok().cloned().unwrap_or_default();
+
+        let gv = manager.gridview();
+        println!("ticks: {}", gv.now());
+        println!("droplets: {}", droplets.len());
+        for d in &droplets {
+            print_droplet(d, &names);
+        }
+
+        if let Some(path) = &self.trace {
+            let trace: Vec<Vec<DropletInfo>> = gv
+                .completed()
+                .iter()
+                .map(|snapshot| snapshot.droplet_info(None))
+                .collect();
+            serde_json::to_writer(BufWriter::new(File::create(path)?), &trace)?;
+        }
+
+        if let Err(e) = result {
+            exit!("error: {:?}", e);
+        }
+        Ok(())
+    }
+}
+
+fn print_droplet(d: &DropletInfo, names: &Map<String, DropletId>) {
+    let name = names
+        .iter()
+        .find(|(_, id)| **id == d.id)
+        .map_or("-", |(name, _)| name.as_str());
+    println!(
+        "  {} (id {}): volume {}, at {}, dimensions {}",
+        name, d.id.id, d.volume, d.location, d.dimensions
+    );
+}
+
+fn main() {
+    // enable logging
+    let _ = env_logger::try_init();
+
+    let run = PuddleRun::from_args();
+
+    if let Err(e) = run.run() {
+        exit!("error: {}", e);
+    }
+}
@@ -12,7 +12,7 @@ use jsonrpc_core::{futures::Future, IoHandler};
 use jsonrpc_http_server::{hyper, RequestMiddlewareAction, Response, ServerBuilder};
 use structopt::StructOpt;
 
-use puddle_core::{Grid, Manager, Rpc};
+use puddle_core::{util::seconds_duration, Grid, Manager, Rpc};
 
 #[derive(StructOpt)]
 struct PuddleServer {
@@ -24,6 +24,12 @@ struct PuddleServer {
     should_sync: bool,
     #[structopt(long = "arch")]
     arch_file: String,
+    /// Seconds a command may wait for room on the board before failing.
+    /// Without it, commands that can't be placed fail right away.
+    #[structopt(long = "queue-timeout")]
+    queue_timeout: Option<f64>,
+    #[structopt(long, default_value = "4")]
+    threads: usize,
 }
 
 macro_rules! exit {
@@ -52,6 +58,7 @@ impl PuddleServer {
 
         let grid = Grid::from_reader(reader)?;
         let manager = Manager::new(should_sync, grid);
+        manager.set_queue_timeout(self.queue_timeout.map(seconds_duration));
         let arc = Arc::new(manager);
 
         #[cfg(feature = "pi")]
@@ -63,7 +70,9 @@ impl PuddleServer {
         let mut io = IoHandler::new();
         io.extend_with(arc.to_delegate());
 
+        // queued commands block their request, so serve others alongside them
         let server = ServerBuilder::new(io)
+            .threads(self.threads)
             .request_middleware(
                 move |request: hyper::Request<hyper::Body>| -> RequestMiddlewareAction {
                     if request.uri() == "/status" {
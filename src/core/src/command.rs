@@ -4,19 +4,25 @@ use std::sync::mpsc::Sender;
 use std::thread;
 use std::time::Duration;
 
-use plan::PlanError;
+use exec::tick_duration;
+use plan::{PlaceRequest, PlanError, Transform};
+use util::{duration_seconds, seconds_duration};
 
 #[cfg(feature = "pi")]
 use pi::RaspberryPi;
 
 use grid::{
     droplet::{Blob, SimpleBlob},
-    Droplet, DropletId, DropletInfo, Grid, Location, Peripheral, Snapshot,
+    Droplet, DropletId, DropletInfo, Grid, Hold, HoldResult, HoldStage, Location, Peripheral,
+    SensorKind, Snapshot,
 };
 
-use process::{ProcessId, PuddleResult};
+use process::{ProcessId, PuddleError, PuddleResult};
 
 pub trait Command: fmt::Debug + Send {
+    // What this kind of command is called, e.g. by keep-out regions in the
+    // arch file that only let certain commands in
+    fn name(&self) -> &'static str;
     fn input_droplets(&self) -> Vec<DropletId> {
         vec![]
     }
@@ -26,6 +32,15 @@ pub trait Command: fmt::Debug + Send {
     fn bypass(&self, _gridview: &GridView) -> bool {
         false
     }
+    // The process that issued this command. By default this is figured out
+    // from the droplets, so only commands without any need to override it.
+    fn process_id(&self) -> Option<ProcessId> {
+        self.input_droplets()
+            .iter()
+            .chain(self.output_droplets().iter())
+            .map(|id| id.process_id)
+            .next()
+    }
     // FIXME this shouldn't be mut, but we need to set the collision groups in mix
     fn request(&self, &mut GridView) -> CommandRequest;
 
@@ -59,6 +74,7 @@ pub struct Create {
     location: Location,
     dimensions: Location,
     volume: f64,
+    substance: Option<String>,
     trusted: bool,
 }
 
@@ -67,6 +83,23 @@ pub struct CommandRequest {
     pub shape: Grid,
     pub input_locations: Vec<Location>,
     pub trusted: bool,
+    // The shape may always be mirrored or turned halfway around, but turning
+    // it a quarter turn swaps droplets' dimensions, so commands have to ask.
+    pub rotatable: bool,
+}
+
+impl CommandRequest {
+    pub fn transforms(&self) -> Vec<Transform> {
+        if self.rotatable {
+            Transform::all()
+        } else {
+            Transform::preserving_axes()
+        }
+    }
+}
+
+fn is_square(dim: &Location) -> bool {
+    dim.y == dim.x
 }
 
 // TODO: dimensions probably shouldn't be optional?
@@ -83,12 +116,23 @@ impl Create {
             location: loc.unwrap_or(Location { y: 0, x: 0 }),
             dimensions: dim.unwrap_or(Location { y: 1, x: 1 }),
             volume: vol,
+            substance: None,
             trusted: loc.is_some(),
         })
     }
+
+    // the created droplet is entirely made of the given substance
+    pub fn labeled(mut self, substance: String) -> Create {
+        self.substance = Some(substance);
+        self
+    }
 }
 
 impl Command for Create {
+    fn name(&self) -> &'static str {
+        "create"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -104,16 +148,16 @@ impl Command for Create {
             shape: grid,
             input_locations: vec![],
             trusted: self.trusted,
+            rotatable: is_square(&self.dimensions),
         }
     }
 
     fn run(&mut self, gridview: &mut GridSubView) {
-        gridview.insert(Droplet::new(
-            self.outputs[0],
-            self.volume,
-            self.location,
-            self.dimensions,
-        ));
+        let mut d = Droplet::new(self.outputs[0], self.volume, self.location, self.dimensions);
+        if let Some(ref substance) = self.substance {
+            d.composition.insert(substance.clone(), self.volume);
+        }
+        gridview.insert(d);
         gridview.tick();
     }
 }
@@ -137,11 +181,20 @@ impl Flush {
 }
 
 impl Command for Flush {
+    fn name(&self) -> &'static str {
+        "flush"
+    }
+
+    fn process_id(&self) -> Option<ProcessId> {
+        Some(self.pid)
+    }
+
     fn request(&self, _gridview: &mut GridView) -> CommandRequest {
         CommandRequest {
             shape: Grid::rectangle(0, 0),
             input_locations: vec![],
             trusted: false,
+            rotatable: false,
         }
     }
 
@@ -188,6 +241,10 @@ impl Move {
 }
 
 impl Command for Move {
+    fn name(&self) -> &'static str {
+        "move"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -203,6 +260,7 @@ impl Command for Move {
             shape: Grid::rectangle(dim.y as usize, dim.x as usize),
             input_locations: vec![self.destination[0]],
             trusted: true,
+            rotatable: false,
         }
     }
 
@@ -217,6 +275,140 @@ impl Command for Move {
     }
 }
 
+//
+//  Park
+//
+
+/// Moves an idle droplet into storage, keeping its id. The planner makes
+/// these itself when idle droplets are in some other command's way.
+#[derive(Debug)]
+pub struct Park {
+    inputs: Vec<DropletId>,
+}
+
+impl Park {
+    /// What parking is called, for keep-out regions that let it in
+    pub const NAME: &'static str = "park";
+
+    pub fn new(id: DropletId) -> Park {
+        Park { inputs: vec![id] }
+    }
+}
+
+impl Command for Park {
+    fn name(&self) -> &'static str {
+        Park::NAME
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    // the process didn't ask for this, so it doesn't hold up its commands
+    fn process_id(&self) -> Option<ProcessId> {
+        None
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let dim = gridview.snapshot().droplets[&self.inputs[0]].dimensions;
+        CommandRequest {
+            shape: Grid::rectangle(dim.y as usize, dim.x as usize),
+            input_locations: vec![Location { y: 0, x: 0 }],
+            trusted: false,
+            rotatable: false,
+        }
+    }
+
+    fn run(&mut self, _gridview: &mut GridSubView) {
+        // getting to the spot is all there is to it
+    }
+}
+
+//
+//  Reshape
+//
+
+#[derive(Debug)]
+pub struct Reshape {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    dimensions: Location,
+}
+
+impl Reshape {
+    pub fn new(in_id: DropletId, dimensions: Location, out_id: DropletId) -> PuddleResult<Reshape> {
+        if dimensions.y < 1 || dimensions.x < 1 {
+            let msg = format!("can't reshape a droplet to {}", dimensions);
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        Ok(Reshape {
+            inputs: vec![in_id],
+            outputs: vec![out_id],
+            dimensions,
+        })
+    }
+}
+
+// one step from `from` towards `to`
+fn step_towards(from: i32, to: i32) -> i32 {
+    from + (to - from).signum()
+}
+
+impl Command for Reshape {
+    fn name(&self) -> &'static str {
+        "reshape"
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let droplets = &gridview.snapshot().droplets;
+        let dim = droplets.get(&self.inputs[0]).unwrap().dimensions;
+
+        // room for both the old shape and the new one, anchored at the corner
+        let h = dim.y.max(self.dimensions.y) as usize;
+        let w = dim.x.max(self.dimensions.x) as usize;
+        CommandRequest {
+            shape: Grid::rectangle(h, w),
+            input_locations: vec![Location { y: 0, x: 0 }],
+            trusted: false,
+            rotatable: is_square(&dim) && is_square(&self.dimensions),
+        }
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) {
+        let old_id = self.inputs[0];
+        let new_id = self.outputs[0];
+        let target = self.dimensions;
+
+        // stretch or squeeze by a cell at a time, the volume stays the same
+        let mut d = gridview.remove(&old_id);
+        while d.dimensions != target {
+            d.dimensions = Location {
+                y: step_towards(d.dimensions.y, target.y),
+                x: step_towards(d.dimensions.x, target.x),
+            };
+            gridview.insert(d.clone());
+            gridview.tick();
+            d = gridview.remove(&old_id);
+        }
+
+        d.id = new_id;
+        gridview.insert(d);
+        gridview.tick()
+    }
+}
+
 //
 //  Combine
 //
@@ -228,6 +420,86 @@ pub struct Combine {
     pin_d0: bool,
 }
 
+/// Which side of the first droplet the second one joins it from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Side {
+    Above,
+    Below,
+    Left,
+    Right,
+}
+
+// in order of preference when there's a tie, above first like it always was
+const SIDES: [Side; 4] = [Side::Above, Side::Below, Side::Left, Side::Right];
+
+// How two droplets line up before they're merged, relative to the top-left
+// corner of the box they fill together, which is also where the merged
+// droplet ends up
+struct CombineLayout {
+    frame: Location,
+    offset0: Location,
+    offset1: Location,
+}
+
+impl Side {
+    fn is_vertical(self) -> bool {
+        self == Side::Above || self == Side::Below
+    }
+
+    // the side `d1` is already on
+    fn between(d0: &Droplet, d1: &Droplet) -> Side {
+        let delta = &d1.location - &d0.location;
+        if delta.x.abs() > delta.y.abs() {
+            if delta.x < 0 {
+                Side::Left
+            } else {
+                Side::Right
+            }
+        } else if delta.y < 0 {
+            Side::Above
+        } else {
+            Side::Below
+        }
+    }
+
+    fn layout(self, dim0: &Location, dim1: &Location) -> CombineLayout {
+        let origin = Location { y: 0, x: 0 };
+        let (frame, offset0, offset1) = match self {
+            Side::Above | Side::Below => {
+                let frame = Location {
+                    y: dim0.y + dim1.y,
+                    x: dim0.x.max(dim1.x),
+                };
+                if self == Side::Above {
+                    (frame, Location { y: dim1.y, x: 0 }, origin)
+                } else {
+                    (frame, origin, Location { y: dim0.y, x: 0 })
+                }
+            }
+            Side::Left | Side::Right => {
+                let frame = Location {
+                    y: dim0.y.max(dim1.y),
+                    x: dim0.x + dim1.x,
+                };
+                if self == Side::Left {
+                    (frame, Location { y: 0, x: dim1.x }, origin)
+                } else {
+                    (frame, origin, Location { y: 0, x: dim0.x })
+                }
+            }
+        };
+        CombineLayout {
+            frame,
+            offset0,
+            offset1,
+        }
+    }
+}
+
+fn ceil_div(a: i32, b: i32) -> i32 {
+    (a + b - 1) / b
+}
+
 impl Combine {
     pub fn new(id1: DropletId, id2: DropletId, out_id: DropletId) -> PuddleResult<Combine> {
         Ok(Combine {
@@ -250,27 +522,140 @@ impl Combine {
         })
     }
 
-    fn combined(&self, d0: &Droplet, d1: &Droplet) -> SimpleBlob {
-        // FIXME this is a hack
-        // right now we only support vertical stacking
-        if self.pin_d0 {
-            assert!(d0.location.y > d1.dimensions.y);
-        }
+    // Cells go with volume, so the merged droplet covers as many cells as
+    // its volume needs, spread across the direction they were merged in.
+    // Going by the sparser input means it never needs more cells than the
+    // two inputs had, so it always fits in the layout's frame.
+    fn combined(&self, d0: &Droplet, d1: &Droplet, vertical: bool) -> SimpleBlob {
+        let area0 = d0.dimensions.y * d0.dimensions.x;
+        let area1 = d1.dimensions.y * d1.dimensions.x;
+        let cells_per_volume = (area0 as f64 / d0.volume).min(area1 as f64 / d1.volume);
+        let volume = d0.volume + d1.volume;
+        let cells = (volume * cells_per_volume).ceil().max(1.0) as i32;
+        // rounding can't make it outgrow the inputs
+        let area = cells.min(area0 + area1);
+        let dimensions = if vertical {
+            let x = d0.dimensions.x.max(d1.dimensions.x);
+            Location {
+                y: ceil_div(area, x),
+                x,
+            }
+        } else {
+            let y = d0.dimensions.y.max(d1.dimensions.y);
+            Location {
+                y,
+                x: ceil_div(area, y),
+            }
+        };
         SimpleBlob {
-            location: &d0.location - &Location {
-                y: d1.dimensions.y,
-                x: 0,
-            },
-            dimensions: Location {
-                y: (d0.dimensions.y + d1.dimensions.y),
-                x: d0.dimensions.x.max(d1.dimensions.x),
+            location: Location {
+                y: d0.location.y.min(d1.location.y),
+                x: d0.location.x.min(d1.location.x),
             },
-            volume: d0.volume + d1.volume,
+            dimensions,
+            volume,
+        }
+    }
+
+    // Where d1 should join the pinned d0: the side closest to d1 where the
+    // two of them fit on the board without touching anything else
+    fn pinned_side(&self, gridview: &GridView, d0: &Droplet, d1: &Droplet) -> (Side, Location) {
+        let others: Vec<&Droplet> = gridview
+            .snapshot()
+            .droplets
+            .values()
+            .filter(|d| d.id != d0.id && d.id != d1.id)
+            .collect();
+
+        let candidates = SIDES.iter().map(|&side| {
+            let layout = side.layout(&d0.dimensions, &d1.dimensions);
+            let origin = &d0.location - &layout.offset0;
+            (side, origin, layout)
+        });
+
+        let fits = |origin: &Location, layout: &CombineLayout| {
+            let frame = Droplet {
+                location: *origin,
+                dimensions: layout.frame,
+                ..d0.clone()
+            };
+            let on_grid = (0..layout.frame.y).all(|y| {
+                (0..layout.frame.x).all(|x| {
+                    gridview
+                        .grid
+                        .get_cell(&(origin + &Location { y, x }))
+                        .is_some()
+                })
+            });
+            on_grid && others.iter().all(|d| d.collision_distance(&frame) > 0)
+        };
+
+        let distance = |(_, origin, layout): &(Side, Location, CombineLayout)| {
+            let target = origin + &layout.offset1;
+            target.distance_to(&d1.location)
+        };
+
+        // if nothing fits, at least let d1 come from the closest side
+        let best = candidates
+            .clone()
+            .filter(|(_, origin, layout)| fits(origin, layout))
+            .min_by_key(&distance)
+            .or_else(|| candidates.min_by_key(&distance));
+        let (side, origin, layout) = best.unwrap();
+        (side, &origin + &layout.offset1)
+    }
+
+    // d1 joins d0 from the side it's already on, unless there isn't room
+    // for that right now but there is the other way around
+    fn free_side(&self, gridview: &GridView, d0: &Droplet, d1: &Droplet) -> Side {
+        let preferred = Side::between(d0, d1);
+        let other = if preferred.is_vertical() {
+            if d1.location.x < d0.location.x {
+                Side::Left
+            } else {
+                Side::Right
+            }
+        } else if d1.location.y < d0.location.y {
+            Side::Above
+        } else {
+            Side::Below
+        };
+
+        let mut snapshot = gridview.snapshot().new_with_same_droplets();
+        snapshot.droplets.remove(&d0.id);
+        snapshot.droplets.remove(&d1.id);
+
+        let fits = |side: Side| {
+            let layout = side.layout(&d0.dimensions, &d1.dimensions);
+            let shape = Grid::rectangle(layout.frame.y as usize, layout.frame.x as usize);
+            let inputs = [(d0.location, layout.offset0), (d1.location, layout.offset1)];
+            let req = PlaceRequest {
+                shape: &shape,
+                name: Some(self.name()),
+                transforms: &Transform::preserving_axes(),
+                inputs: &inputs,
+                target: None,
+                strategy: &*gridview.plan.strategy,
+            };
+            gridview
+                .grid
+                .place(&req, &snapshot, &gridview.bad_edges)
+                .is_some()
+        };
+
+        if !fits(preferred) && fits(other) {
+            other
+        } else {
+            preferred
         }
     }
 }
 
 impl Command for Combine {
+    fn name(&self) -> &'static str {
+        "combine"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -291,14 +676,13 @@ impl Command for Combine {
     }
 
     fn request(&self, gridview: &mut GridView) -> CommandRequest {
-        let droplets = &mut gridview.snapshot_mut().droplets;
-
         let id0 = &self.inputs[0];
         let id1 = &self.inputs[1];
 
         // set the collision groups to be the same
         // must scope the mutable borrow
         {
+            let droplets = &mut gridview.snapshot_mut().droplets;
             let cg1 = droplets[id1].collision_group;
             let d0 = droplets.get_mut(id0).unwrap();
             d0.collision_group = cg1;
@@ -308,34 +692,26 @@ impl Command for Combine {
             }
         }
 
-        let d0 = &droplets[id0];
-        let d1 = &droplets[id1];
-
-        let combined = self.combined(d0, d1);
+        let d0 = gridview.snapshot().droplets[id0].clone();
+        let d1 = gridview.snapshot().droplets[id1].clone();
 
         if self.pin_d0 {
+            let (side, target) = self.pinned_side(gridview, &d0, &d1);
+            let layout = side.layout(&d0.dimensions, &d1.dimensions);
             CommandRequest {
-                shape: Grid::rectangle(
-                    combined.dimensions.y as usize,
-                    combined.dimensions.x as usize,
-                ),
-                input_locations: vec![d0.location, combined.location],
+                shape: Grid::rectangle(layout.frame.y as usize, layout.frame.x as usize),
+                input_locations: vec![d0.location, target],
                 trusted: true,
+                rotatable: false,
             }
         } else {
+            let side = self.free_side(gridview, &d0, &d1);
+            let layout = side.layout(&d0.dimensions, &d1.dimensions);
             CommandRequest {
-                shape: Grid::rectangle(
-                    combined.dimensions.y as usize,
-                    combined.dimensions.x as usize,
-                ),
-                input_locations: vec![
-                    Location {
-                        y: d1.dimensions.y,
-                        x: 0,
-                    },
-                    Location { y: 0, x: 0 },
-                ],
+                shape: Grid::rectangle(layout.frame.y as usize, layout.frame.x as usize),
+                input_locations: vec![layout.offset0, layout.offset1],
                 trusted: false,
+                rotatable: false,
             }
         }
     }
@@ -347,13 +723,14 @@ impl Command for Combine {
 
         let d0 = gridview.remove(&in0);
         let d1 = gridview.remove(&in1);
-        // TODO right now this only mixes vertical
-        // it should somehow communicate with the Combine command to control the mixed droplets dimensions
-        let combined = self.combined(&d0, &d1);
 
-        // assert_eq!(d0.location.y, d1.location.y);
-        // assert_eq!(d0.location.x + d0.dimensions.x, d1.location.x);
-        gridview.insert(combined.to_droplet(out));
+        // they're lined up by now, so they're stacked if they aren't side by side
+        let vertical = d0.location.y != d1.location.y;
+        let combined = self.combined(&d0, &d1, vertical);
+
+        let mut d = combined.to_droplet(out);
+        d.composition = d0.combined_composition(&d1);
+        gridview.insert(d);
     }
 
     fn run(&mut self, _: &mut GridSubView) {}
@@ -363,26 +740,191 @@ impl Command for Combine {
 //  Agitate
 //
 
+/// The path a droplet takes while it's being mixed
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MixPattern {
+    /// around a square, one cell each way
+    Square,
+    /// back and forth in a line
+    Shuttle,
+    /// around two squares side by side, one each way
+    FigureEight,
+    /// pulled apart into halves and put back together
+    SplitMerge,
+}
+
+impl Default for MixPattern {
+    fn default() -> Self {
+        MixPattern::Square
+    }
+}
+
+/// How to mix a droplet, and for how long. Give at most one of `loops` and
+/// `seconds`, without either bigger droplets get more loops.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MixOptions {
+    pub pattern: MixPattern,
+    pub loops: Option<u32>,
+    pub seconds: Option<f64>,
+}
+
+const NORTH: Location = Location { y: -1, x: 0 };
+const SOUTH: Location = Location { y: 1, x: 0 };
+const EAST: Location = Location { y: 0, x: 1 };
+const WEST: Location = Location { y: 0, x: -1 };
+
+// how far a shuttling droplet goes before it turns around
+const SHUTTLE_DISTANCE: i32 = 2;
+// how far apart split-merge pulls the halves
+const SPLIT_MERGE_PADDING: i32 = 4;
+
+impl MixPattern {
+    // the moves for a single loop, each one takes a tick
+    fn moves(self) -> Vec<Location> {
+        match self {
+            MixPattern::Square => vec![SOUTH, EAST, NORTH, WEST],
+            MixPattern::Shuttle => {
+                let mut moves = vec![EAST; SHUTTLE_DISTANCE as usize];
+                moves.extend(vec![WEST; SHUTTLE_DISTANCE as usize]);
+                moves
+            }
+            MixPattern::FigureEight => vec![SOUTH, WEST, NORTH, EAST, SOUTH, EAST, NORTH, WEST],
+            // split, pull apart, push together, merge
+            MixPattern::SplitMerge => vec![],
+        }
+    }
+
+    fn ticks_per_loop(self) -> u32 {
+        match self {
+            MixPattern::SplitMerge => 4,
+            _ => self.moves().len() as u32,
+        }
+    }
+
+    // how much room the pattern needs around the droplet, and where in that
+    // room the droplet starts
+    fn shape(self, dim: &Location) -> (Location, Location) {
+        let (padding, start) = match self {
+            MixPattern::Square => (Location { y: 1, x: 1 }, Location { y: 0, x: 0 }),
+            MixPattern::Shuttle => (
+                Location {
+                    y: 0,
+                    x: SHUTTLE_DISTANCE,
+                },
+                Location { y: 0, x: 0 },
+            ),
+            MixPattern::FigureEight => (Location { y: 1, x: 2 }, Location { y: 0, x: 1 }),
+            MixPattern::SplitMerge => (
+                Location {
+                    y: 0,
+                    x: SPLIT_MERGE_PADDING,
+                },
+                Location {
+                    y: 0,
+                    x: SPLIT_MERGE_PADDING / 2,
+                },
+            ),
+        };
+        (dim + &padding, start)
+    }
+}
+
 #[derive(Debug)]
 pub struct Agitate {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
-    n_agitation_loops: u32,
+    pattern: MixPattern,
+    // None means it depends on how big the droplet is
+    n_agitation_loops: Option<u32>,
 }
 
 impl Agitate {
     pub fn new(in_id: DropletId, out_id: DropletId) -> PuddleResult<Agitate> {
+        Agitate::with_options(in_id, out_id, MixOptions::default())
+    }
+
+    pub fn with_options(
+        in_id: DropletId,
+        out_id: DropletId,
+        options: MixOptions,
+    ) -> PuddleResult<Agitate> {
+        let pattern = options.pattern;
+        let n_agitation_loops = match (options.loops, options.seconds) {
+            (Some(_), Some(_)) => {
+                let msg = "mix for a number of loops or seconds, not both".to_string();
+                return Err(PuddleError::InvalidArgument(msg));
+            }
+            (Some(0), None) => {
+                let msg = "can't mix for 0 loops".to_string();
+                return Err(PuddleError::InvalidArgument(msg));
+            }
+            (Some(loops), None) => Some(loops),
+            (None, Some(seconds)) => {
+                if !(seconds > 0.0) {
+                    let msg = format!("can't mix for {} seconds", seconds);
+                    return Err(PuddleError::InvalidArgument(msg));
+                }
+                let tick = duration_seconds(&tick_duration());
+                let loop_time = tick * f64::from(pattern.ticks_per_loop());
+                Some((seconds / loop_time).ceil().max(1.0) as u32)
+            }
+            (None, None) => None,
+        };
         Ok(Agitate {
             inputs: vec![in_id],
             outputs: vec![out_id],
-            n_agitation_loops: 1,
+            pattern,
+            n_agitation_loops,
         })
     }
-}
 
-const AGITATE_PADDING: usize = 1;
+    fn split_merge(&self, gridview: &mut GridSubView) {
+        let in_id = self.inputs[0];
+        // the output id isn't used until the end, so borrow it for a half
+        let other_id = self.outputs[0];
+
+        let d = gridview.remove(&in_id);
+        let vol = d.volume / 2.0;
+        let dim = Location {
+            y: d.dimensions.y,
+            x: (d.dimensions.x + 1) / 2,
+        };
+        let width = d.dimensions.x + SPLIT_MERGE_PADDING;
+
+        let loc0 = Location { y: 0, x: 1 };
+        let loc1 = Location {
+            y: 0,
+            x: width - (dim.x + 1),
+        };
+        for &(id, loc) in &[(in_id, loc0), (other_id, loc1)] {
+            let mut half = Droplet::new(id, vol, loc, dim);
+            half.composition = d.part_composition(vol);
+            gridview.insert(half);
+        }
+        gridview.tick();
+
+        gridview.move_west(in_id);
+        gridview.move_east(other_id);
+        gridview.tick();
+        gridview.move_east(in_id);
+        gridview.move_west(other_id);
+        gridview.tick();
+
+        // putting back the same droplet keeps the volume exactly the same
+        gridview.remove(&in_id);
+        gridview.remove(&other_id);
+        gridview.insert(d);
+        gridview.tick();
+    }
+}
 
 impl Command for Agitate {
+    fn name(&self) -> &'static str {
+        "agitate"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -394,14 +936,13 @@ impl Command for Agitate {
     fn request(&self, gridview: &mut GridView) -> CommandRequest {
         let droplets = &mut gridview.snapshot_mut().droplets;
         let droplet = droplets.get_mut(&self.inputs[0]).unwrap();
+        let (shape, start) = self.pattern.shape(&droplet.dimensions);
 
         CommandRequest {
-            shape: Grid::rectangle(
-                droplet.dimensions.y as usize + AGITATE_PADDING,
-                droplet.dimensions.x as usize + AGITATE_PADDING,
-            ),
-            input_locations: vec![Location { y: 0, x: 0 }],
+            shape: Grid::rectangle(shape.y as usize, shape.x as usize),
+            input_locations: vec![start],
             trusted: false,
+            rotatable: is_square(&droplet.dimensions),
         }
     }
 
@@ -409,16 +950,21 @@ impl Command for Agitate {
         let in_id = self.inputs[0];
         let out_id = self.outputs[0];
 
-        for i in 0..self.n_agitation_loops {
+        let n_loops = self.n_agitation_loops.unwrap_or_else(|| {
+            let dim = gridview.get(&in_id).dimensions;
+            dim.y.max(dim.x) as u32
+        });
+        let moves = self.pattern.moves();
+
+        for i in 0..n_loops {
             debug!("Agitating droplet {:?}, iteration {}", in_id, i);
-            gridview.move_south(in_id);
-            gridview.tick();
-            gridview.move_east(in_id);
-            gridview.tick();
-            gridview.move_north(in_id);
-            gridview.tick();
-            gridview.move_west(in_id);
-            gridview.tick();
+            if self.pattern == MixPattern::SplitMerge {
+                self.split_merge(gridview);
+            }
+            for direction in &moves {
+                gridview.move_by(in_id, *direction);
+                gridview.tick();
+            }
         }
 
         let mut droplet = gridview.remove(&in_id);
@@ -431,24 +977,171 @@ impl Command for Agitate {
 //  Split
 //
 
+/// Which way a droplet gets pulled apart. Horizontal splits leave the
+/// outputs side by side, vertical ones leave them stacked on top of each
+/// other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Orientation {
+    Horizontal,
+    Vertical,
+}
+
+impl Default for Orientation {
+    fn default() -> Self {
+        Orientation::Horizontal
+    }
+}
+
+impl Orientation {
+    // turns a position along and across the split into a location
+    fn location(self, along: i32, across: i32) -> Location {
+        match self {
+            Orientation::Horizontal => Location {
+                y: across,
+                x: along,
+            },
+            Orientation::Vertical => Location {
+                y: along,
+                x: across,
+            },
+        }
+    }
+
+    // how long a droplet is in the direction it's being split
+    fn length(self, dim: &Location) -> i32 {
+        match self {
+            Orientation::Horizontal => dim.x,
+            Orientation::Vertical => dim.y,
+        }
+    }
+
+    fn width(self, dim: &Location) -> i32 {
+        match self {
+            Orientation::Horizontal => dim.y,
+            Orientation::Vertical => dim.x,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Split {
     inputs: Vec<DropletId>,
     outputs: Vec<DropletId>,
+    ratios: Vec<f64>,
+    // if the caller didn't pick one, square droplets may split either way
+    orientation: Option<Orientation>,
+}
+
+// Where everything goes in a split, in the command's coordinates
+struct SplitLayout {
+    shape: Location,
+    input_location: Location,
+    // the locations the outputs start at and end up at
+    starts: Vec<Location>,
+    ends: Vec<Location>,
+    dimensions: Vec<Location>,
 }
 
 impl Split {
     pub fn new(id: DropletId, out_id1: DropletId, out_id2: DropletId) -> PuddleResult<Split> {
+        Split::with_ratios(
+            id,
+            vec![out_id1, out_id2],
+            vec![1.0, 1.0],
+            None,
+        )
+    }
+
+    // splits into as many droplets as there are ratios, with the volume
+    // divided up between them according to the ratios, horizontally unless
+    // told otherwise
+    pub fn with_ratios(
+        id: DropletId,
+        out_ids: Vec<DropletId>,
+        ratios: Vec<f64>,
+        orientation: Option<Orientation>,
+    ) -> PuddleResult<Split> {
+        if out_ids.len() != ratios.len() {
+            let msg = format!("{} ratios for {} outputs", ratios.len(), out_ids.len());
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        if ratios.len() < 2 {
+            let msg = format!("can't split into {} droplets", ratios.len());
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        if ratios.iter().any(|r| !(r.is_finite() && *r > 0.0)) {
+            let msg = format!("split ratios must be positive, not {:?}", ratios);
+            return Err(PuddleError::InvalidArgument(msg));
+        }
         Ok(Split {
             inputs: vec![id],
-            outputs: vec![out_id1, out_id2],
+            outputs: out_ids,
+            ratios,
+            orientation,
         })
     }
-}
+
+    fn fractions(&self) -> Vec<f64> {
+        let total: f64 = self.ratios.iter().sum();
+        self.ratios.iter().map(|r| r / total).collect()
+    }
+
+    fn layout(&self, d: &Droplet) -> SplitLayout {
+        let o = self.orientation.unwrap_or_default();
+        let length = o.length(&d.dimensions);
+        let width = o.width(&d.dimensions);
+
+        // each output covers as many cells as the input would for its
+        // volume, as close to square as the input's width allows
+        let cells_per_volume = (length * width) as f64 / d.volume;
+        let sizes: Vec<(i32, i32)> = self
+            .fractions()
+            .iter()
+            .map(|f| {
+                let cells = (d.volume * f * cells_per_volume).ceil().max(1.0);
+                let across = (cells.sqrt().ceil() as i32).min(width);
+                ((cells / across as f64).ceil() as i32, across)
+            })
+            .collect();
+        let lengths: Vec<i32> = sizes.iter().map(|&(along, _)| along).collect();
+
+        // the outputs end up with at least 2 cells between them (3 if there
+        // are only 2) so they can start 1 cell closer in
+        let n_gaps = lengths.len() as i32 - 1;
+        let total: i32 = lengths.iter().sum();
+        let shape_length = (length + SPLIT_PADDING as i32).max(total + 2 * n_gaps + 1);
+        let free = shape_length - total;
+
+        let mut ends = Vec::new();
+        let mut along = 0;
+        for (i, len) in lengths.iter().enumerate() {
+            ends.push(along);
+            let gap = free / n_gaps + if (i as i32) < free % n_gaps { 1 } else { 0 };
+            along += len + gap;
+        }
+
+        let mut starts = ends.clone();
+        starts[0] += 1;
+        *starts.last_mut().unwrap() -= 1;
+
+        SplitLayout {
+            shape: o.location(shape_length, width),
+            input_location: o.location((shape_length - length) / 2, 0),
+            starts: starts.iter().map(|&a| o.location(a, 0)).collect(),
+            ends: ends.iter().map(|&a| o.location(a, 0)).collect(),
+            dimensions: sizes.iter().map(|&(l, w)| o.location(l, w)).collect(),
+        }
+    }
+}
 
 const SPLIT_PADDING: usize = 4;
 
 impl Command for Split {
+    fn name(&self) -> &'static str {
+        "split"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -459,10 +1152,9 @@ impl Command for Split {
 
     fn bypass(&self, gridview: &GridView) -> bool {
         let droplets = &gridview.snapshot().droplets;
-        // if it has one, it better have both
-        if droplets.contains_key(&self.outputs[0]) || droplets.contains_key(&self.outputs[1]) {
-            assert!(droplets.contains_key(&self.outputs[0]));
-            assert!(droplets.contains_key(&self.outputs[1]));
+        // if it has one, it better have all of them
+        if self.outputs.iter().any(|id| droplets.contains_key(id)) {
+            assert!(self.outputs.iter().all(|id| droplets.contains_key(id)));
             assert!(!droplets.contains_key(&self.inputs[0]));
             true
         } else {
@@ -473,57 +1165,122 @@ impl Command for Split {
     fn request(&self, gridview: &mut GridView) -> CommandRequest {
         let droplets = &gridview.snapshot().droplets;
         let d0 = droplets.get(&self.inputs[0]).unwrap();
-        // we only split in the x right now, so we don't need y padding
-        let x_dim = (d0.dimensions.x as usize) + SPLIT_PADDING;
-        let y_dim = d0.dimensions.y as usize;
-        let grid = Grid::rectangle(y_dim, x_dim);
-
-        let input_locations = vec![Location { y: 0, x: 2 }];
+        let layout = self.layout(d0);
+        let grid = Grid::rectangle(layout.shape.y as usize, layout.shape.x as usize);
 
         CommandRequest {
             shape: grid,
-            input_locations: input_locations,
+            input_locations: vec![layout.input_location],
             trusted: false,
+            // a quarter turn just splits along the other axis
+            rotatable: self.orientation.is_none() && is_square(&d0.dimensions),
         }
     }
 
     fn run(&mut self, gridview: &mut GridSubView) {
-        let x_dim = {
-            // limit the scope of d0 borrow
-            let d0 = gridview.get(&self.inputs[0]);
-            (d0.dimensions.x as usize) + SPLIT_PADDING
-        };
-
-        let inp = self.inputs[0];
-        let out0 = self.outputs[0];
-        let out1 = self.outputs[1];
+        let d = gridview.remove(&self.inputs[0]);
+        let layout = self.layout(&d);
+
+        let outputs = self.outputs.iter().zip(self.fractions());
+        for (i, (&id, fraction)) in outputs.enumerate() {
+            let vol = d.volume * fraction;
+            let mut out = Droplet::new(id, vol, layout.starts[i], layout.dimensions[i]);
+            out.composition = d.part_composition(vol);
+            gridview.insert(out);
+        }
 
-        let d = gridview.remove(&inp);
-        let vol = d.volume / 2.0;
+        gridview.tick();
+        let moves = layout.starts.iter().zip(&layout.ends);
+        for (&id, (start, end)) in self.outputs.iter().zip(moves) {
+            if end.x < start.x {
+                gridview.move_west(id)
+            } else if end.x > start.x {
+                gridview.move_east(id)
+            } else if end.y < start.y {
+                gridview.move_north(id)
+            } else if end.y > start.y {
+                gridview.move_south(id)
+            }
+        }
+        gridview.tick();
+    }
+}
 
-        // TODO: this should be related to volume in some fashion
-        // currently, take the ceiling of the division of the split by two
-        let dim = Location {
-            y: d.dimensions.y,
-            x: (d.dimensions.x + 1) / 2,
-        };
+/// The shape `d` needs to sit on a heater. Heaters only take droplets that
+/// are one cell wide, so anything wider has to be reshaped into a column
+/// first, which has to fit on `grid`.
+pub fn heater_shape(d: &Droplet, grid: &Grid) -> PuddleResult<Location> {
+    let dim = d.dimensions;
+    if dim.x == 1 {
+        return Ok(dim);
+    }
+    let tall = Location {
+        y: dim.y * dim.x,
+        x: 1,
+    };
+    if tall.y as usize > grid.max_height() {
+        let msg = format!(
+            "droplet {:?} is too big to fit on a heater as a {} column",
+            d.id, tall
+        );
+        return Err(PuddleError::InvalidArgument(msg));
+    }
+    Ok(tall)
+}
 
-        let loc0 = Location { y: 0, x: 1 };
-        let loc1 = Location {
-            y: 0,
-            x: x_dim as i32 - (dim.x + 1),
-        };
+// the commands that use a heater only take droplets `heater_shape` made
+fn check_heater_shape(id: DropletId, dim: &Location) -> PuddleResult<()> {
+    if dim.x != 1 {
+        let msg = format!("droplet {:?} is {} but heaters take one column", id, dim);
+        return Err(PuddleError::InvalidArgument(msg));
+    }
+    Ok(())
+}
 
-        gridview.insert(Droplet::new(out0, vol, loc0, dim));
-        gridview.insert(Droplet::new(out1, vol, loc1, dim));
+// A droplet-sized shape with a heater under the bottom-left corner
+fn heater_request(dim: &Location) -> CommandRequest {
+    // the parameters of heater here don't matter, as it's just used to
+    // match up with the "real" heater in the actual grid
+    let heater = Peripheral::Heater {
+        pwm_channel: 0,
+        spi_channel: 0,
+    };
+    peripheral_request(dim, heater)
+}
 
-        gridview.tick();
-        gridview.move_west(out0);
-        gridview.move_east(out1);
-        gridview.tick();
+// A droplet-sized shape with the peripheral under the bottom-left corner
+fn peripheral_request(dim: &Location, peripheral: Peripheral) -> CommandRequest {
+    let mut grid = Grid::rectangle(dim.y as usize, dim.x as usize);
+    let loc = Location { y: dim.y - 1, x: 0 };
+    grid.get_cell_mut(&loc).unwrap().peripheral = Some(peripheral);
+
+    // the droplet covers the whole shape, peripheral and all
+    CommandRequest {
+        shape: grid,
+        input_locations: vec![Location { y: 0, x: 0 }],
+        trusted: false,
+        rotatable: is_square(dim),
     }
 }
 
+// The real heater under a droplet placed by `heater_request`
+#[cfg(feature = "pi")]
+fn find_heater(gridview: &GridSubView, id: &DropletId) -> Peripheral {
+    let d = gridview.get(id);
+    let loc = Location {
+        y: d.dimensions.y - 1,
+        x: 0,
+    };
+    let heater = gridview
+        .get_electrode(&loc)
+        .cloned()
+        .unwrap()
+        .peripheral
+        .unwrap();
+    assert_matches!(heater, Peripheral::Heater { .. });
+    heater
+}
+
 #[derive(Debug)]
 pub struct Heat {
     inputs: Vec<DropletId>,
@@ -534,12 +1291,15 @@ pub struct Heat {
 }
 
 impl Heat {
+    // `dim` is what the droplet looks like by the time it's heated
     pub fn new(
         id: DropletId,
+        dim: &Location,
         out_id: DropletId,
         temperature: f32,
         duration: Duration,
     ) -> PuddleResult<Heat> {
+        check_heater_shape(id, dim)?;
         Ok(Heat {
             inputs: vec![id],
             outputs: vec![out_id],
@@ -551,6 +1311,10 @@ impl Heat {
 }
 
 impl Command for Heat {
+    fn name(&self) -> &'static str {
+        "heat"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -562,66 +1326,363 @@ impl Command for Heat {
     fn request(&self, gridview: &mut GridView) -> CommandRequest {
         let droplets = &gridview.snapshot().droplets;
         let d = droplets.get(&self.inputs[0]).unwrap();
-        // we only split in the x right now, so we don't need y padding
-        let x_dim = d.dimensions.x as usize;
-        let y_dim = d.dimensions.y as usize;
+        heater_request(&d.dimensions)
+    }
 
-        // right now we can only heat droplets that are 1x1
-        // assert_eq!(y_dim, 1);
-        assert_eq!(x_dim, 1);
-        let mut grid = Grid::rectangle(y_dim, x_dim);
+    fn run(&mut self, gridview: &mut GridSubView) {
+        #[cfg(feature = "pi")]
+        {
+            self.heater = Some(find_heater(gridview, &self.inputs[0]));
+        }
+        let old_id = self.inputs[0];
+        let new_id = self.outputs[0];
 
-        // the parameters of heater here don't matter, as it's just used to
-        // match up with the "real" heater in the actual grid
-        let loc = Location {
-            y: y_dim as i32 - 1,
-            x: 0,
-        };
-        grid.get_cell_mut(&loc).unwrap().peripheral = Some(Peripheral::Heater {
-            pwm_channel: 0,
-            spi_channel: 0,
-        });
+        let mut d = gridview.remove(&old_id);
+        // NOTE this is a rare place it's ok to change an id, like move
+        d.id = new_id;
+        gridview.insert(d);
+        gridview.tick()
+    }
+
+    #[cfg(feature = "pi")]
+    fn finalize(&mut self, _: &Snapshot, pi: Option<&mut RaspberryPi>) {
+        let heater = self.heater.take().unwrap();
+        pi.map(|pi| pi.heat(&heater, self.temperature as f64, self.duration));
+    }
+}
+
+//
+//  Incubate
+//
+
+#[derive(Debug)]
+pub struct Incubate {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    duration: Duration,
+    done: Sender<HoldResult>,
+}
 
-        let input_locations = vec![loc];
+impl Incubate {
+    pub fn new(
+        id: DropletId,
+        out_id: DropletId,
+        seconds: f64,
+        done: Sender<HoldResult>,
+    ) -> PuddleResult<Incubate> {
+        if !(seconds >= 0.0 && seconds.is_finite()) {
+            let msg = format!("can't incubate for {} seconds", seconds);
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        Ok(Incubate {
+            inputs: vec![id],
+            outputs: vec![out_id],
+            duration: seconds_duration(seconds),
+            done,
+        })
+    }
+}
+
+impl Command for Incubate {
+    fn name(&self) -> &'static str {
+        "incubate"
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
 
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let droplets = &gridview.snapshot().droplets;
+        let dim = droplets.get(&self.inputs[0]).unwrap().dimensions;
+
+        // just enough room for the droplet to sit still
         CommandRequest {
-            shape: grid,
-            input_locations: input_locations,
+            shape: Grid::rectangle(dim.y as usize, dim.x as usize),
+            input_locations: vec![Location { y: 0, x: 0 }],
             trusted: false,
+            rotatable: is_square(&dim),
         }
     }
 
+    // the droplet stays pinned where it is, and the executor lets it go
+    // once the time is up, see `Hold`
     fn run(&mut self, gridview: &mut GridSubView) {
-        #[cfg(feature = "pi")]
-        {
-            let d = gridview.get(&self.inputs[0]);
-            let loc = Location {
-                y: d.dimensions.y - 1,
-                x: 0,
-            };
-            let heater = gridview
-                .get_electrode(&loc)
-                .cloned()
-                .unwrap()
-                .peripheral
-                .unwrap();
-            assert_matches!(heater, Peripheral::Heater{..});
-            self.heater = Some(heater)
+        let old_id = self.inputs[0];
+        let new_id = self.outputs[0];
+
+        let mut d = gridview.remove(&old_id);
+        d.id = new_id;
+        d.pinned = true;
+        gridview.insert(d);
+        let stage = HoldStage {
+            duration: self.duration,
+            heater: None,
+        };
+        gridview.hold(Hold {
+            droplet: new_id,
+            stages: vec![stage],
+            report: self.done.clone(),
+        });
+    }
+
+    fn abort(&mut self, err: PlanError) {
+        error!("Aborting command {:?} with {:#?}", self, err);
+        // whoever was waiting might not be around anymore
+        let _ = self.done.send(Err(err));
+    }
+}
+
+//
+//  Thermocycle
+//
+
+/// One step of a thermocycling program: heat to `temperature` and hold it
+/// there for `seconds`
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermocycleStage {
+    pub temperature: f32,
+    pub seconds: f64,
+}
+
+/// What the client gets back from a thermocycle: the droplet, and what the
+/// heater measured at the end of each stage, one list per cycle. A reading
+/// is missing if the sensor couldn't be read.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ThermocycleReport {
+    pub droplet: DropletId,
+    pub temperatures: Vec<Vec<Option<f32>>>,
+}
+
+#[derive(Debug)]
+pub struct Thermocycle {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    stages: Vec<ThermocycleStage>,
+    cycles: u32,
+    // hears what the heater measured at the end of each stage
+    report: Sender<HoldResult>,
+}
+
+impl Thermocycle {
+    // `dim` is what the droplet looks like by the time it's heated
+    pub fn new(
+        id: DropletId,
+        dim: &Location,
+        out_id: DropletId,
+        stages: Vec<ThermocycleStage>,
+        cycles: u32,
+        report: Sender<HoldResult>,
+    ) -> PuddleResult<Thermocycle> {
+        check_heater_shape(id, dim)?;
+        if stages.is_empty() {
+            let msg = "a thermocycle needs at least one stage".to_string();
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        if cycles == 0 {
+            let msg = "can't thermocycle for 0 cycles".to_string();
+            return Err(PuddleError::InvalidArgument(msg));
         }
+        let bad_stage = stages
+            .iter()
+            .find(|stage| !(stage.seconds >= 0.0 && stage.seconds.is_finite()));
+        if let Some(stage) = bad_stage {
+            let msg = format!("can't hold a stage for {} seconds", stage.seconds);
+            return Err(PuddleError::InvalidArgument(msg));
+        }
+        Ok(Thermocycle {
+            inputs: vec![id],
+            outputs: vec![out_id],
+            stages,
+            cycles,
+            report,
+        })
+    }
+}
+
+impl Command for Thermocycle {
+    fn name(&self) -> &'static str {
+        "thermocycle"
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let droplets = &gridview.snapshot().droplets;
+        let d = droplets.get(&self.inputs[0]).unwrap();
+        heater_request(&d.dimensions)
+    }
+
+    // the droplet stays pinned on the heater for the whole program, which
+    // the executor runs a stage at a time, see `Hold`
+    fn run(&mut self, gridview: &mut GridSubView) {
+        let old_id = self.inputs[0];
+        let new_id = self.outputs[0];
+        let dim = gridview.get(&old_id).dimensions;
+        let heater = Location { y: dim.y - 1, x: 0 };
+
+        let cycle = self.stages.iter().map(|stage| HoldStage {
+            duration: seconds_duration(stage.seconds),
+            heater: Some((heater, stage.temperature)),
+        });
+        let n_stages = self.stages.len() * self.cycles as usize;
+        let stages: Vec<HoldStage> = cycle.cycle().take(n_stages).collect();
+
+        let mut d = gridview.remove(&old_id);
+        d.id = new_id;
+        d.pinned = true;
+        gridview.insert(d);
+        gridview.hold(Hold {
+            droplet: new_id,
+            stages,
+            report: self.report.clone(),
+        });
+    }
+
+    fn abort(&mut self, err: PlanError) {
+        error!("Aborting command {:?} with {:#?}", self, err);
+        // whoever was waiting might not be around anymore
+        let _ = self.report.send(Err(err));
+    }
+}
+
+//
+//  Sense
+//
+
+// what a temperature sensor reads without any hardware, room temperature
+const SIMULATED_TEMPERATURE: f32 = 25.0;
+
+/// What a sensor measured about a droplet
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum Reading {
+    Temperature { celsius: f32 },
+    Area { cells: f64 },
+}
+
+/// The reading is missing if the sensor couldn't be read
+pub type SenseResult = Result<Option<Reading>, PlanError>;
+
+/// What the client gets back from sensing: the droplet, and the reading
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct SenseReport {
+    pub droplet: DropletId,
+    pub reading: Option<Reading>,
+}
+
+#[derive(Debug)]
+pub struct Sense {
+    inputs: Vec<DropletId>,
+    outputs: Vec<DropletId>,
+    sensor: Peripheral,
+    tx: Sender<SenseResult>,
+}
+
+impl Sense {
+    /// `sensor` should be the actual peripheral from the grid
+    pub fn new(
+        id: DropletId,
+        out_id: DropletId,
+        sensor: Peripheral,
+        tx: Sender<SenseResult>,
+    ) -> PuddleResult<Sense> {
+        assert_matches!(sensor, Peripheral::Sensor { .. });
+        Ok(Sense {
+            inputs: vec![id],
+            outputs: vec![out_id],
+            sensor,
+            tx,
+        })
+    }
+
+    fn kind(&self) -> SensorKind {
+        match self.sensor {
+            Peripheral::Sensor { kind, .. } => kind,
+            _ => unreachable!(),
+        }
+    }
+
+    // the camera's idea of the droplet is already in the snapshot, since
+    // it's been corrected by the time it's finalized
+    fn simulated_reading(&self, snapshot: &Snapshot) -> Reading {
+        match self.kind() {
+            SensorKind::Temperature => Reading::Temperature {
+                celsius: SIMULATED_TEMPERATURE,
+            },
+            SensorKind::Area => {
+                let dim = snapshot.droplets[&self.outputs[0]].dimensions;
+                Reading::Area {
+                    cells: f64::from(dim.y * dim.x),
+                }
+            }
+        }
+    }
+}
+
+impl Command for Sense {
+    fn name(&self) -> &'static str {
+        "sense"
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        self.outputs.clone()
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let droplets = &gridview.snapshot().droplets;
+        let d = droplets.get(&self.inputs[0]).unwrap();
+        peripheral_request(&d.dimensions, self.sensor.clone())
+    }
+
+    fn run(&mut self, gridview: &mut GridSubView) {
         let old_id = self.inputs[0];
         let new_id = self.outputs[0];
 
         let mut d = gridview.remove(&old_id);
-        // NOTE this is a rare place it's ok to change an id, like move
         d.id = new_id;
         gridview.insert(d);
         gridview.tick()
     }
 
+    #[cfg(not(feature = "pi"))]
+    fn finalize(&mut self, snapshot: &Snapshot) {
+        let reading = self.simulated_reading(snapshot);
+        self.tx.send(Ok(Some(reading))).unwrap();
+    }
+
     #[cfg(feature = "pi")]
-    fn finalize(&mut self, _: &Snapshot, pi: Option<&mut RaspberryPi>) {
-        let heater = self.heater.take().unwrap();
-        pi.map(|pi| pi.heat(&heater, self.temperature as f64, self.duration));
+    fn finalize(&mut self, snapshot: &Snapshot, pi: Option<&mut RaspberryPi>) {
+        let reading = match (self.kind(), pi) {
+            (SensorKind::Temperature, Some(pi)) => match pi.get_temperature(self.sensor.clone()) {
+                Ok(celsius) => Some(Reading::Temperature { celsius }),
+                Err(e) => {
+                    error!("Couldn't read {:?}: {:?}", self.sensor, e);
+                    None
+                }
+            },
+            _ => Some(self.simulated_reading(snapshot)),
+        };
+        self.tx.send(Ok(reading)).unwrap();
+    }
+
+    fn abort(&mut self, err: PlanError) {
+        error!("Aborting command {:?} with {:#?}", self, err);
+        self.tx.send(Err(err)).unwrap();
     }
 }
 
@@ -652,6 +1713,10 @@ impl Input {
 }
 
 impl Command for Input {
+    fn name(&self) -> &'static str {
+        "input"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         vec![]
     }
@@ -663,8 +1728,8 @@ impl Command for Input {
     fn request(&self, _gridview: &mut GridView) -> CommandRequest {
         let mut grid = Grid::rectangle(self.dimensions.y as usize, self.dimensions.x as usize + 1);
 
-        // fake peripheral used to match up with the real one
-        // FIXME: this is a total hack to assume that input is always on the right-hand side
+        // fake peripheral used to match up with the real one, the placement
+        // will turn the shape around to whichever side the real one is on
         let loc = Location {
             y: self.dimensions.y / 2,
             x: self.dimensions.x - 1 + 1,
@@ -680,11 +1745,12 @@ impl Command for Input {
             shape: grid,
             input_locations: vec![],
             trusted: false,
+            rotatable: is_square(&self.dimensions),
         }
     }
 
     fn run(&mut self, gridview: &mut GridSubView) {
-        // FIXME: this is a total hack to assume that input is always on the right-hand side
+        // the input is on the right-hand side in the command's coordinates
         let input_loc = Location {
             y: self.dimensions.y / 2,
             x: self.dimensions.x - 1 + 1,
@@ -703,7 +1769,8 @@ impl Command for Input {
         let new_id = self.outputs[0];
 
         let d_loc = Location { y: 0, x: 0 };
-        let d = Droplet::new(new_id, self.volume, d_loc, self.dimensions);
+        let mut d = Droplet::new(new_id, self.volume, d_loc, self.dimensions);
+        d.composition.insert(self.substance.clone(), self.volume);
         gridview.insert(d);
         gridview.tick()
     }
@@ -763,6 +1830,10 @@ impl Output {
 }
 
 impl Command for Output {
+    fn name(&self) -> &'static str {
+        "output"
+    }
+
     fn input_droplets(&self) -> Vec<DropletId> {
         self.inputs.clone()
     }
@@ -777,8 +1848,8 @@ impl Command for Output {
 
         let mut grid = Grid::rectangle(d.dimensions.y as usize, d.dimensions.x as usize);
 
-        // fake peripheral used to match up with the real one
-        // FIXME: this is a total hack to assume that output is always on the left-hand side
+        // fake peripheral used to match up with the real one, the placement
+        // will turn the shape around to whichever side the real one is on
         let loc = Location {
             y: d.dimensions.y / 2,
             x: 0,
@@ -790,10 +1861,12 @@ impl Command for Output {
 
         debug!("Output location will be at {}", loc);
 
+        // the droplet covers the whole shape, peripheral and all
         CommandRequest {
             shape: grid,
-            input_locations: vec![loc],
+            input_locations: vec![Location { y: 0, x: 0 }],
             trusted: false,
+            rotatable: is_square(&d.dimensions),
         }
     }
 
@@ -801,7 +1874,7 @@ impl Command for Output {
         let id = self.inputs[0];
         #[cfg(feature = "pi")]
         {
-            // FIXME: this is a total hack to assume that output is always on the left-hand side
+            // the output is on the left-hand side in the command's coordinates
             let loc = Location {
                 y: gridview.get(&id).dimensions.y / 2,
                 x: 0,
@@ -829,3 +1902,66 @@ impl Command for Output {
         pi.map(|pi| pi.output(&output, volume));
     }
 }
+
+//
+//  Discard
+//
+
+/// Takes a droplet off the board without sending it anywhere, for when
+/// there's no waste output
+#[derive(Debug)]
+pub struct Discard {
+    inputs: Vec<DropletId>,
+}
+
+impl Discard {
+    pub fn new(id: DropletId) -> PuddleResult<Discard> {
+        Ok(Discard { inputs: vec![id] })
+    }
+}
+
+impl Command for Discard {
+    fn name(&self) -> &'static str {
+        "discard"
+    }
+
+    fn input_droplets(&self) -> Vec<DropletId> {
+        self.inputs.clone()
+    }
+
+    fn output_droplets(&self) -> Vec<DropletId> {
+        vec![]
+    }
+
+    fn request(&self, gridview: &mut GridView) -> CommandRequest {
+        let droplets = &gridview.snapshot().droplets;
+        let dim = droplets.get(&self.inputs[0]).unwrap().dimensions;
+
+        CommandRequest {
+            shape: Grid::rectangle(dim.y as usize, dim.x as usize),
+            input_locations: vec![Location { y: 0, x: 0 }],
+            trusted: false,
+            rotatable: is_square(&dim),
+        }
+    }
+
+    // only ever used in simulation, see `discard`
+    fn run(&mut self, gridview: &mut GridSubView) {
+        let id = self.inputs[0];
+        gridview.remove(&id);
+        gridview.tick()
+    }
+}
+
+/// Gets rid of a droplet by sending it to the waste output. If there isn't
+/// one, a simulated droplet can just be taken off the board, but a real one
+/// can't.
+pub fn discard(gridview: &GridView, id: DropletId) -> PuddleResult<BoxedCommand> {
+    match gridview.grid.waste {
+        Some(ref name) => Ok(Box::new(Output::new(name.clone(), id)?)),
+        None if gridview.on_hardware() => Err(PuddleError::MissingPeripheral(
+            "a waste output to discard droplets into".into(),
+        )),
+        None => Ok(Box::new(Discard::new(id)?)),
+    }
+}
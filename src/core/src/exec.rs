@@ -1,7 +1,7 @@
 use std::env;
 use std::sync::{Arc, Mutex};
 use std::thread::sleep;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use rand::Rng;
 
@@ -15,6 +15,29 @@ static STEP_DELAY: u64 = 100;
 #[cfg(not(feature = "pi"))]
 static STEP_DELAY: u64 = 1;
 
+/// How long a tick takes, PUDDLE_STEP_DELAY_MS overrides the default
+pub fn step_delay() -> Duration {
+    let sleep_ms = env::var("PUDDLE_STEP_DELAY_MS")
+        .ok()
+        .map(|s| u64::from_str_radix(&s, 10).expect("Couldn't parse!"))
+        .unwrap_or(STEP_DELAY);
+    Duration::from_millis(sleep_ms)
+}
+
+/// How long the executor takes to run a tick, at the least. It sleeps for
+/// `step_delay` before every tick, and on the pi again after setting the
+/// pins. A blocking executor also waits on the visualizer, so its ticks can
+/// take any amount of time.
+pub fn tick_duration() -> Duration {
+    // even without a delay, a tick isn't free
+    let delay = step_delay().max(Duration::from_millis(1));
+    if cfg!(feature = "pi") {
+        delay * 2
+    } else {
+        delay
+    }
+}
+
 pub struct Executor {
     blocking: bool,
     gridview: Arc<Mutex<GridView>>,
@@ -26,11 +49,7 @@ impl Executor {
     }
 
     pub fn run(&mut self, endpoint: Endpoint<Vec<DropletInfo>, ()>) {
-        let sleep_ms = env::var("PUDDLE_STEP_DELAY_MS")
-            .ok()
-            .map(|s| u64::from_str_radix(&s, 10).expect("Couldn't parse!"))
-            .unwrap_or(STEP_DELAY);
-        let sleep_time = Duration::from_millis(sleep_ms);
+        let sleep_time = step_delay();
 
         let mut rng = mk_rng();
 
@@ -154,6 +173,19 @@ impl Executor {
                 NotReady => {}
                 Done => break,
             }
+
+            // held droplets keep their heaters going even when there's
+            // nothing to execute
+            let now = Instant::now();
+            #[cfg(feature = "pi")]
+            {
+                let targets = gv.heater_targets(now);
+                if let Some(mut pi) = gv.pi.take() {
+                    pi.drive_heaters(&gv.grid, &targets);
+                    gv.pi = Some(pi);
+                }
+            }
+            gv.release_holds(now);
         }
         info!("Executor is terminating!");
         ::std::mem::drop(endpoint);
@@ -4,6 +4,7 @@ use std::sync::atomic::Ordering::Relaxed;
 
 use super::Location;
 use process::ProcessId;
+use util::collections::Map;
 
 static NEXT_COLLISION_GROUP: AtomicUsize = AtomicUsize::new(0);
 
@@ -13,6 +14,10 @@ pub struct DropletId {
     pub process_id: ProcessId,
 }
 
+/// How much of each substance is in a droplet, in the same units as its
+/// volume. A droplet that was made without saying what's in it is empty.
+pub type Composition = Map<String, f64>;
+
 #[derive(Debug, Clone)]
 pub struct Droplet {
     // The droplet's id should never be modified once it has been created. They
@@ -21,6 +26,7 @@ pub struct Droplet {
     pub location: Location,
     pub dimensions: Location,
     pub volume: f64,
+    pub composition: Composition,
 
     // all this stuff is used for routing
     // TODO should droplets really know about their destinations?
@@ -37,6 +43,7 @@ pub struct DropletInfo {
     pub location: Location,
     pub volume: f64,
     pub dimensions: Location,
+    pub composition: Composition,
 }
 
 impl Droplet {
@@ -51,6 +58,7 @@ impl Droplet {
             dimensions,
             destination: None,
             volume: volume,
+            composition: Composition::new(),
             collision_group: NEXT_COLLISION_GROUP.fetch_add(1, Relaxed),
             pinned: false,
         }
@@ -88,7 +96,27 @@ impl Droplet {
             location: self.location,
             dimensions: self.dimensions,
             volume: self.volume,
+            composition: self.composition.clone(),
+        }
+    }
+
+    /// The composition of a part of this droplet with the given volume,
+    /// assuming the droplet is well mixed.
+    pub fn part_composition(&self, volume: f64) -> Composition {
+        let fraction = volume / self.volume;
+        self.composition
+            .iter()
+            .map(|(substance, amount)| (substance.clone(), amount * fraction))
+            .collect()
+    }
+
+    /// The composition of this droplet combined with `other`
+    pub fn combined_composition(&self, other: &Droplet) -> Composition {
+        let mut composition = self.composition.clone();
+        for (substance, amount) in &other.composition {
+            *composition.entry(substance.clone()).or_insert(0.0) += amount;
         }
+        composition
     }
 
     pub fn to_blob(&self) -> SimpleBlob {
@@ -124,6 +152,7 @@ impl Default for Droplet {
             dimensions: bad_loc,
             pinned: false,
             volume: 1.0,
+            composition: Composition::new(),
             destination: None,
             collision_group: NEXT_COLLISION_GROUP.fetch_add(1, Relaxed),
         }
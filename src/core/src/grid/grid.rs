@@ -1,13 +1,14 @@
-use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use serde_json;
 
 use std::collections::HashSet;
 use std::io::Read;
 
-use super::{Location, Snapshot};
+use super::{Droplet, Location, Snapshot};
 use util::collections::{Map, Set};
 
 use grid::parse::{Mark, ParsedElectrode, ParsedGrid};
+use plan::{best_placement, PlaceRequest, Placement};
 
 #[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
 pub struct Electrode {
@@ -21,6 +22,17 @@ pub enum Peripheral {
     Heater { pwm_channel: u8, spi_channel: u8 },
     Input { pwm_channel: u8, name: String },
     Output { pwm_channel: u8, name: String },
+    Sensor { name: String, kind: SensorKind },
+}
+
+/// What a `Sensor` peripheral measures about the droplet on top of it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SensorKind {
+    /// the MAX31865 temperature probe
+    Temperature,
+    /// the camera, how much of the board the droplet covers
+    Area,
 }
 
 impl Electrode {
@@ -35,19 +47,79 @@ impl Electrode {
         match (mine, theirs) {
             (Input { name: n1, .. }, Input { name: n2, .. }) => n1 == n2,
             (Output { name: n1, .. }, Output { name: n2, .. }) => n1 == n2,
+            (Sensor { name: n1, .. }, Sensor { name: n2, .. }) => n1 == n2,
             (Heater { .. }, Heater { .. }) => true,
             _ => false,
         }
     }
 }
 
+/// A rectangle of the board set aside for droplets to wait in while they
+/// aren't being used, so they stay out of the way of everything else.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+pub struct StorageZone {
+    pub location: Location,
+    pub dimensions: Location,
+}
+
+impl StorageZone {
+    /// Every spot where a droplet of the given dimensions fits in the zone
+    pub fn spots(&self, dimensions: Location) -> Vec<Location> {
+        let mut spots = Vec::new();
+        for y in 0..=(self.dimensions.y - dimensions.y) {
+            for x in 0..=(self.dimensions.x - dimensions.x) {
+                spots.push(&self.location + &Location { y, x });
+            }
+        }
+        spots
+    }
+
+    pub fn contains(&self, loc: Location, dimensions: Location) -> bool {
+        let far = &self.location + &self.dimensions;
+        let loc_far = &loc + &dimensions;
+        self.location.y <= loc.y
+            && self.location.x <= loc.x
+            && loc_far.y <= far.y
+            && loc_far.x <= far.x
+    }
+}
+
+/// A rectangle of electrodes that must never be actuated, except by the
+/// kinds of commands named in `allow`.
+#[derive(Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Clone)]
+pub struct KeepOut {
+    pub location: Location,
+    pub dimensions: Location,
+    #[serde(default)]
+    pub allow: Vec<String>,
+}
+
+impl KeepOut {
+    /// Whether a droplet at `loc` with dimensions `dim` would be on any of
+    /// these electrodes
+    pub fn overlaps(&self, loc: Location, dim: Location) -> bool {
+        let far = &self.location + &self.dimensions;
+        let loc_far = &loc + &dim;
+        loc.y < far.y && self.location.y < loc_far.y && loc.x < far.x && self.location.x < loc_far.x
+    }
+
+    /// Whether a command called `name` may use these electrodes
+    pub fn allows(&self, name: &str) -> bool {
+        self.allow.iter().any(|a| a == name)
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Grid {
     pub vec: Vec<Vec<Option<Electrode>>>,
+    pub storage: Vec<StorageZone>,
+    pub keep_out: Vec<KeepOut>,
+    // the name of the output that discarded droplets go to
+    pub waste: Option<String>,
 }
 
 #[cfg_attr(rustfmt, rustfmt_skip)]
-const NEIGHBORS_8: [Location; 8] = [
+pub const NEIGHBORS_8: [Location; 8] = [
     Location { y: -1, x: -1 },
     Location { y:  0, x: -1 },
     Location { y:  1, x: -1 },
@@ -92,7 +164,13 @@ impl Grid {
                         }
                     }).collect()
             }).collect();
-        ParsedGrid { board, peripherals }
+        ParsedGrid {
+            board,
+            peripherals,
+            storage: self.storage.clone(),
+            keep_out: self.keep_out.clone(),
+            waste: self.waste.clone(),
+        }
     }
 
     pub fn rectangle(h: usize, w: usize) -> Self {
@@ -127,7 +205,7 @@ impl Grid {
 
     pub fn from_reader<R: Read>(reader: R) -> Result<Grid, serde_json::Error> {
         let parsed_grid: ParsedGrid = serde_json::from_reader(reader)?;
-        Ok(parsed_grid.to_grid())
+        parsed_grid.to_grid().map_err(de::Error::custom)
     }
 
     pub fn locations<'a>(&'a self) -> impl Iterator<Item = (Location, Electrode)> + 'a {
@@ -173,48 +251,85 @@ impl Grid {
         })
     }
 
-    fn mapping_into_other_from_offset(
-        &self,
-        offset: Location,
-        _bigger: &Self,
-    ) -> Map<Location, Location> {
-        // assert!(self.is_compatible_within(offset, bigger));
+    /// The cells that a command called `name` may not be placed on. Keep-out
+    /// regions are off limits to every command they don't name.
+    pub fn kept_out(&self, name: Option<&str>) -> HashSet<Location> {
+        self.keep_out
+            .iter()
+            .filter(|ko| name.map_or(true, |name| !ko.allows(name)))
+            .flat_map(|ko| {
+                self.locations()
+                    .map(|(loc, _cell)| loc)
+                    .filter(move |loc| ko.overlaps(*loc, Location { y: 1, x: 1 }))
+            })
+            .collect()
+    }
 
-        let mut map = Map::new();
+    /// The cells in storage zones. Commands would rather not be placed
+    /// there, so there's usually somewhere to park idle droplets.
+    pub fn storage_cells(&self) -> HashSet<Location> {
+        self.storage
+            .iter()
+            .flat_map(|zone| zone.spots(Location { y: 1, x: 1 }))
+            .collect()
+    }
 
-        for (loc, _) in self.locations() {
-            map.insert(loc, &loc + &offset);
-        }
+    /// Whether a droplet at `loc` with dimensions `dim` would be on a keep-out
+    /// region that doesn't allow the command called `name`
+    pub fn keeps_out(&self, name: &str, loc: Location, dim: Location) -> bool {
+        self.keep_out
+            .iter()
+            .any(|ko| !ko.allows(name) && ko.overlaps(loc, dim))
+    }
 
-        map
+    /// Whether `droplet` may pass through `loc` on its way to its destination
+    /// for the command called `name`. Nothing gets routed through a keep-out
+    /// region, except out of the one it's already in, or into the one it's
+    /// headed for if that region allows the command.
+    pub fn may_route(&self, droplet: &Droplet, loc: Location, name: Option<&str>) -> bool {
+        let dim = droplet.dimensions;
+        let dest = droplet.destination.unwrap_or(droplet.location);
+        let allowed = |ko: &KeepOut| name.map_or(false, |name| ko.allows(name));
+        self.keep_out.iter().all(|ko| {
+            !ko.overlaps(loc, dim)
+                || ko.overlaps(droplet.location, dim)
+                || (ko.overlaps(dest, dim) && allowed(ko))
+        })
     }
 
     pub fn place(
         &self,
-        smaller: &Self,
+        req: &PlaceRequest,
         snapshot: &Snapshot,
         bad_edges: &Set<(Location, Location)>,
-    ) -> Option<Map<Location, Location>> {
-        let offset_found = self
-            .vec
-            .iter()
-            .enumerate()
-            .flat_map(move |(i, row)| {
-                (0..row.len()).map(move |j| Location {
-                    y: i as i32,
-                    x: j as i32,
-                })
-            }).find(|&offset| smaller.is_compatible_within(offset, self, snapshot, bad_edges));
+    ) -> Option<Placement> {
+        let reserved = self.kept_out(req.name);
+        let blocked: HashSet<Location> = self
+            .locations()
+            .map(|(loc, _cell)| loc)
+            .filter(|loc| {
+                reserved.contains(loc)
+                    || snapshot.droplets.values().any(|droplet| {
+                        let corner1 = droplet.location;
+                        let corner2 = &droplet.location + &droplet.dimensions;
+                        loc.min_distance_to_box(corner1, corner2) <= 0
+                    })
+            })
+            .collect();
 
-        let result =
-            offset_found.map(|offset| smaller.mapping_into_other_from_offset(offset, self));
+        let result = best_placement(self, req, &blocked, |turned, offset| {
+            turned.is_compatible_within(offset, self, snapshot, bad_edges)
+                && turned
+                    .locations()
+                    .all(|(loc, _cell)| !reserved.contains(&(&loc + &offset)))
+        });
 
         // verify the mapping by checking that each space is far enough away from the droplets
-        if let Some(mapping) = result.as_ref() {
+        if let Some(placement) = result.as_ref() {
             for droplet in snapshot.droplets.values() {
                 let corner1 = droplet.location;
                 let corner2 = &droplet.location + &droplet.dimensions;
-                for loc in mapping.values() {
+                for loc in placement.mapping.values() {
                     assert!(loc.min_distance_to_box(corner1, corner2) > 0);
                 }
             }
@@ -238,7 +353,12 @@ impl Grid {
                     }).collect()
             }).collect();
 
-        Grid { vec }
+        Grid {
+            vec,
+            storage: Vec::new(),
+            keep_out: Vec::new(),
+            waste: None,
+        }
     }
 
     // from here on out, functions only return valid locations
@@ -327,7 +447,8 @@ impl<'de> Deserialize<'de> for Grid {
     where
         D: Deserializer<'de>,
     {
-        ParsedGrid::deserialize(deserializer).map(|pg| pg.to_grid())
+        let parsed_grid = ParsedGrid::deserialize(deserializer)?;
+        parsed_grid.to_grid().map_err(de::Error::custom)
     }
 }
 
@@ -337,6 +458,23 @@ pub mod tests {
 
     use std::iter::FromIterator;
 
+    use plan::{FirstFit, Transform};
+
+    fn first_fit<'a>(
+        shape: &'a Grid,
+        name: Option<&'a str>,
+        transforms: &'a [Transform],
+    ) -> PlaceRequest<'a> {
+        PlaceRequest {
+            shape,
+            name,
+            transforms,
+            inputs: &[],
+            target: None,
+            strategy: &FirstFit,
+        }
+    }
+
     #[test]
     fn test_connected() {
         let el = || {
@@ -347,9 +485,15 @@ pub mod tests {
         };
         let grid1 = Grid {
             vec: vec![vec![None, el()], vec![el(), None]],
+            storage: vec![],
+            keep_out: vec![],
+            waste: None,
         };
         let grid2 = Grid {
             vec: vec![vec![el(), el()], vec![None, None]],
+            storage: vec![],
+            keep_out: vec![],
+            waste: None,
         };
 
         assert!(!grid1.is_connected());
@@ -378,9 +522,45 @@ pub mod tests {
         let snapshot = &Snapshot::default();
         let bad_edges = &Set::default();
 
-        let map = grid.place(&small_grid, snapshot, bad_edges).unwrap();
+        let transforms = Transform::all();
+        let req = first_fit(&small_grid, None, &transforms);
+        let map = grid.place(&req, snapshot, bad_edges).unwrap();
+
+        assert_eq!(map.mapping.get(&Location { y: 0, x: 0 }), Some(&heater_loc));
+    }
+
+    #[test]
+    fn test_place_keep_out() {
+        let mut grid = Grid::rectangle(3, 3);
+        grid.keep_out.push(KeepOut {
+            location: Location { y: 0, x: 0 },
+            dimensions: Location { y: 3, x: 1 },
+            allow: vec!["heat".into()],
+        });
+
+        let shape = Grid::rectangle(3, 2);
+        let snapshot = &Snapshot::default();
+        let bad_edges = &Set::default();
+        let transforms = &Transform::preserving_axes();
+        let place = |name| {
+            let req = first_fit(&shape, name, transforms);
+            grid.place(&req, snapshot, bad_edges).unwrap()
+        };
+
+        // nothing else can go in the left column
+        for name in &[None, Some("move")] {
+            let map = place(*name);
+            assert_eq!(
+                map.mapping[&Location { y: 0, x: 0 }],
+                Location { y: 0, x: 1 }
+            );
+        }
 
-        assert_eq!(map.get(&Location { y: 0, x: 0 }), Some(&heater_loc));
+        let map = place(Some("heat"));
+        assert_eq!(
+            map.mapping[&Location { y: 0, x: 0 }],
+            Location { y: 0, x: 0 }
+        );
     }
 
     #[test]
@@ -399,10 +579,12 @@ pub mod tests {
 
         let snapshot = &Snapshot::default();
         let bad_edges = &Set::default();
-        let map = grid.place(&grid, snapshot, bad_edges).unwrap();
+        let transforms = Transform::all();
+        let req = first_fit(&grid, None, &transforms);
+        let map = grid.place(&req, snapshot, bad_edges).unwrap();
 
         let identity_locs: Map<Location, Location> =
             Map::from_iter(grid.locations().map(|(loc, _)| (loc, loc)));
-        assert_eq!(&identity_locs, &map);
+        assert_eq!(&identity_locs, &map.mapping);
     }
 }
@@ -1,14 +1,18 @@
 use rand::Rng;
 use std::collections::VecDeque;
+use std::mem;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
 
 use pathfinding::kuhn_munkres::kuhn_munkres_min;
 use pathfinding::matrix::Matrix;
 
+use command;
 use command::Command;
 use grid::droplet::{Blob, SimpleBlob};
 use grid::Electrode;
-use plan::Path;
-use process::ProcessId;
+use plan::{Path, Placement, Plan, PlanError, RouteBudget, Tick};
+use process::{ProcessId, PuddleError};
 use util::collections::{Map, Set};
 
 #[cfg(feature = "pi")]
@@ -22,6 +26,16 @@ pub struct GridView {
     planned: VecDeque<Snapshot>,
     pub done: bool,
     pub bad_edges: Set<(Location, Location)>,
+    // reservations over time, so commands can be planned into the past
+    pub plan: Plan,
+    // the tick at which each process's last command finishes
+    pub process_ready: Map<ProcessId, Tick>,
+    // processes that were killed, which can't plan anything anymore
+    pub killed: Set<ProcessId>,
+    // how hard `route` tries once prioritized routing fails
+    pub route_budget: RouteBudget,
+    // the holds the executor has started, and when each one is up
+    holding: Vec<(Instant, Hold)>,
     #[cfg(feature = "pi")]
     pub pi: Option<RaspberryPi>,
 }
@@ -31,6 +45,41 @@ pub struct GridView {
 pub struct Snapshot {
     pub droplets: Map<DropletId, Droplet>,
     pub commands_to_finalize: Vec<Box<dyn Command>>,
+    pub holds: Vec<Hold>,
+}
+
+/// Keeps the heater at `location` at `temperature` for a tick. If there's
+/// a `report`, it gets what the heater measured, or `None` if it couldn't
+/// be read. Heaters are off unless some `Hold` wants them on.
+#[derive(Debug)]
+pub struct HeaterTarget {
+    pub location: Location,
+    pub temperature: f32,
+    pub report: Option<Sender<HoldResult>>,
+}
+
+/// What a process hears at the end of each stage of a `Hold`: what the
+/// heater measured, if anything did
+pub type HoldResult = Result<Option<f32>, PlanError>;
+
+/// One stretch of a `Hold`, and the heater under the droplet to keep at a
+/// temperature meanwhile, if any
+#[derive(Debug, Clone)]
+pub struct HoldStage {
+    pub duration: Duration,
+    pub heater: Option<(Location, f32)>,
+}
+
+/// Keeps `droplet` pinned where it is through each of `stages` in turn,
+/// starting once the executor gets to the snapshot the hold was planned
+/// in. The executor checks the deadlines every tick, so a long wait only
+/// takes up a single planned snapshot instead of one per tick. `report`
+/// hears about the end of every stage.
+#[derive(Debug)]
+pub struct Hold {
+    pub droplet: DropletId,
+    pub stages: Vec<HoldStage>,
+    pub report: Sender<HoldResult>,
 }
 
 #[derive(Debug, PartialEq)]
@@ -181,7 +230,10 @@ impl Snapshot {
             .iter()
             .map(|(&id, blob)| {
                 let d = self.droplets.get_mut(&id).unwrap();
-                let d_new = blob.to_droplet(id);
+                let mut d_new = blob.to_droplet(id);
+                // the camera only sees how big a droplet is, so keep the
+                // concentrations we already knew
+                d_new.composition = d.part_composition(d_new.volume);
                 if d.location != d_new.location || d.dimensions != d_new.dimensions {
                     info!("Found error in droplet {:?}", id);
                     debug!("Droplet error\n  Expected: {:#?}\n  Found: {:#?}", d, d_new);
@@ -195,6 +247,7 @@ impl Snapshot {
                     )
                 }
                 d.volume = d_new.volume;
+                d.composition = d_new.composition.clone();
                 (id, d_new)
             }).collect();
 
@@ -202,11 +255,13 @@ impl Snapshot {
             let mut new_snapshot = Snapshot {
                 droplets: new_droplets,
                 commands_to_finalize: Vec::new(),
+                holds: Vec::new(),
             };
             ::std::mem::swap(
                 &mut new_snapshot.commands_to_finalize,
                 &mut self.commands_to_finalize,
             );
+            ::std::mem::swap(&mut new_snapshot.holds, &mut self.holds);
             Some(new_snapshot)
         } else {
             None
@@ -295,16 +350,23 @@ impl GridView {
         };
 
         GridView {
+            plan: Plan::new(grid.clone()),
             grid: grid,
             planned,
             completed: Vec::new(),
             done: false,
             bad_edges: Set::new(),
+            process_ready: Map::new(),
+            killed: Set::new(),
+            route_budget: RouteBudget::default(),
+            holding: Vec::new(),
             #[cfg(feature = "pi")]
             pi,
         }
     }
 
+    /// Lets the executor stop once everything planned has run. This shuts
+    /// down the whole board, not just one process.
     pub fn close(&mut self) {
         info!("Marking gridview as DONE!");
         self.done = true;
@@ -338,9 +400,73 @@ impl GridView {
         #[cfg(feature = "pi")]
         snapshot.finalize(self.pi.as_mut());
 
+        let started = Instant::now();
+        for hold in snapshot.holds.drain(..) {
+            debug!("Holding {:?} through {:?}", hold.droplet, hold.stages);
+            self.holding.push((started + hold.stages[0].duration, hold));
+        }
+
         self.completed.push(snapshot);
     }
 
+    /// The heaters that held droplets need, the ones whose stage is up at
+    /// `now` getting read, see `release_holds`
+    #[cfg(feature = "pi")]
+    pub fn heater_targets(&self, now: Instant) -> Vec<HeaterTarget> {
+        self.holding
+            .iter()
+            .filter_map(|(deadline, hold)| {
+                let (location, temperature) = hold.stages[0].heater?;
+                let report = if *deadline <= now {
+                    Some(hold.report.clone())
+                } else {
+                    None
+                };
+                Some(HeaterTarget {
+                    location,
+                    temperature,
+                    report,
+                })
+            })
+            .collect()
+    }
+
+    /// Moves holds whose stage is up at `now` on to their next stage, and
+    /// lets go of the droplets that are done. The executor calls this every
+    /// tick, whether or not there was anything to execute.
+    pub fn release_holds(&mut self, now: Instant) {
+        let (done, holding) = self
+            .holding
+            .drain(..)
+            .partition(|(deadline, _)| *deadline <= now);
+        self.holding = holding;
+
+        for (_, mut hold) in done {
+            let stage = hold.stages.remove(0);
+            // on hardware the heater reported when it was read
+            if stage.heater.is_none() || !self.on_hardware() {
+                // whoever was waiting might not be around anymore
+                let _ = hold.report.send(Ok(None));
+            }
+            match hold.stages.first().map(|next| next.duration) {
+                Some(duration) => self.holding.push((now + duration, hold)),
+                None => {
+                    debug!("Done holding {:?}", hold.droplet);
+                    self.unpin(hold.droplet);
+                }
+            }
+        }
+    }
+
+    /// Lets the planner move `id` again, if it's still around
+    fn unpin(&mut self, id: DropletId) {
+        self.snapshot_ensure();
+        self.update_all(id, |d| d.pinned = false);
+        if let Some(droplet) = self.snapshot().droplets.get(&id).cloned() {
+            self.plan.update_droplet(&droplet);
+        }
+    }
+
     pub fn snapshot(&self) -> &Snapshot {
         self.planned.back().unwrap()
     }
@@ -361,18 +487,92 @@ impl GridView {
         self.completed.last().unwrap()
     }
 
+    /// Every snapshot that has been executed, one per tick
+    pub fn completed(&self) -> &[Snapshot] {
+        &self.completed
+    }
+
+    /// Whether the droplets are really on a board, rather than simulated
+    #[cfg(feature = "pi")]
+    pub fn on_hardware(&self) -> bool {
+        self.pi.is_some()
+    }
+
+    #[cfg(not(feature = "pi"))]
+    pub fn on_hardware(&self) -> bool {
+        false
+    }
+
+    /// The absolute tick of the first planned snapshot
+    pub fn now(&self) -> Tick {
+        self.completed.len()
+    }
+
+    /// The absolute tick of the last planned snapshot
+    pub fn last_tick(&self) -> Tick {
+        self.now() + self.planned.len() - 1
+    }
+
     fn tick(&mut self) {
-        let new_snapshot = {
-            let just_planned = self.planned.back().unwrap();
-            if let Some(col) = just_planned.get_collision() {
-                panic!("collision: {:#?}", col);
-            };
+        let back = self.planned.len() - 1;
+        self.tick_from(back);
+    }
 
-            just_planned.new_with_same_droplets()
-        };
+    /// Finishes planning the snapshot at `index`, returning the index of the
+    /// next one. A new snapshot is made if `index` was the last one.
+    fn tick_from(&mut self, index: usize) -> usize {
+        if index + 1 == self.planned.len() {
+            let new_snapshot = self.planned[index].new_with_same_droplets();
+            self.planned.push_back(new_snapshot);
+        }
+
+        trace!("TICK! index={}, len={}", index + 1, self.planned.len());
+        index + 1
+    }
+
+    /// The planned snapshot at the absolute tick `tick`
+    pub fn snapshot_at(&self, tick: Tick) -> &Snapshot {
+        &self.planned[tick - self.now()]
+    }
 
-        self.planned.push_back(new_snapshot);
-        trace!("TICK! len={}", self.planned.len());
+    /// Applies `func` to the droplet in every planned snapshot it's in
+    pub fn update_all(&mut self, id: DropletId, func: impl Fn(&mut Droplet)) {
+        for snapshot in self.planned.iter_mut() {
+            if let Some(droplet) = snapshot.droplets.get_mut(&id) {
+                func(droplet);
+            }
+        }
+    }
+
+    /// Makes sure there is a planned snapshot at `index`
+    fn extend_to(&mut self, index: usize) {
+        while self.planned.len() <= index {
+            let new_snapshot = self.snapshot().new_with_same_droplets();
+            self.planned.push_back(new_snapshot);
+        }
+    }
+
+    /// Copies the droplet at `index` into all of the later snapshots, since
+    /// they were planned assuming the droplet would sit still.
+    fn propagate(&mut self, index: usize, id: DropletId) {
+        let droplet = self.planned[index].droplets.get(&id).cloned();
+        for snapshot in self.planned.iter_mut().skip(index + 1) {
+            match droplet {
+                Some(ref d) => snapshot.droplets.insert(id, d.clone()),
+                None => snapshot.droplets.remove(&id),
+            };
+        }
+    }
+
+    fn update_at(&mut self, index: usize, id: DropletId, func: impl FnOnce(&mut Droplet)) {
+        {
+            let droplet = self.planned[index]
+                .droplets
+                .get_mut(&id)
+                .unwrap_or_else(|| panic!("Tried to update a non-existent droplet: {:?}", id));
+            func(droplet);
+        }
+        self.propagate(index, id);
     }
 
     fn update(&mut self, id: DropletId, func: impl FnOnce(&mut Droplet)) {
@@ -402,10 +602,7 @@ impl GridView {
         for i in 1..max_len {
             for (&id, path) in paths.iter() {
                 if i < path.len() {
-                    self.update(id, |droplet| {
-                        assert!(droplet.location.distance_to(&path[i]) <= 1);
-                        droplet.location = path[i];
-                    });
+                    self.update(id, |droplet| droplet.location = path[i]);
                 }
             }
 
@@ -418,12 +615,15 @@ impl GridView {
     pub fn subview(
         &mut self,
         ids: impl IntoIterator<Item = DropletId>,
-        mapping: Map<Location, Location>,
+        placement: Placement,
     ) -> GridSubView {
+        let index = self.planned.len() - 1;
         GridSubView {
             backing_gridview: self,
-            mapping: mapping,
+            placement,
             ids: ids.into_iter().collect(),
+            index,
+            unmapped: None,
         }
     }
 
@@ -433,17 +633,194 @@ impl GridView {
         self.planned[just_planned].commands_to_finalize.push(cmd)
     }
 
+    /// Moves droplets along paths that end at the absolute tick `end_tick`.
+    pub fn take_paths_until(&mut self, paths: &Map<DropletId, Path>, end_tick: Tick) {
+        let end_index = end_tick - self.now();
+        self.extend_to(end_index);
+
+        for (&id, path) in paths.iter() {
+            let start_index = end_index + 1 - path.len();
+            assert_eq!(self.planned[start_index].droplets[&id].location, path[0]);
+            for (i, loc) in path.iter().enumerate().skip(1) {
+                self.update_at(start_index + i, id, |droplet| droplet.location = *loc);
+            }
+        }
+    }
+
+    /// A subview that starts planning at the absolute tick `tick`, rather than
+    /// at the end of the planned snapshots.
+    pub fn subview_at(
+        &mut self,
+        tick: Tick,
+        ids: impl IntoIterator<Item = DropletId>,
+        placement: Placement,
+    ) -> GridSubView {
+        let index = tick - self.now();
+        self.extend_to(index);
+        GridSubView {
+            backing_gridview: self,
+            placement,
+            ids: ids.into_iter().collect(),
+            index,
+            unmapped: None,
+        }
+    }
+
+    /// Registers `cmd` to be finalized at the absolute tick `tick`.
+    pub fn register_at(&mut self, tick: Tick, cmd: Box<dyn Command>) {
+        let index = tick - self.now();
+        self.planned[index].commands_to_finalize.push(cmd)
+    }
+
+    /// Forgets all of the reservations in the plan, treating every droplet as
+    /// if it sat still from the last planned snapshot onwards.
+    pub fn reset_plan(&mut self) {
+        let mut plan = Plan::new(self.grid.clone());
+        plan.min_tick = self.last_tick();
+        mem::swap(&mut plan.strategy, &mut self.plan.strategy);
+        mem::swap(&mut plan.targets, &mut self.plan.targets);
+        self.plan = plan;
+        self.sync_plan();
+    }
+
+    /// Lets the plan know about any droplets it's missing, and forgets the
+    /// ones that aren't around anymore.
+    pub fn sync_plan(&mut self) {
+        let since = self.last_tick();
+        let now = self.now();
+        let snapshot = self.planned.back().unwrap();
+
+        self.plan.bad_edges = self.bad_edges.clone();
+        self.plan.prune(now);
+        self.plan
+            .retain_droplets(|id| snapshot.droplets.contains_key(id));
+        for (id, droplet) in &snapshot.droplets {
+            if !self.plan.has_droplet(id) {
+                self.plan.add_droplet(droplet.clone(), since);
+            }
+        }
+    }
+
     pub fn rollback(&mut self, new_snapshot: &Snapshot) {
         let old_planned: Vec<_> = self.planned.drain(..).collect();
         self.planned
             .push_back(new_snapshot.new_with_same_droplets());
         assert_eq!(self.planned.len(), 1);
+        self.reset_plan();
 
         for planned_snapshot in old_planned {
             planned_snapshot.abort(self)
         }
     }
 
+    /// Stops `pid` for good: every command it has planned is aborted, its
+    /// droplets are discarded, and it can't plan anything else. Its droplets
+    /// stay where they are until they're discarded, unless another process
+    /// was planned around them moving, in which case they go wherever the
+    /// aborted commands were taking them.
+    pub fn kill(&mut self, pid: ProcessId) {
+        info!("Killing process {}", pid);
+        for snapshot in self.planned.iter_mut() {
+            let (killed, kept) = snapshot
+                .commands_to_finalize
+                .drain(..)
+                .partition(|cmd| cmd.process_id() == Some(pid));
+            snapshot.commands_to_finalize = kept;
+            for mut cmd in killed {
+                cmd.abort(PlanError::ProcessKilled(pid));
+            }
+            // the heaters only run for holds, so this turns them off too
+            snapshot.holds.retain(|hold| hold.droplet.process_id != pid);
+        }
+        let (killed, holding) = self
+            .holding
+            .drain(..)
+            .partition(|(_, hold)| hold.droplet.process_id == pid);
+        self.holding = holding;
+        for (_, hold) in killed {
+            let _ = hold.report.send(Err(PlanError::ProcessKilled(pid)));
+        }
+
+        self.snapshot_ensure();
+        let checkpoint = self.checkpoint();
+        let now = self.now();
+        self.freeze(pid);
+        if let Err(violation) = self.validate_from(now) {
+            warn!(
+                "Process {}'s droplets can't stay put, letting them move: {:?}",
+                pid, violation
+            );
+            self.restore(checkpoint);
+        }
+        // its droplets have to be movable to be discarded
+        for snapshot in self.planned.iter_mut() {
+            for d in snapshot.droplets.values_mut() {
+                if d.id.process_id == pid {
+                    d.pinned = false;
+                }
+            }
+        }
+        self.trim_idle();
+        self.process_ready.remove(&pid);
+        self.reset_plan();
+
+        let ids: Vec<DropletId> = self
+            .snapshot()
+            .droplets
+            .keys()
+            .filter(|id| id.process_id == pid)
+            .cloned()
+            .collect();
+        for id in ids {
+            let result = match command::discard(self, id) {
+                Ok(cmd) => self
+                    .plan(cmd)
+                    .map_err(|(_cmd, err)| PuddleError::PlanError(err)),
+                Err(err) => Err(err),
+            };
+            if let Err(err) = result {
+                warn!("Couldn't discard {:?}, leaving it parked: {:?}", id, err);
+            }
+        }
+
+        self.killed.insert(pid);
+    }
+
+    /// Drops the planned snapshots off the end where nothing happens anymore
+    fn trim_idle(&mut self) {
+        while self.planned.len() > 1 {
+            let n = self.planned.len();
+            let (prev, last) = (&self.planned[n - 2], &self.planned[n - 1]);
+            let idle = last.commands_to_finalize.is_empty()
+                && last.holds.is_empty()
+                && last.droplet_info(None) == prev.droplet_info(None);
+            if !idle {
+                break;
+            }
+            self.planned.pop_back();
+        }
+    }
+
+    /// Cuts `pid`'s droplets out of the planned snapshots after the first,
+    /// leaving every droplet it has now where it is
+    fn freeze(&mut self, pid: ProcessId) {
+        let frozen: Vec<Droplet> = self.planned[0]
+            .droplets
+            .values()
+            .filter(|d| d.id.process_id == pid)
+            .map(|d| Droplet {
+                destination: None,
+                ..d.clone()
+            })
+            .collect();
+        for snapshot in self.planned.iter_mut().skip(1) {
+            snapshot.droplets.retain(|id, _| id.process_id != pid);
+            for droplet in &frozen {
+                snapshot.droplets.insert(droplet.id, droplet.clone());
+            }
+        }
+    }
+
     pub fn perturb(&self, rng: &mut impl Rng, snapshot: &Snapshot) -> Option<Snapshot> {
         let now = snapshot;
         let then = self.completed.last()?;
@@ -484,15 +861,175 @@ impl GridView {
     }
 }
 
+/// Something wrong with the snapshots that a command planned. These mean
+/// the command (or the planner) has a bug, so the command gets thrown out
+/// instead of being executed.
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub enum Violation {
+    /// two droplets that aren't supposed to touch got too close
+    Collision {
+        tick: Tick,
+        droplets: (DropletId, DropletId),
+    },
+    /// a droplet moved more than one cell in a single tick
+    Jump {
+        tick: Tick,
+        droplet: DropletId,
+        from: Location,
+        to: Location,
+    },
+    /// a droplet is (at least partly) somewhere there's no electrode
+    OffGrid {
+        tick: Tick,
+        droplet: DropletId,
+        location: Location,
+    },
+    /// a command moved a droplet out of the area it was placed in
+    Unmapped {
+        tick: Tick,
+        droplet: DropletId,
+        location: Location,
+    },
+}
+
+/// Enough of the planned snapshots to undo whatever a command planned.
+/// Commands waiting to be finalized can't be copied, so only how many
+/// there were gets saved; a failed command only ever adds to them. The
+/// same goes for holds.
+#[derive(Clone)]
+pub struct Checkpoint {
+    droplets: Vec<Map<DropletId, Droplet>>,
+    n_commands: Vec<usize>,
+    n_holds: Vec<usize>,
+    process_ready: Map<ProcessId, Tick>,
+}
+
+impl GridView {
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            droplets: self.planned.iter().map(|s| s.droplets.clone()).collect(),
+            n_commands: self
+                .planned
+                .iter()
+                .map(|s| s.commands_to_finalize.len())
+                .collect(),
+            n_holds: self.planned.iter().map(|s| s.holds.len()).collect(),
+            process_ready: self.process_ready.clone(),
+        }
+    }
+
+    /// Puts the planned snapshots back the way they were at `checkpoint`
+    pub fn restore(&mut self, checkpoint: Checkpoint) {
+        let len = checkpoint.droplets.len();
+        self.planned.truncate(len);
+        let saved = checkpoint
+            .droplets
+            .into_iter()
+            .zip(checkpoint.n_commands)
+            .zip(checkpoint.n_holds);
+        for (snapshot, ((droplets, n_commands), n_holds)) in self.planned.iter_mut().zip(saved) {
+            snapshot.droplets = droplets;
+            snapshot.commands_to_finalize.truncate(n_commands);
+            snapshot.holds.truncate(n_holds);
+        }
+        assert_eq!(self.planned.len(), len);
+        self.process_ready = checkpoint.process_ready;
+    }
+
+    /// Checks every planned snapshot for droplets that collide, jump, or
+    /// fall off the grid.
+    pub fn validate(&self) -> Result<(), Violation> {
+        let now = self.now();
+        self.validate_from(now)
+    }
+
+    /// Like `validate`, but only checks the planned snapshots from the
+    /// absolute tick `tick` on. Planning a command only changes the snapshots
+    /// from where it starts, so the ones before it were already checked.
+    pub fn validate_from(&self, tick: Tick) -> Result<(), Violation> {
+        let now = self.now();
+        let start = tick.max(now) - now;
+        for (i, snapshot) in self.planned.iter().enumerate().skip(start) {
+            let tick = now + i;
+
+            if let Some((_, d1, d2)) = snapshot.get_collision() {
+                return Err(Violation::Collision {
+                    tick,
+                    droplets: (d1.id, d2.id),
+                });
+            }
+
+            for droplet in snapshot.droplets.values() {
+                if let Some(location) = self.off_grid(droplet) {
+                    return Err(Violation::OffGrid {
+                        tick,
+                        droplet: droplet.id,
+                        location,
+                    });
+                }
+            }
+
+            if i == 0 {
+                continue;
+            }
+            for (id, droplet) in &snapshot.droplets {
+                if let Some(before) = self.planned[i - 1].droplets.get(id) {
+                    if before.location.distance_to(&droplet.location) > 1 {
+                        return Err(Violation::Jump {
+                            tick,
+                            droplet: *id,
+                            from: before.location,
+                            to: droplet.location,
+                        });
+                    }
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// The first cell of the droplet that isn't on the grid, if any
+    fn off_grid(&self, droplet: &Droplet) -> Option<Location> {
+        for y in 0..droplet.dimensions.y {
+            for x in 0..droplet.dimensions.x {
+                let loc = &droplet.location + &Location { y, x };
+                if self.grid.get_cell(&loc).is_none() {
+                    return Some(loc);
+                }
+            }
+        }
+        None
+    }
+}
+
 pub struct GridSubView<'a> {
     backing_gridview: &'a mut GridView,
-    mapping: Map<Location, Location>,
+    // maps the command's coordinates onto the grid, possibly turned around
+    placement: Placement,
     ids: Set<DropletId>,
+    // which planned snapshot this subview is working on
+    index: usize,
+    // the first time the command let a droplet leave its placement
+    unmapped: Option<Violation>,
 }
 
 impl<'a> GridSubView<'a> {
     pub fn tick(&mut self) {
-        self.backing_gridview.tick()
+        self.index = self.backing_gridview.tick_from(self.index)
+    }
+
+    /// The absolute tick this subview is currently planning
+    pub fn current_tick(&self) -> Tick {
+        self.backing_gridview.now() + self.index
+    }
+
+    /// Whether the command kept its droplets inside its placement
+    pub fn check(&self) -> Result<(), Violation> {
+        match self.unmapped {
+            Some(ref violation) => Err(violation.clone()),
+            None => Ok(()),
+        }
     }
 
     #[cfg(feature = "pi")]
@@ -501,55 +1038,78 @@ impl<'a> GridSubView<'a> {
     }
 
     pub fn get_electrode(&self, loc: &Location) -> Option<&Electrode> {
-        let actual_loc = self.mapping.get(loc)?;
+        let actual_loc = self.placement.mapping.get(loc)?;
         self.backing_gridview.grid.get_cell(&actual_loc)
     }
 
-    // TODO: translate or somehow hide the untranslated location of this
-    pub fn get(&self, id: &DropletId) -> &Droplet {
-        assert!(self.ids.contains(&id));
-        &self.backing_gridview.snapshot().droplets[id]
+    /// Plans a hold in the snapshot being planned, see `Hold`. The heaters
+    /// are in the command's coordinates.
+    pub fn hold(&mut self, mut hold: Hold) {
+        for stage in &mut hold.stages {
+            if let Some((ref mut loc, _)) = stage.heater {
+                *loc = self.placement.mapping[loc];
+            }
+        }
+        let snapshot = &mut self.backing_gridview.planned[self.index];
+        snapshot.holds.push(hold);
+    }
+
+    /// Takes a droplet on the grid into the command's coordinates
+    fn unmap(&self, mut droplet: Droplet) -> Droplet {
+        let transform = self.placement.transform;
+        let inverse = transform.inverse();
+        // any cell will do to line the two coordinate systems up
+        let (origin, mapped_origin) = self.placement.mapping.iter().next().unwrap();
+        let loc = origin + &inverse.apply(&(&droplet.location - mapped_origin));
+        droplet.location = inverse.apply_box(&loc, &droplet.dimensions);
+        droplet.dimensions = inverse.apply_dimensions(&droplet.dimensions);
+        droplet
     }
 
-    fn get_mut(&mut self, id: &DropletId) -> &mut Droplet {
+    /// A copy of the droplet, in the command's coordinates
+    pub fn get(&self, id: &DropletId) -> Droplet {
         assert!(self.ids.contains(&id));
-        self.backing_gridview
-            .snapshot_mut()
-            .droplets
-            .get_mut(id)
-            .unwrap()
+        self.unmap(self.backing_gridview.planned[self.index].droplets[id].clone())
     }
 
+    /// Inserts a droplet given in the command's coordinates
     pub fn insert(&mut self, mut droplet: Droplet) {
-        let new_loc = self.mapping.get(&droplet.location);
+        droplet.dimensions = self
+            .placement
+            .transform
+            .apply_dimensions(&droplet.dimensions);
+        let new_loc = self
+            .placement
+            .droplet_location(&droplet.location, &droplet.dimensions);
         trace!("Inserting {:#?} at {:?}", droplet, new_loc);
-        droplet.location = *new_loc.unwrap();
+        droplet.location = new_loc.unwrap();
         let was_not_there = self.ids.insert(droplet.id);
         assert!(was_not_there);
-        let snapshot = self.backing_gridview.snapshot_mut();
-        let was_there = snapshot.droplets.insert(droplet.id, droplet);
+        let id = droplet.id;
+        let snapshot = &mut self.backing_gridview.planned[self.index];
+        let was_there = snapshot.droplets.insert(id, droplet);
         assert!(was_there.is_none());
+        self.backing_gridview.propagate(self.index, id);
     }
 
     pub fn remove(&mut self, id: &DropletId) -> Droplet {
         let was_there = self.ids.remove(id);
         assert!(was_there);
-        let snapshot = self.backing_gridview.snapshot_mut();
-        let mut droplet = snapshot.droplets.remove(id).unwrap();
-        // FIXME this is pretty dumb
-        let (unmapped_loc, _) = self
-            .mapping
-            .iter()
-            .find(|(_, &v)| v == droplet.location)
-            .unwrap();
-        droplet.location = *unmapped_loc;
-        droplet
+        let mut droplet = {
+            let snapshot = &mut self.backing_gridview.planned[self.index];
+            snapshot.droplets.remove(id).unwrap()
+        };
+        self.backing_gridview.propagate(self.index, *id);
+        self.unmap(droplet)
     }
 
-    fn check_droplet(&self, id: &DropletId) {
-        // TODO will this have translated or real location??
-        let droplet = self.get(id);
-        let mapped_to: Set<_> = self.mapping.values().collect();
+    fn check_droplet(&mut self, id: &DropletId) {
+        if self.unmapped.is_some() {
+            return;
+        }
+        // this checks the real location, not the command's
+        let droplet = &self.backing_gridview.planned[self.index].droplets[id];
+        let mapped_to: Set<_> = self.placement.mapping.values().collect();
         // TODO this is pretty slow
         for i in 0..droplet.dimensions.y {
             for j in 0..droplet.dimensions.x {
@@ -558,43 +1118,54 @@ impl<'a> GridSubView<'a> {
                     x: droplet.location.x + j,
                 };
                 if !mapped_to.contains(&loc) {
-                    panic!("{} was unmapped!, mapping: {:#?}", loc, self.mapping);
+                    warn!(
+                        "{} was unmapped!, mapping: {:#?}",
+                        loc, self.placement.mapping
+                    );
+                    self.unmapped = Some(Violation::Unmapped {
+                        tick: self.current_tick(),
+                        droplet: *id,
+                        location: loc,
+                    });
+                    return;
                 }
             }
         }
     }
 
     fn update(&mut self, id: &DropletId, func: impl FnOnce(&mut Droplet)) {
-        func(self.get_mut(id));
+        assert!(self.ids.contains(&id));
+        self.backing_gridview.update_at(self.index, *id, func);
         self.check_droplet(id);
     }
 
-    pub fn move_west(&mut self, id: DropletId) {
-        trace!("Moving droplet {:?} west", id);
+    // directions are in the command's coordinates, so they get turned
+    // around with the placement
+    pub fn move_by(&mut self, id: DropletId, direction: Location) {
+        let delta = self.placement.transform.apply(&direction);
         self.update(&id, |droplet| {
-            droplet.location = droplet.location.west();
+            droplet.location = &droplet.location + &delta;
         })
     }
 
+    pub fn move_west(&mut self, id: DropletId) {
+        trace!("Moving droplet {:?} west", id);
+        self.move_by(id, Location { y: 0, x: -1 })
+    }
+
     pub fn move_east(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} east", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.east();
-        })
+        self.move_by(id, Location { y: 0, x: 1 })
     }
 
     pub fn move_north(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} north", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.north();
-        })
+        self.move_by(id, Location { y: -1, x: 0 })
     }
 
     pub fn move_south(&mut self, id: DropletId) {
         trace!("Moving droplet {:?} south", id);
-        self.update(&id, |droplet| {
-            droplet.location = droplet.location.south();
-        })
+        self.move_by(id, Location { y: 1, x: 0 })
     }
 }
 
@@ -773,4 +1344,94 @@ pub mod tests {
         assert_eq!(error_edges.len(), 1);
         assert_eq!(error_edges[0], (from, to));
     }
+
+    #[test]
+    fn test_validate_restore() {
+        let mut gv = parse_gridview(&["a........", ".........", "......b.."]);
+        assert_eq!(gv.validate(), Ok(()));
+        let checkpoint = gv.checkpoint();
+
+        // a jumps right next to b, which is both a jump and a collision,
+        // but the collision is found first
+        let mut next = gv.planned[0].new_with_same_droplets();
+        next.droplets.get_mut(&c2id('a')).unwrap().location = Location { y: 2, x: 5 };
+        gv.planned.push_back(next);
+        match gv.validate() {
+            Err(Violation::Collision { droplets, .. }) => {
+                assert_eq!(droplets, (c2id('a'), c2id('b')))
+            }
+            other => panic!("expected a collision, got {:?}", other),
+        }
+
+        // farther from b, now it's only a jump
+        gv.planned[1].droplets.get_mut(&c2id('a')).unwrap().location = Location { y: 0, x: 3 };
+        assert_eq!(
+            gv.validate(),
+            Err(Violation::Jump {
+                tick: 1,
+                droplet: c2id('a'),
+                from: Location { y: 0, x: 0 },
+                to: Location { y: 0, x: 3 },
+            })
+        );
+
+        // the jump is into tick 1, so checking from after it finds nothing
+        let next = gv.planned[1].new_with_same_droplets();
+        gv.planned.push_back(next);
+        assert!(gv.validate_from(1).is_err());
+        assert_eq!(gv.validate_from(2), Ok(()));
+
+        gv.restore(checkpoint);
+        assert_eq!(gv.planned.len(), 1);
+        assert_eq!(gv.validate(), Ok(()));
+    }
+
+    #[test]
+    fn kill_stops_droplets() {
+        let _ = env_logger::try_init();
+        let mut gv = GridView::new(Grid::rectangle(10, 10));
+        let id = |id, process_id| DropletId { id, process_id };
+        let run_ticks = |gv: &mut GridView, n| {
+            for _ in 0..n {
+                match gv.execute() {
+                    ExecResponse::Step(snapshot) => gv.commit_pending(snapshot),
+                    resp => panic!("nothing left to run: {:?}", resp),
+                }
+            }
+        };
+
+        let loc = Some(Location { y: 0, x: 0 });
+        let create = command::Create::new(loc, 1.0, None, id(0, 0)).unwrap();
+        gv.plan(Box::new(create)).unwrap();
+        let n = gv.planned.len();
+        run_ticks(&mut gv, n);
+
+        // process 0 heads across the board, process 1 minds its own business
+        let dest = Location { y: 8, x: 8 };
+        let cmd = command::Move::new(id(0, 0), dest, id(1, 0)).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+        let loc = Some(Location { y: 8, x: 0 });
+        let create = command::Create::new(loc, 1.0, None, id(0, 1)).unwrap();
+        gv.plan(Box::new(create)).unwrap();
+        let cmd = command::Agitate::new(id(0, 1), id(1, 1)).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+
+        run_ticks(&mut gv, 3);
+        let stopped_at = gv.planned[0].droplets[&id(0, 0)].location;
+        assert_ne!(stopped_at, Location { y: 0, x: 0 });
+
+        gv.kill(0);
+        assert!(gv.validate().is_ok());
+        for snapshot in &gv.planned {
+            for droplet in snapshot.droplets.values() {
+                if droplet.id.process_id == 0 {
+                    assert_eq!(droplet.id, id(0, 0));
+                    assert_eq!(droplet.location, stopped_at);
+                }
+            }
+        }
+        let last = gv.snapshot();
+        assert!(last.droplets.keys().all(|id| id.process_id != 0));
+        assert!(last.droplets.contains_key(&id(1, 1)));
+    }
 }
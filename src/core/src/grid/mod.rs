@@ -5,6 +5,8 @@ mod location;
 pub mod parse;
 
 pub use self::droplet::*;
-pub use self::grid::{Electrode, Grid, Peripheral};
-pub use self::gridview::{ExecResponse, GridView, Snapshot};
+pub use self::grid::{Electrode, Grid, KeepOut, Peripheral, SensorKind, StorageZone};
+pub use self::gridview::{
+    ExecResponse, GridView, HeaterTarget, Hold, HoldResult, HoldStage, Snapshot, Violation,
+};
 pub use self::location::Location;
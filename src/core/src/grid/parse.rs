@@ -1,6 +1,7 @@
 use util::collections::Map;
 
 use grid::grid::*;
+use grid::Location;
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Mark {
@@ -26,10 +27,17 @@ pub struct ParsedGrid {
     pub board: Vec<Vec<ParsedElectrode>>,
     #[serde(default)]
     pub peripherals: Map<String, Peripheral>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub storage: Vec<StorageZone>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub keep_out: Vec<KeepOut>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub waste: Option<String>,
 }
 
 impl ParsedGrid {
-    pub fn to_grid(&self) -> Grid {
+    /// Fails if the arch file refers to anything that isn't on the board
+    pub fn to_grid(&self) -> Result<Grid, String> {
         // find a pin that higher than anything listed
         let mut next_auto_pin = self
             .board
@@ -63,6 +71,9 @@ impl ParsedGrid {
                 .iter()
                 .map(|row| row.iter().map(&mut f).collect())
                 .collect(),
+            storage: self.storage.clone(),
+            keep_out: self.keep_out.clone(),
+            waste: self.waste.clone(),
         };
 
         for (location, periph) in self.peripherals.iter() {
@@ -72,7 +83,24 @@ impl ParsedGrid {
             electrode.peripheral = Some(periph.clone());
         }
 
-        grid
+        for zone in &grid.storage {
+            let spots = zone.spots(Location { y: 1, x: 1 });
+            if spots.iter().any(|loc| grid.get_cell(loc).is_none()) {
+                return Err(format!("Storage zone {:?} isn't all on the board", zone));
+            }
+        }
+
+        if let Some(ref waste) = grid.waste {
+            let is_waste = |p: &Peripheral| match p {
+                Peripheral::Output { name, .. } => name == waste,
+                _ => false,
+            };
+            if !self.peripherals.values().any(is_waste) {
+                return Err(format!("Waste output {:?} isn't on the board", waste));
+            }
+        }
+
+        Ok(grid)
     }
 }
 
@@ -173,6 +201,63 @@ pub mod tests {
     }
 
     use env_logger;
+    #[test]
+    fn test_parse_storage() {
+        let grid: Grid = sj::from_str(
+            r#"
+            {
+                "board": [["a", "a", "a"],
+                          ["a", "a", "a"]],
+                "storage": [{"location": {"y": 0, "x": 1},
+                             "dimensions": {"y": 2, "x": 2}}]
+            }
+        "#,
+        )
+        .expect("parse failed");
+
+        let zone = &grid.storage[0];
+        assert_eq!(zone.spots(Location { y: 1, x: 1 }).len(), 4);
+        assert_eq!(
+            zone.spots(Location { y: 2, x: 2 }),
+            vec![Location { y: 0, x: 1 }]
+        );
+        assert!(zone.contains(Location { y: 1, x: 2 }, Location { y: 1, x: 1 }));
+        assert!(!zone.contains(Location { y: 1, x: 2 }, Location { y: 1, x: 2 }));
+
+        check_round_trip(grid, "storage");
+
+        let off_board = sj::from_str::<Grid>(
+            r#"
+            {
+                "board": [["a", "a", "a"]],
+                "storage": [{"location": {"y": 0, "x": 1},
+                             "dimensions": {"y": 2, "x": 2}}]
+            }
+        "#,
+        );
+        assert!(off_board.is_err());
+    }
+
+    #[test]
+    fn test_parse_waste() {
+        let with_waste = |name: &str| {
+            let json = r#"
+            {
+                "board": [["a", "a", "a"]],
+                "peripherals": {
+                    "(0, 0)": {"type": "Output", "pwm_channel": 0, "name": "waste"}
+                },
+                "waste": "NAME"
+            }
+            "#;
+            sj::from_str::<Grid>(&json.replace("NAME", name))
+        };
+
+        let grid = with_waste("waste").expect("parse failed");
+        assert_eq!(grid.waste, Some("waste".into()));
+        assert!(with_waste("nowhere").is_err());
+    }
+
     #[test]
     fn test_parse_files() {
         let mut successes = 0;
@@ -8,6 +8,7 @@ extern crate serde;
 #[macro_use]
 extern crate serde_derive;
 
+#[macro_use]
 extern crate serde_json;
 
 #[cfg(test)]
@@ -52,7 +53,10 @@ pub mod vision;
 #[cfg(feature = "pi")]
 pub mod pi;
 
-pub use exec::Executor;
+pub use command::{
+    MixOptions, MixPattern, Orientation, Reading, SenseReport, ThermocycleReport, ThermocycleStage,
+};
+pub use exec::{tick_duration, Executor};
 pub use grid::parse;
 pub use grid::{Blob, DropletId, DropletInfo, Grid, Location};
 pub use process::*;
@@ -2,6 +2,7 @@ pub mod max31865;
 pub mod mcp4725;
 pub mod pca9685;
 
+use std::collections::HashMap;
 use std::ffi::CStr;
 use std::fmt;
 use std::os::raw::{c_char, c_int, c_uint};
@@ -9,7 +10,7 @@ use std::ptr;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use grid::{Blob, Grid, Location, Peripheral, Snapshot};
+use grid::{Grid, HeaterTarget, Location, Peripheral, SensorKind, Snapshot};
 use util::{pid::PidController, seconds_duration, Timer};
 
 #[cfg(feature = "vision")]
@@ -135,6 +136,8 @@ pub struct RaspberryPi {
     pub mcp4725: Mcp4725,
     pub pca9685: Pca9685,
     pub max31865: Max31865,
+    // the heaters that `drive_heaters` is keeping warm, by pwm channel
+    heater_pids: HashMap<u8, (PidController, Timer)>,
 }
 
 impl RaspberryPi {
@@ -179,6 +182,7 @@ impl RaspberryPi {
             mcp4725,
             pca9685,
             max31865,
+            heater_pids: HashMap::new(),
         };
 
         pi.init_hv507();
@@ -245,24 +249,7 @@ impl RaspberryPi {
             panic!("Peripheral wasn't a heater!: {:#?}", heater)
         };
 
-        let mut pid = PidController::default();
-        pid.p_gain = 1.0;
-        pid.i_gain = 1.0;
-        pid.d_gain = 1.0;
-
-        use std::env;
-
-        pid.p_gain = env::var("PID_P").unwrap_or("1.0".into()).parse().unwrap();
-        pid.i_gain = env::var("PID_I").unwrap_or("1.0".into()).parse().unwrap();
-        pid.d_gain = env::var("PID_D").unwrap_or("1.0".into()).parse().unwrap();
-
-        pid.i_min = 0.0;
-        pid.i_max = pca9685::DUTY_CYCLE_MAX as f64;
-
-        pid.out_min = 0.0;
-        pid.out_max = pca9685::DUTY_CYCLE_MAX as f64;
-
-        pid.target = target_temperature;
+        let mut pid = heater_pid(target_temperature);
 
         let epsilon = 2.0; // degrees C
         let extra_delay = Duration::from_millis(20);
@@ -319,14 +306,84 @@ impl RaspberryPi {
         Ok(())
     }
 
-    pub fn get_temperature(&mut self, temp_sensor: Peripheral) -> Result<f32> {
-        if let Peripheral::Heater { spi_channel, .. } = temp_sensor {
-            // right now we can only work on the one channel
-            assert_eq!(spi_channel, 0);
-            self.max31865.read_temperature()
-        } else {
-            panic!("Not a temperature sensor!: {:#?}")
+    /// Nudges every heater with a target toward its temperature, and turns
+    /// off the ones that don't have one anymore. Unlike `heat`, this doesn't
+    /// block, so the executor can do it every tick.
+    pub fn drive_heaters(&mut self, grid: &Grid, targets: &[HeaterTarget]) {
+        let mut driven = Vec::new();
+        for target in targets {
+            let peripheral = grid
+                .get_cell(&target.location)
+                .and_then(|electrode| electrode.peripheral.clone());
+            let pwm_channel = match peripheral {
+                Some(Peripheral::Heater { pwm_channel, .. }) => pwm_channel,
+                _ => panic!("No heater at {}", target.location),
+            };
+            driven.push(pwm_channel);
+
+            let reading = self.drive_heater(pwm_channel, target.temperature);
+            if let Err(ref e) = reading {
+                error!("Couldn't drive the heater at {}: {:?}", target.location, e);
+            }
+            if let Some(ref report) = target.report {
+                // whoever wanted the reading might not be around anymore
+                let _ = report.send(Ok(reading.ok()));
+            }
+        }
+
+        let idle: Vec<u8> = self
+            .heater_pids
+            .keys()
+            .filter(|channel| !driven.contains(channel))
+            .cloned()
+            .collect();
+        for channel in idle {
+            self.heater_pids.remove(&channel);
+            if let Err(e) = self.pca9685.set_duty_cycle(channel, 0) {
+                error!("Couldn't turn off heater {}: {:?}", channel, e);
+            }
+        }
+    }
+
+    // one iteration of the loop in `heat`, giving back what it measured
+    fn drive_heater(&mut self, pwm_channel: u8, target_temperature: f32) -> Result<f32> {
+        let target_temperature = f64::from(target_temperature);
+        let measured = self.max31865.read_one_temperature()?;
+
+        let (pid, timer) = self
+            .heater_pids
+            .entry(pwm_channel)
+            .or_insert_with(|| (heater_pid(target_temperature), Timer::new()));
+        // a new stage might want a new temperature
+        pid.target = target_temperature;
+        let dt = timer.lap();
+        let mut duty_cycle = pid.update(f64::from(measured), &dt);
+        if f64::from(measured) > target_temperature {
+            duty_cycle = 0.0;
         }
+        debug!(
+            "Driving heater {} to {}*C, measured: {}*C, duty_cycle: {}",
+            pwm_channel, target_temperature, measured, duty_cycle
+        );
+
+        self.pca9685
+            .set_duty_cycle(pwm_channel, duty_cycle as u16)?;
+        Ok(measured)
+    }
+
+    pub fn get_temperature(&mut self, temp_sensor: Peripheral) -> Result<f32> {
+        let spi_channel = match temp_sensor {
+            Peripheral::Heater { spi_channel, .. } => spi_channel,
+            // there's only the one probe, so sensors don't get a channel
+            Peripheral::Sensor {
+                kind: SensorKind::Temperature,
+                ..
+            } => 0,
+            _ => panic!("Not a temperature sensor!: {:#?}", temp_sensor),
+        };
+        // right now we can only work on the one channel
+        assert_eq!(spi_channel, 0);
+        self.max31865.read_temperature()
     }
 
     // FIXME HACK
@@ -434,6 +491,26 @@ impl RaspberryPi {
     }
 }
 
+// a controller for getting a heater to `target_temperature`, the gains can
+// be tuned with PID_P, PID_I, and PID_D
+fn heater_pid(target_temperature: f64) -> PidController {
+    use std::env;
+
+    let mut pid = PidController::default();
+    pid.p_gain = env::var("PID_P").unwrap_or("1.0".into()).parse().unwrap();
+    pid.i_gain = env::var("PID_I").unwrap_or("1.0".into()).parse().unwrap();
+    pid.d_gain = env::var("PID_D").unwrap_or("1.0".into()).parse().unwrap();
+
+    pid.i_min = 0.0;
+    pid.i_max = pca9685::DUTY_CYCLE_MAX as f64;
+
+    pid.out_min = 0.0;
+    pid.out_max = pca9685::DUTY_CYCLE_MAX as f64;
+
+    pid.target = target_temperature;
+    pid
+}
+
 #[derive(Debug)]
 pub struct I2cHandle {
     pi_num: i32,
@@ -1,93 +1,537 @@
 mod place;
 mod route;
 
-use self::place::Placement;
-pub use self::route::Path;
+use std::collections::HashSet;
 
-use command::{BoxedCommand, Command, CommandRequest};
-use grid::{Droplet, DropletId, Grid, GridView, Location, Snapshot};
-use util::collections::Map;
+pub use self::place::{
+    best_offset, best_placement, Candidate, FirstFit, PlaceRequest, Placement, PlacementStrategy,
+    Transform, WeightedCost,
+};
+pub use self::route::{Path, RouteBudget, RouteContext, RouteFailure};
+
+use command::{self, BoxedCommand, Command, CommandRequest};
+use grid::{Droplet, DropletId, Grid, GridView, Location, Snapshot, Violation};
+use process::ProcessId;
+use util::collections::{Map, Set};
 
 #[derive(Debug)]
 pub enum PlanError {
     RouteError {
         placement: Placement,
         droplets: Vec<Droplet>,
+        // boxed so every `Result` carrying a `PlanError` stays small
+        failure: Box<RouteFailure>,
     },
     PlaceError,
+    InvalidPlan(Violation),
+    // the process was killed, so none of its commands run anymore
+    ProcessKilled(ProcessId),
 }
 
+/// The tick at which a command starts running
 pub type Schedule = usize;
+/// Every path in a `Routing` ends at the command's `Schedule`
 pub type Routing = Map<DropletId, Path>;
 
+#[derive(Debug)]
 pub struct CommandPlan {
-    schedule: Schedule,
-    placement: Placement,
-    routing: Routing,
+    pub schedule: Schedule,
+    pub placement: Placement,
+    pub routing: Routing,
 }
 
 pub type Tick = usize;
 
+/// How long past the last reservation we are willing to push a command back
+/// before giving up on it
+const MAX_DELAY: Tick = 50;
+
 pub struct PlannedCommand {
-    cmd: BoxedCommand,
     start_tick: Tick,
+    end_tick: Tick,
     placement: Placement,
 }
 
 struct PlannedRoute {
     id: DropletId,
     start_tick: Tick,
+    dimensions: Location,
     route: Path,
 }
 
+/// A droplet that sits still from `since` until some command consumes it
+struct RestingDroplet {
+    droplet: Droplet,
+    since: Tick,
+}
+
 pub struct Plan {
     grid: Grid,
+    pub bad_edges: Set<(Location, Location)>,
+    // nothing may be planned before this tick
+    pub min_tick: Tick,
+    pub strategy: Box<dyn PlacementStrategy>,
+    // where droplets are headed once they're made, so the commands making
+    // them get placed close by
+    pub targets: Map<DropletId, Location>,
     cmds: Vec<PlannedCommand>,
     routes: Vec<PlannedRoute>,
+    resting: Map<DropletId, RestingDroplet>,
 }
 
 pub struct PlanSnapshot {
     cmd_shapes: Vec<Placement>,
 }
 
+/// The cells that are off limits, over time.
+///
+/// Everything in here is already padded by one cell, so a droplet is safe as
+/// long as none of its cells are blocked.
+#[derive(Default, Clone)]
+struct Occupancy {
+    by_tick: Map<Tick, HashSet<Location>>,
+    // resting droplets block their neighborhood forever
+    resting: Vec<(Tick, HashSet<Location>)>,
+}
+
+impl Occupancy {
+    fn block(&mut self, grid: &Grid, tick: Tick, loc: Location, dimensions: Location) {
+        let cells = self.by_tick.entry(tick).or_insert_with(HashSet::new);
+        cells.extend(grid.neighbors_dimensions(&loc, &dimensions));
+    }
+
+    fn block_path(&mut self, grid: &Grid, start_tick: Tick, path: &[Location], dim: Location) {
+        for (i, loc) in path.iter().enumerate() {
+            let tick = start_tick + i;
+            // droplets take a tick to move, so block the ticks around it too
+            if tick > 0 {
+                self.block(grid, tick - 1, *loc, dim);
+            }
+            self.block(grid, tick, *loc, dim);
+            // once the path is over, the droplet belongs to a command
+            if i + 1 < path.len() {
+                self.block(grid, tick + 1, *loc, dim);
+            }
+        }
+    }
+
+    fn block_resting(&mut self, grid: &Grid, since: Tick, loc: Location, dimensions: Location) {
+        let cells = grid
+            .neighbors_dimensions(&loc, &dimensions)
+            .into_iter()
+            .collect();
+        self.resting.push((since, cells));
+    }
+
+    fn is_blocked(&self, loc: &Location, tick: Tick) -> bool {
+        let by_tick = self
+            .by_tick
+            .get(&tick)
+            .map_or(false, |cells| cells.contains(loc));
+        by_tick
+            || self
+                .resting
+                .iter()
+                .any(|(since, cells)| *since <= tick && cells.contains(loc))
+    }
+
+    /// Is `loc` blocked at any point in `[start_tick, end_tick)`?
+    /// An `end_tick` of `None` means forever.
+    fn is_blocked_during(&self, loc: &Location, start_tick: Tick, end_tick: Option<Tick>) -> bool {
+        let before_end = |tick: Tick| end_tick.map_or(true, |end| tick < end);
+        let by_tick = self
+            .by_tick
+            .range(start_tick..)
+            .take_while(|(&tick, _)| before_end(tick))
+            .any(|(_, cells)| cells.contains(loc));
+        by_tick
+            || self
+                .resting
+                .iter()
+                .any(|(since, cells)| before_end(*since) && cells.contains(loc))
+    }
+
+    fn fits(&self, loc: Location, dimensions: Location, tick: Tick) -> bool {
+        (0..dimensions.y).all(|y| {
+            (0..dimensions.x).all(|x| !self.is_blocked(&(&loc + &Location { y, x }), tick))
+        })
+    }
+}
+
 impl Plan {
-    fn new(grid: Grid) -> Plan {
+    pub fn new(grid: Grid) -> Plan {
         Plan {
             grid,
+            bad_edges: Set::new(),
+            min_tick: 0,
+            strategy: Box::new(WeightedCost::default()),
+            targets: Map::new(),
             cmds: vec![],
             routes: vec![],
+            resting: Map::new(),
+        }
+    }
+
+    /// Where the droplets `cmd` makes are headed next, if anyone said
+    pub fn target(&self, cmd: &dyn Command) -> Option<Location> {
+        cmd.output_droplets()
+            .iter()
+            .filter_map(|id| self.targets.get(id))
+            .cloned()
+            .next()
+    }
+
+    /// Adds a droplet that will sit still starting at `since`.
+    pub fn add_droplet(&mut self, droplet: Droplet, since: Tick) {
+        let was_there = self
+            .resting
+            .insert(droplet.id, RestingDroplet { droplet, since });
+        assert!(was_there.is_none());
+    }
+
+    pub fn has_droplet(&self, id: &DropletId) -> bool {
+        self.resting.contains_key(id)
+    }
+
+    /// Forgets about resting droplets for which `keep` returns false
+    pub fn retain_droplets(&mut self, mut keep: impl FnMut(&DropletId) -> bool) {
+        let ids: Vec<DropletId> = self.resting.keys().cloned().collect();
+        for id in ids {
+            if !keep(&id) {
+                self.resting.remove(&id);
+            }
         }
     }
 
-    fn plan(&self, cmd: BoxedCommand) -> PlanResult {
-        unimplemented!()
+    /// Forgets about reservations that are over by `tick`
+    pub fn prune(&mut self, tick: Tick) {
+        // routes also block the tick after they end
+        self.cmds.retain(|cmd| cmd.end_tick > tick);
+        self.routes
+            .retain(|r| r.start_tick + r.route.len() + 1 > tick);
+    }
+
+    /// The first tick after which nothing is reserved (except resting droplets)
+    fn last_tick(&self) -> Tick {
+        let cmd_end = self.cmds.iter().map(|cmd| cmd.end_tick);
+        let route_end = self.routes.iter().map(|r| r.start_tick + r.route.len());
+        cmd_end.chain(route_end).max().unwrap_or(0)
+    }
+
+    /// Everything reserved in the plan, except for the given droplets
+    fn occupancy(&self, except: &[DropletId]) -> Occupancy {
+        let mut occ = Occupancy::default();
+
+        for cmd in &self.cmds {
+            for loc in cmd.placement.mapping.values() {
+                for tick in cmd.start_tick..cmd.end_tick {
+                    occ.block(&self.grid, tick, *loc, Location { y: 1, x: 1 });
+                }
+            }
+        }
+
+        for r in self.routes.iter().filter(|r| !except.contains(&r.id)) {
+            occ.block_path(&self.grid, r.start_tick, &r.route, r.dimensions);
+        }
+
+        for (id, rd) in &self.resting {
+            if !except.contains(id) {
+                let d = &rd.droplet;
+                occ.block_resting(&self.grid, rd.since, d.location, d.dimensions);
+            }
+        }
+
+        occ
+    }
+
+    /// Updates how a resting droplet may be routed, in case a command's
+    /// request changed its collision group or pinned it.
+    pub fn update_droplet(&mut self, droplet: &Droplet) {
+        if let Some(rd) = self.resting.get_mut(&droplet.id) {
+            rd.droplet.collision_group = droplet.collision_group;
+            rd.droplet.pinned = droplet.pinned;
+        }
+    }
+
+    /// Finds a start tick, a placement, and routes for the input droplets
+    /// such that `cmd` doesn't interfere with anything already in the plan.
+    ///
+    /// Nothing is reserved until the result is passed to `commit`. `now` is
+    /// the earliest tick that can still be planned into.
+    pub fn plan(&self, cmd: &dyn Command, req: &CommandRequest, now: Tick) -> PlanResult {
+        self.plan_placed(cmd, req, None, now)
+    }
+
+    /// Like `plan`, but the command has to go exactly at `placement`, once
+    /// nothing else needs those cells anymore.
+    pub fn plan_pinned(
+        &self,
+        cmd: &dyn Command,
+        req: &CommandRequest,
+        placement: &Placement,
+        now: Tick,
+    ) -> PlanResult {
+        self.plan_placed(cmd, req, Some(placement), now)
+    }
+
+    fn plan_placed(
+        &self,
+        cmd: &dyn Command,
+        req: &CommandRequest,
+        pinned: Option<&Placement>,
+        now: Tick,
+    ) -> PlanResult {
+        let in_ids = cmd.input_droplets();
+        assert_eq!(req.input_locations.len(), in_ids.len());
+        let now = now.max(self.min_tick);
+
+        let inputs: Vec<&RestingDroplet> = in_ids
+            .iter()
+            .map(|id| {
+                self.resting
+                    .get(id)
+                    .unwrap_or_else(|| panic!("Droplet {:?} isn't resting in the plan", id))
+            })
+            .collect();
+
+        let occ = self.occupancy(&in_ids);
+        let ready_tick = |rd: &RestingDroplet| rd.since.max(now);
+
+        let earliest = inputs.iter().map(|rd| ready_tick(rd)).max().unwrap_or(now);
+        let latest = earliest.max(self.last_tick()) + MAX_DELAY;
+
+        let place_inputs: Vec<(Location, Location)> = inputs
+            .iter()
+            .zip(&req.input_locations)
+            .map(|(rd, loc)| (rd.droplet.location, *loc))
+            .collect();
+        let transforms = req.transforms();
+        let place_req = PlaceRequest {
+            shape: &req.shape,
+            name: Some(cmd.name()),
+            transforms: &transforms,
+            inputs: &place_inputs,
+            target: self.target(cmd),
+            strategy: &*self.strategy,
+        };
+
+        let mut last_failure = None;
+        let mut start_tick = earliest;
+
+        while start_tick <= latest {
+            let placement = if let Some(pinned) = pinned {
+                let blocked = |loc| occ.is_blocked_during(loc, start_tick, None);
+                if pinned.mapping.values().any(blocked) {
+                    start_tick += 1;
+                    continue;
+                }
+                pinned.clone()
+            } else if req.trusted {
+                Placement::identity(&self.grid)
+            } else {
+                match self.place_in(&occ, &place_req, start_tick, None) {
+                    Ok(placement) => placement,
+                    Err(_) => {
+                        start_tick += 1;
+                        continue;
+                    }
+                }
+            };
+
+            let dests: Vec<Location> = req
+                .input_locations
+                .iter()
+                .zip(&inputs)
+                .map(|(loc, rd)| {
+                    placement
+                        .droplet_location(loc, &rd.droplet.dimensions)
+                        .unwrap_or_else(|| {
+                            panic!(
+                                "Input location {} wasn't in placement.\n  All input locations: {:?}",
+                                loc, req.input_locations
+                            )
+                        })
+                }).collect();
+
+            // don't bother routing if someone can't possibly make it in time
+            let needed_tick = inputs
+                .iter()
+                .zip(&dests)
+                .map(|(rd, dest)| ready_tick(rd) + rd.droplet.location.distance_to(dest) as Tick)
+                .max()
+                .unwrap_or(start_tick);
+            if needed_tick > start_tick {
+                start_tick = needed_tick;
+                continue;
+            }
+
+            match self.route_inputs(cmd.name(), &occ, &inputs, &dests, now, start_tick) {
+                Ok(routing) => {
+                    return Ok(CommandPlan {
+                        schedule: start_tick,
+                        placement,
+                        routing,
+                    })
+                }
+                Err(failure) => {
+                    last_failure = Some((placement, failure));
+                    start_tick += 1;
+                }
+            }
+        }
+
+        match last_failure {
+            None => Err(PlanError::PlaceError),
+            Some((placement, failure)) => Err(PlanError::RouteError {
+                placement,
+                droplets: inputs.iter().map(|rd| rd.droplet.clone()).collect(),
+                failure: Box::new(failure),
+            }),
+        }
+    }
+
+    fn route_inputs(
+        &self,
+        name: &str,
+        occ: &Occupancy,
+        inputs: &[&RestingDroplet],
+        dests: &[Location],
+        now: Tick,
+        end_tick: Tick,
+    ) -> Result<Routing, RouteFailure> {
+        let mut routing = Routing::new();
+        let mut occ = occ.clone();
+
+        // everything that might be in the way, in case routing fails
+        let mut obstacles: Vec<(DropletId, Location, Location)> = self
+            .resting
+            .values()
+            .map(|rd| (rd.droplet.id, rd.droplet.location, rd.droplet.dimensions))
+            .collect();
+        for pr in &self.routes {
+            let during = pr.start_tick + pr.route.len() > now && pr.start_tick <= end_tick;
+            if during {
+                obstacles.extend(pr.route.iter().map(|loc| (pr.id, *loc, pr.dimensions)));
+            }
+        }
+
+        for (rd, dest) in inputs.iter().zip(dests) {
+            let mut droplet = rd.droplet.clone();
+            droplet.destination = Some(*dest);
+
+            // the droplet is still at rest until we start moving it, so it
+            // can't be blocked by its own resting reservation
+            let start_tick = rd.since.max(now);
+            let dim = droplet.dimensions;
+            let ctx = RouteContext {
+                grid: &self.grid,
+                bad_edges: &self.bad_edges,
+                obstacles: &obstacles,
+            };
+            let is_free = |loc, tick| occ.fits(loc, dim, tick);
+            let path = route::route_in_time(&droplet, name, &ctx, start_tick, end_tick, is_free)?;
+
+            // inputs in the same collision group are allowed to touch
+            let same_group = inputs
+                .iter()
+                .filter(|other| other.droplet.id != droplet.id)
+                .all(|other| other.droplet.collision_group == droplet.collision_group);
+            if !same_group {
+                occ.block_path(&self.grid, start_tick, &path, dim);
+                obstacles.extend(path.iter().map(|loc| (droplet.id, *loc, dim)));
+            }
+
+            routing.insert(droplet.id, path);
+        }
+
+        Ok(routing)
+    }
+
+    /// Reserves everything in `cmd_plan`. The command occupies its placement
+    /// until `end_tick`, and then `outputs` come to rest.
+    pub fn commit(
+        &mut self,
+        cmd_plan: &CommandPlan,
+        end_tick: Tick,
+        outputs: impl IntoIterator<Item = Droplet>,
+    ) {
+        assert!(cmd_plan.schedule <= end_tick);
+
+        for (id, path) in &cmd_plan.routing {
+            let rd = self
+                .resting
+                .remove(id)
+                .unwrap_or_else(|| panic!("Droplet {:?} isn't resting in the plan", id));
+
+            // the droplet sat still from when it came to rest until the route
+            // started, so make sure that time stays reserved
+            let start_tick = cmd_plan.schedule + 1 - path.len();
+            let since = rd.since.max(self.min_tick).min(start_tick);
+            let mut route = vec![path[0]; start_tick - since];
+            route.extend(path);
+
+            self.routes.push(PlannedRoute {
+                id: *id,
+                start_tick: since,
+                dimensions: rd.droplet.dimensions,
+                route,
+            });
+        }
+
+        self.cmds.push(PlannedCommand {
+            start_tick: cmd_plan.schedule,
+            end_tick,
+            placement: cmd_plan.placement.clone(),
+        });
+
+        for droplet in outputs {
+            self.add_droplet(droplet, end_tick);
+        }
     }
 
     fn snapshot_at(&self, tick: Tick) -> PlanSnapshot {
         let cmd_shapes = self
             .cmds
             .iter()
-            .filter(|cmd| tick >= cmd.start_tick)
+            .filter(|cmd| cmd.start_tick <= tick && tick < cmd.end_tick)
             .map(|cmd| cmd.placement.clone())
             .collect();
         PlanSnapshot { cmd_shapes }
     }
 }
 
-type PlanResult = Result<CommandPlan, (Box<dyn Command>, PlanError)>;
+type PlanResult = Result<CommandPlan, PlanError>;
 
 impl GridView {
-    pub fn plan(&mut self, mut cmd: Box<dyn Command>) -> Result<(), (Box<dyn Command>, PlanError)> {
+    pub fn plan(&mut self, cmd: BoxedCommand) -> Result<(), (BoxedCommand, PlanError)> {
         info!("Planning {:?}", cmd);
 
         // make sure there's a snapshot available to plan into
         self.snapshot_ensure();
+
+        // a command that can't be planned shouldn't leave anything behind
+        let checkpoint = self.checkpoint();
+        let result = self.try_plan(cmd);
+        if let Err((ref cmd, ref err)) = result {
+            debug!(
+                "Undoing whatever {:?} planned, it failed with {:?}",
+                cmd, err
+            );
+            self.restore(checkpoint);
+        }
+        result
+    }
+
+    fn try_plan(&mut self, cmd: BoxedCommand) -> Result<(), (BoxedCommand, PlanError)> {
+        if let Some(pid) = cmd.process_id().filter(|pid| self.killed.contains(pid)) {
+            return Err((cmd, PlanError::ProcessKilled(pid)));
+        }
+
         if cmd.bypass(&self) {
             info!("Bypassing command: {:#?}", cmd);
             return Ok(());
         }
 
-        let in_ids = cmd.input_droplets();
         let req = cmd.request(self);
 
         debug!(
@@ -104,26 +548,258 @@ impl GridView {
                 .collect::<Vec<_>>()
         );
 
-        let placement_mapping = if req.trusted {
-            // if we are trusting placement, just use an identity map
-            self.grid
+        // trusted commands don't get placed, so nothing else keeps them out
+        if req.trusted {
+            let inputs: Vec<(Location, Location)> = cmd
+                .input_droplets()
+                .iter()
+                .zip(&req.input_locations)
+                .map(|(id, loc)| (*loc, self.snapshot().droplets[id].dimensions))
+                .collect();
+            if let Err(err) = self.check_keep_out(&*cmd, &inputs) {
+                return Err((cmd, err));
+            }
+        }
+
+        self.sync_plan();
+        self.sync_input_droplets(&*cmd);
+
+        // commands from the same process run in order, but they don't have
+        // to wait on anyone else's
+        let mut earliest = self.now();
+        if let Some(ready) = cmd
+            .process_id()
+            .and_then(|pid| self.process_ready.get(&pid))
+        {
+            earliest = earliest.max(*ready);
+        }
+        // we don't know what trusted commands will touch, so they go last
+        if req.trusted {
+            earliest = earliest.max(self.last_tick());
+        }
+
+        let result = match self.plan.plan(&*cmd, &req, earliest) {
+            Ok(cmd_plan) => return self.run_planned(cmd, &req, cmd_plan),
+            // maybe it's just idle droplets in the way
+            Err(PlanError::PlaceError) if !req.trusted => self.plan_parked(cmd, &req, earliest),
+            Err(err) => Err((cmd, err)),
+        };
+        result.or_else(|(cmd, err)| {
+            debug!(
+                "Couldn't plan {:?} alongside others, planning it last: {:?}",
+                cmd, err
+            );
+            let result = self.plan_last(cmd, req);
+            if result.is_ok() {
+                self.reset_plan();
+            }
+            result
+        })
+    }
+
+    /// Parks the idle droplets that keep `cmd` from being placed, then plans
+    /// it alongside everything else. If that doesn't work out, nothing is
+    /// left behind.
+    fn plan_parked(
+        &mut self,
+        cmd: BoxedCommand,
+        req: &CommandRequest,
+        earliest: Tick,
+    ) -> Result<(), (BoxedCommand, PlanError)> {
+        let spots = match self.parking_spots(&*cmd, req) {
+            Some((_placement, spots)) => spots,
+            None => return Err((cmd, PlanError::PlaceError)),
+        };
+
+        let checkpoint = self.checkpoint();
+        let result = if spots.iter().all(|&(id, spot)| self.park(id, spot)) {
+            match self.plan.plan(&*cmd, req, earliest) {
+                Ok(cmd_plan) => self.run_planned(cmd, req, cmd_plan),
+                Err(err) => Err((cmd, err)),
+            }
+        } else {
+            Err((cmd, PlanError::PlaceError))
+        };
+
+        if result.is_err() {
+            // the plan already has the parked droplets in storage
+            self.restore(checkpoint);
+            self.reset_plan();
+        }
+        result
+    }
+
+    /// Plans moving the idle droplet `id` to `spot` in storage
+    fn park(&mut self, id: DropletId, spot: Location) -> bool {
+        debug!("Parking droplet {:?} at {}", id, spot);
+        let cmd: BoxedCommand = Box::new(command::Park::new(id));
+        let req = cmd.request(self);
+        let placement = Placement {
+            mapping: req
+                .shape
                 .locations()
-                .map(|(loc, _cell)| (loc, loc))
-                .collect::<Map<_, _>>()
+                .map(|(loc, _cell)| (loc, &loc + &spot))
+                .collect(),
+            transform: Transform::identity(),
+        };
+
+        let now = self.now();
+        match self.plan.plan_pinned(&*cmd, &req, &placement, now) {
+            Ok(cmd_plan) => self.run_planned(cmd, &req, cmd_plan).is_ok(),
+            Err(err) => {
+                debug!("Couldn't park {:?}: {:?}", id, err);
+                false
+            }
+        }
+    }
+
+    /// The request may have changed the input droplets (collision groups,
+    /// pinning), so make sure every planned snapshot and the plan agree.
+    fn sync_input_droplets(&mut self, cmd: &dyn Command) {
+        for id in cmd.input_droplets() {
+            let droplet = self.snapshot().droplets[&id].clone();
+            self.update_all(id, |d| {
+                d.collision_group = droplet.collision_group;
+                d.pinned = droplet.pinned;
+            });
+            self.plan.update_droplet(&droplet);
+        }
+    }
+
+    fn run_planned(
+        &mut self,
+        mut cmd: BoxedCommand,
+        req: &CommandRequest,
+        cmd_plan: CommandPlan,
+    ) -> Result<(), (BoxedCommand, PlanError)> {
+        debug!("plan for {:#?}: {:#?}", cmd, cmd_plan);
+
+        let in_ids = cmd.input_droplets();
+        let out_ids = cmd.output_droplets();
+        let start_tick = cmd_plan.schedule;
+
+        // nothing before the inputs start moving changes
+        let first_tick = cmd_plan
+            .routing
+            .values()
+            .map(|path| start_tick + 1 - path.len())
+            .min()
+            .unwrap_or(start_tick);
+
+        trace!("Taking paths...");
+        self.take_paths_until(&cmd_plan.routing, start_tick);
+
+        let (end_tick, checked) = {
+            let placement = cmd_plan.placement.clone();
+            let mut subview = self.subview_at(start_tick, in_ids.iter().cloned(), placement);
+
+            trace!("Pre-Running command {:?}", cmd);
+            cmd.pre_run(&mut subview);
+            subview.tick();
+
+            trace!("Running command {:?}", cmd);
+            cmd.run(&mut subview);
+            (subview.current_tick(), subview.check())
+        };
+
+        if let Err(violation) = checked.and_then(|()| self.validate_from(first_tick)) {
+            return Err((cmd, PlanError::InvalidPlan(violation)));
+        }
+        if req.trusted {
+            let outputs = self.footprints(&out_ids, end_tick);
+            if let Err(err) = self.check_keep_out(&*cmd, &outputs) {
+                return Err((cmd, err));
+            }
+        }
+
+        // the command is finalized in the last snapshot it touched
+        if let Some(pid) = cmd.process_id() {
+            self.process_ready.insert(pid, end_tick);
+        }
+        self.register_at(end_tick - 1, cmd);
+
+        let outputs: Vec<Droplet> = out_ids
+            .iter()
+            .filter_map(|id| self.snapshot_at(end_tick).droplets.get(id).cloned())
+            .collect();
+
+        if req.trusted {
+            // we don't know what a trusted command will touch until it runs,
+            // so reserve the cells its droplets actually used
+            let mut mapping = Map::new();
+            for tick in start_tick..end_tick {
+                let snapshot = self.snapshot_at(tick);
+                for id in in_ids.iter().chain(&out_ids) {
+                    if let Some(d) = snapshot.droplets.get(id) {
+                        for loc in self.grid.neighbors_dimensions(&d.location, &d.dimensions) {
+                            mapping.insert(loc, loc);
+                        }
+                    }
+                }
+            }
+            let cmd_plan = CommandPlan {
+                placement: Placement {
+                    mapping,
+                    transform: Transform::identity(),
+                },
+                ..cmd_plan
+            };
+            self.plan.commit(&cmd_plan, end_tick, outputs);
+        } else {
+            self.plan.commit(&cmd_plan, end_tick, outputs);
+        }
+
+        Ok(())
+    }
+
+    /// Plans the command after everything else that's been planned,
+    /// moving every droplet on the board out of the way if need be.
+    fn plan_last(
+        &mut self,
+        mut cmd: BoxedCommand,
+        req: CommandRequest,
+    ) -> Result<(), (BoxedCommand, PlanError)> {
+        let in_ids = cmd.input_droplets();
+        let mut parked = Vec::new();
+
+        let placement = if req.trusted {
+            // if we are trusting placement, just use an identity map
+            Placement::identity(&self.grid)
         } else {
             // TODO place should be a method of gridview
             let mut snapshot: Snapshot = self.snapshot().new_with_same_droplets();
 
-            for id in &in_ids {
-                snapshot.droplets.remove(id);
+            let mut inputs = Vec::new();
+            for (id, loc) in in_ids.iter().zip(&req.input_locations) {
+                let droplet = snapshot.droplets.remove(id).unwrap();
+                inputs.push((droplet.location, *loc));
             }
-            match self.grid.place(&req.shape, &snapshot, &self.bad_edges) {
+            let place_req = PlaceRequest {
+                shape: &req.shape,
+                name: Some(cmd.name()),
+                transforms: &req.transforms(),
+                inputs: &inputs,
+                target: self.plan.target(&*cmd),
+                strategy: &*self.plan.strategy,
+            };
+            let placement = self.grid.place(&place_req, &snapshot, &self.bad_edges);
+            let placement = placement.or_else(|| {
+                let (placement, spots) = self.parking_spots(&*cmd, &req)?;
+                for (id, spot) in spots {
+                    debug!("Parking droplet {:?} at {}", id, spot);
+                    let droplet = self.snapshot_mut().droplets.get_mut(&id).unwrap();
+                    droplet.destination = Some(spot);
+                    parked.push(id);
+                }
+                Some(placement)
+            });
+            match placement {
                 None => return Err((cmd, PlanError::PlaceError)),
-                Some(placement_mapping) => placement_mapping,
+                Some(placement) => placement,
             }
         };
 
-        debug!("placement for {:#?}: {:#?}", cmd, placement_mapping);
+        debug!("placement for {:#?}: {:#?}", cmd, placement);
 
         assert_eq!(req.input_locations.len(), in_ids.len());
 
@@ -137,28 +813,60 @@ impl GridView {
 
             assert!(droplet.destination.is_none());
 
-            let mapped_loc = placement_mapping.get(loc).unwrap_or_else(|| {
-                panic!(
-                    "Input location {} wasn't in placement.\n  All input locations: {:?}",
-                    loc, req.input_locations
-                )
-            });
-            droplet.destination = Some(*mapped_loc);
+            let mapped_loc = placement
+                .droplet_location(loc, &droplet.dimensions)
+                .unwrap_or_else(|| {
+                    panic!(
+                        "Input location {} wasn't in placement.\n  All input locations: {:?}",
+                        loc, req.input_locations
+                    )
+                });
+            droplet.destination = Some(mapped_loc);
+        }
+
+        // each droplet may only go into the keep-outs that let in what it's
+        // headed for
+        let mut heading_to: Map<DropletId, &str> = Map::new();
+        for id in &in_ids {
+            heading_to.insert(*id, cmd.name());
+        }
+        for id in &parked {
+            heading_to.insert(*id, command::Park::NAME);
         }
 
         debug!("routing {:?}", cmd);
-        let paths = match self.route() {
-            Some(p) => p,
-            None => {
+        let paths = self.route(&heading_to);
+
+        // parked droplets are done moving once they get to storage
+        for id in &parked {
+            self.snapshot_mut()
+                .droplets
+                .get_mut(id)
+                .unwrap()
+                .destination = None;
+        }
+
+        let paths = match paths {
+            Ok(p) => p,
+            Err(failure) => {
+                // only the droplets of whoever asked, the rest are none of
+                // their business
+                let pid = cmd.process_id();
+                let droplets = self
+                    .snapshot()
+                    .droplets
+                    .values()
+                    .filter(|d| Some(d.id.process_id) == pid)
+                    .cloned()
+                    .collect();
                 return Err((
                     cmd,
                     PlanError::RouteError {
-                        placement: Placement {
-                            mapping: placement_mapping,
-                        },
-                        droplets: self.snapshot().droplets.values().cloned().collect(),
+                        placement,
+                        droplets,
+                        failure: Box::new(failure),
                     },
-                ))
+                ));
             }
         };
         debug!("route for {:#?}: {:#?}", cmd, paths);
@@ -167,10 +875,11 @@ impl GridView {
         // FIXME final tick is a hack
         // we *carefully* pre-run the command before making the final tick
         let final_tick = false;
+        let first_tick = self.last_tick();
         self.take_paths(&paths, final_tick);
 
-        {
-            let mut subview = self.subview(in_ids.iter().cloned(), placement_mapping.clone());
+        let checked = {
+            let mut subview = self.subview(in_ids.iter().cloned(), placement);
 
             trace!("Pre-Running command {:?}", cmd);
             cmd.pre_run(&mut subview);
@@ -178,6 +887,17 @@ impl GridView {
 
             trace!("Running command {:?}", cmd);
             cmd.run(&mut subview);
+            subview.check()
+        };
+
+        if let Err(violation) = checked.and_then(|()| self.validate_from(first_tick)) {
+            return Err((cmd, PlanError::InvalidPlan(violation)));
+        }
+        if req.trusted {
+            let outputs = self.footprints(&cmd.output_droplets(), self.last_tick());
+            if let Err(err) = self.check_keep_out(&*cmd, &outputs) {
+                return Err((cmd, err));
+            }
         }
 
         self.register(cmd);
@@ -193,6 +913,121 @@ impl GridView {
 
         Ok(())
     }
+
+    /// Where each of `ids` that exists at `tick` is, and how big it is
+    fn footprints(&self, ids: &[DropletId], tick: Tick) -> Vec<(Location, Location)> {
+        let snapshot = self.snapshot_at(tick);
+        ids.iter()
+            .filter_map(|id| snapshot.droplets.get(id))
+            .map(|d| (d.location, d.dimensions))
+            .collect()
+    }
+
+    /// Fails if any of the `(location, dimensions)` in `footprints` is on a
+    /// keep-out region that doesn't allow `cmd`. Trusted commands aren't
+    /// placed, so this is what keeps them out.
+    fn check_keep_out(
+        &self,
+        cmd: &dyn Command,
+        footprints: &[(Location, Location)],
+    ) -> Result<(), PlanError> {
+        let name = cmd.name();
+        if footprints
+            .iter()
+            .any(|&(loc, dim)| self.grid.keeps_out(name, loc, dim))
+        {
+            debug!("{:?} would go into a keep-out region", cmd);
+            return Err(PlanError::PlaceError);
+        }
+        Ok(())
+    }
+
+    /// Finds room for a command that doesn't fit by moving the idle droplets
+    /// that are in the way into storage. Gives back where the command would
+    /// go, and a spot in storage for each droplet that has to move. They'll
+    /// get brought back out whenever a command needs them.
+    fn parking_spots(
+        &self,
+        cmd: &dyn Command,
+        req: &CommandRequest,
+    ) -> Option<(Placement, Vec<(DropletId, Location)>)> {
+        let in_ids = &cmd.input_droplets();
+        let storage = &self.grid.storage;
+        let in_storage = |d: &Droplet| storage.iter().any(|z| z.contains(d.location, d.dimensions));
+        let idle: Vec<Droplet> = self
+            .snapshot()
+            .droplets
+            .values()
+            .filter(|d| {
+                !in_ids.contains(&d.id) && !d.pinned && d.destination.is_none() && !in_storage(d)
+            })
+            .cloned()
+            .collect();
+        if storage.is_empty() || idle.is_empty() {
+            return None;
+        }
+
+        // see if the command would fit with every idle droplet out of the way
+        let mut snapshot = self.snapshot().new_with_same_droplets();
+        let mut inputs = Vec::new();
+        for (id, loc) in in_ids.iter().zip(&req.input_locations) {
+            let droplet = snapshot.droplets.remove(id).unwrap();
+            inputs.push((droplet.location, *loc));
+        }
+        for d in &idle {
+            snapshot.droplets.remove(&d.id);
+        }
+        let place_req = PlaceRequest {
+            shape: &req.shape,
+            name: Some(cmd.name()),
+            transforms: &req.transforms(),
+            inputs: &inputs,
+            target: self.plan.target(cmd),
+            strategy: &*self.plan.strategy,
+        };
+        let placement = self.grid.place(&place_req, &snapshot, &self.bad_edges)?;
+
+        let in_the_way = |d: &Droplet| {
+            let far = &d.location + &d.dimensions;
+            placement
+                .mapping
+                .values()
+                .any(|loc| loc.min_distance_to_box(d.location, far) <= 0)
+        };
+        let moving: Vec<Droplet> = idle.into_iter().filter(|d| in_the_way(d)).collect();
+
+        // everyone else stays where they are, except for the inputs
+        let mut taken: Vec<Droplet> = self
+            .snapshot()
+            .droplets
+            .values()
+            .filter(|d| !in_ids.contains(&d.id) && !moving.iter().any(|m| m.id == d.id))
+            .cloned()
+            .collect();
+
+        let mut spots = Vec::new();
+        for droplet in &moving {
+            let spot = storage
+                .iter()
+                .flat_map(|zone| zone.spots(droplet.dimensions))
+                .map(|loc| Droplet {
+                    location: loc,
+                    ..droplet.clone()
+                })
+                .filter(|d| {
+                    !in_the_way(d)
+                        && self
+                            .grid
+                            .may_route(droplet, d.location, Some(command::Park::NAME))
+                        && taken.iter().all(|t| t.collision_distance(d) > 0)
+                })
+                .min_by_key(|d| d.location.distance_to(&droplet.location))?;
+            spots.push((droplet.id, spot.location));
+            taken.push(spot);
+        }
+
+        Some((placement, spots))
+    }
 }
 
 #[cfg(test)]
@@ -204,7 +1039,8 @@ mod tests {
     use super::*;
 
     use command;
-    use grid::{DropletId, Grid};
+    use grid::{DropletId, Grid, KeepOut, Peripheral, StorageZone};
+    use process::PuddleError;
 
     fn mk_gv(path: &str) -> GridView {
         let _ = env_logger::try_init();
@@ -252,4 +1088,289 @@ mod tests {
         gv.plan(Box::new(cmd)).unwrap();
     }
 
+    #[test]
+    fn plan_output_big_droplet() {
+        let _ = env_logger::try_init();
+        let mut grid = Grid::rectangle(5, 6);
+        let output = Location { y: 3, x: 0 };
+        grid.get_cell_mut(&output).unwrap().peripheral = Some(Peripheral::Output {
+            pwm_channel: 0,
+            name: "output".into(),
+        });
+        let mut gv = GridView::new(grid);
+
+        // the output is halfway down the droplet's side, so it has to be
+        // placed with its top-left corner above the output
+        let id = mk_droplet(0, 0, 0).id;
+        let dim = Some(Location { y: 3, x: 3 });
+        let location = Some(Location { y: 1, x: 3 });
+        let create = command::Create::new(location, 1.0, dim, id).unwrap();
+        gv.plan(Box::new(create)).unwrap();
+        let cmd = command::Output::new("output".into(), id).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+
+        assert!(gv.snapshot().droplets.is_empty());
+    }
+
+    #[test]
+    fn plan_processes_concurrently() {
+        let _ = env_logger::try_init();
+        let mut gv = GridView::new(Grid::rectangle(12, 12));
+
+        let id = |id, process_id| DropletId { id, process_id };
+        let create = |out_id| {
+            let dim = Some(Location { y: 1, x: 1 });
+            Box::new(command::Create::new(None, 1.0, dim, out_id).unwrap())
+        };
+
+        // process 0 makes a droplet and mixes it for a while
+        gv.plan(create(id(0, 0))).unwrap();
+        for i in 0..3 {
+            let cmd = command::Agitate::new(id(i, 0), id(i + 1, 0)).unwrap();
+            gv.plan(Box::new(cmd)).unwrap();
+        }
+        let busy_until = gv.last_tick();
+        assert!(busy_until > 10);
+
+        // process 1 shouldn't have to wait for process 0 to finish
+        gv.plan(create(id(0, 1))).unwrap();
+        let cmd = command::Agitate::new(id(0, 1), id(1, 1)).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+
+        assert_eq!(gv.last_tick(), busy_until);
+        assert!(gv.process_ready[&1] < busy_until);
+        assert!(gv.snapshot_at(busy_until).droplets.contains_key(&id(1, 1)));
+        assert!(gv.snapshot_at(busy_until).droplets.contains_key(&id(3, 0)));
+    }
+
+    #[test]
+    fn plan_parks_idle_droplets() {
+        let _ = env_logger::try_init();
+        let mut grid = Grid::rectangle(5, 8);
+        grid.storage.push(StorageZone {
+            location: Location { y: 0, x: 6 },
+            dimensions: Location { y: 5, x: 2 },
+        });
+        let mut gv = GridView::new(grid);
+
+        // an idle droplet sits right in the middle of the board
+        let idle = mk_droplet(0, 2, 3);
+        let idle_id = idle.id;
+        gv.snapshot_mut().droplets.insert(idle_id, idle);
+
+        // there's only room for something this big if it gets out of the way
+        let out_id = mk_droplet(1, 0, 0).id;
+        let dim = Some(Location { y: 2, x: 5 });
+        let cmd = command::Create::new(None, 1.0, dim, out_id).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+
+        let parked = gv.snapshot().droplets[&idle_id].clone();
+        assert!(gv.grid.storage[0].contains(parked.location, parked.dimensions));
+        assert_eq!(parked.destination, None);
+        // parking didn't need everything else to be planned first
+        assert_eq!(gv.plan.min_tick, 0);
+
+        // once it's used again, it comes back out of storage
+        let created = gv.snapshot().droplets[&out_id].clone();
+        let y = if created.location.y >= 2 { 0 } else { 4 };
+        let loc = Location { y, x: 0 };
+        let moved_id = mk_droplet(2, 0, 0).id;
+        let cmd = command::Move::new(idle_id, loc, moved_id).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+        assert_eq!(gv.snapshot().droplets[&moved_id].location, loc);
+    }
+
+    #[test]
+    fn plan_keeps_storage_free() {
+        let _ = env_logger::try_init();
+        let mut grid = Grid::rectangle(5, 8);
+        let zone = StorageZone {
+            location: Location { y: 0, x: 0 },
+            dimensions: Location { y: 5, x: 3 },
+        };
+        grid.storage.push(zone.clone());
+        let mut gv = GridView::new(grid);
+
+        for i in 0..2 {
+            let dim = Some(Location { y: 1, x: 1 });
+            let cmd = command::Create::new(None, 1.0, dim, mk_droplet(i, 0, 0).id).unwrap();
+            gv.plan(Box::new(cmd)).unwrap();
+        }
+
+        for d in gv.snapshot().droplets.values() {
+            let far = &d.location + &d.dimensions;
+            for loc in zone.spots(Location { y: 1, x: 1 }) {
+                assert!(loc.min_distance_to_box(d.location, far) > 0);
+            }
+        }
+    }
+
+    #[test]
+    fn heaters_only_take_columns() {
+        use std::sync::mpsc::channel;
+        use std::time::Duration;
+
+        let id = mk_droplet(0, 0, 0).id;
+        let out = mk_droplet(1, 0, 0).id;
+        let wide = Location { y: 1, x: 2 };
+        let heat = command::Heat::new(id, &wide, out, 60.0, Duration::from_secs(1));
+        assert_matches!(heat, Err(PuddleError::InvalidArgument(_)));
+
+        let stage = command::ThermocycleStage {
+            temperature: 60.0,
+            seconds: 1.0,
+        };
+        let cycle = command::Thermocycle::new(id, &wide, out, vec![stage], 1, channel().0);
+        assert_matches!(cycle, Err(PuddleError::InvalidArgument(_)));
+    }
+
+    #[test]
+    fn plan_uses_storage_when_nothing_else_fits() {
+        let _ = env_logger::try_init();
+        let mut grid = Grid::rectangle(5, 8);
+        let zone = StorageZone {
+            location: Location { y: 0, x: 0 },
+            dimensions: Location { y: 5, x: 5 },
+        };
+        grid.storage.push(zone.clone());
+        let mut gv = GridView::new(grid);
+
+        // it's only 3 wide outside of storage, which is too small
+        let id = mk_droplet(0, 0, 0).id;
+        let dim = Some(Location { y: 4, x: 4 });
+        let cmd = command::Create::new(None, 1.0, dim, id).unwrap();
+        gv.plan(Box::new(cmd)).unwrap();
+
+        let d = &gv.snapshot().droplets[&id];
+        assert!(d.location.x < zone.dimensions.x);
+    }
+
+    #[test]
+    fn plan_keeps_trusted_commands_out() {
+        let _ = env_logger::try_init();
+        let mut grid = Grid::rectangle(3, 5);
+        grid.keep_out.push(KeepOut {
+            location: Location { y: 0, x: 3 },
+            dimensions: Location { y: 3, x: 2 },
+            allow: vec!["heat".into()],
+        });
+        let mut gv = GridView::new(grid);
+        let dim = Some(Location { y: 1, x: 1 });
+        let id = |id| mk_droplet(id, 0, 0).id;
+
+        // a create can't just put a droplet in there
+        let inside = Location { y: 1, x: 3 };
+        let create = command::Create::new(Some(inside), 1.0, dim, id(0)).unwrap();
+        match gv.plan(Box::new(create)) {
+            Err((_, PlanError::PlaceError)) => (),
+            other => panic!("created in a keep-out: {:?}", other.map_err(|(_, e)| e)),
+        }
+
+        // and neither can a move
+        let outside = Location { y: 1, x: 0 };
+        let create = command::Create::new(Some(outside), 1.0, dim, id(1)).unwrap();
+        gv.plan(Box::new(create)).unwrap();
+        let mv = command::Move::new(id(1), inside, id(2)).unwrap();
+        match gv.plan(Box::new(mv)) {
+            Err((_, PlanError::PlaceError)) => (),
+            other => panic!("moved into a keep-out: {:?}", other.map_err(|(_, e)| e)),
+        }
+
+        // so the droplet stayed where it was
+        assert_eq!(gv.snapshot().droplets[&id(1)].location, outside);
+    }
+
+    fn mk_droplet(id: usize, y: i32, x: i32) -> Droplet {
+        let id = DropletId { id, process_id: 0 };
+        let location = Location { y, x };
+        let dimensions = Location { y: 1, x: 1 };
+        Droplet::new(id, 1.0, location, dimensions)
+    }
+
+    fn square_request(size: usize) -> CommandRequest {
+        CommandRequest {
+            shape: Grid::rectangle(size, size),
+            input_locations: vec![Location { y: 0, x: 0 }],
+            trusted: false,
+            rotatable: true,
+        }
+    }
+
+    fn check_routing(plan: &CommandPlan) {
+        for path in plan.routing.values() {
+            let dest = plan.placement.mapping[&Location { y: 0, x: 0 }];
+            assert_eq!(path.last(), Some(&dest));
+            for pair in path.windows(2) {
+                assert!(pair[0].distance_to(&pair[1]) <= 1);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_overlapping_commands() {
+        let _ = env_logger::try_init();
+        let mut plan = Plan::new(Grid::rectangle(10, 10));
+
+        let a = mk_droplet(0, 0, 0);
+        let b = mk_droplet(1, 9, 9);
+        let (a_id, b_id) = (a.id, b.id);
+        plan.add_droplet(a, 0);
+        plan.add_droplet(b, 0);
+
+        let loc = Location { y: 0, x: 0 };
+        let cmd_a = command::Move::new(a_id, loc, mk_droplet(2, 0, 0).id).unwrap();
+        let cmd_b = command::Move::new(b_id, loc, mk_droplet(3, 0, 0).id).unwrap();
+
+        let plan_a = plan.plan(&cmd_a, &square_request(3), 0).unwrap();
+        check_routing(&plan_a);
+        let a_end = plan_a.schedule + 20;
+        plan.commit(&plan_a, a_end, vec![]);
+
+        // b is far away from a, so it shouldn't have to wait for a to finish
+        let plan_b = plan.plan(&cmd_b, &square_request(3), 0).unwrap();
+        check_routing(&plan_b);
+        assert!(plan_b.schedule < a_end);
+
+        for loc in plan_b.placement.mapping.values() {
+            for a_loc in plan_a.placement.mapping.values() {
+                assert!(loc.distance_to(a_loc) > 1);
+            }
+        }
+    }
+
+    #[test]
+    fn plan_waits_for_space() {
+        let _ = env_logger::try_init();
+        let grid = Grid::rectangle(3, 7);
+        let mut plan = Plan::new(grid.clone());
+
+        // something else is using the right side of the board for a while
+        let blocker = CommandPlan {
+            schedule: 0,
+            placement: Placement {
+                mapping: grid
+                    .locations()
+                    .map(|(loc, _)| (loc, loc))
+                    .filter(|(loc, _)| loc.x >= 3)
+                    .collect(),
+                transform: Transform::identity(),
+            },
+            routing: Routing::new(),
+        };
+        let blocker_end = 10;
+        plan.commit(&blocker, blocker_end, vec![]);
+
+        let a = mk_droplet(0, 1, 0);
+        let a_id = a.id;
+        plan.add_droplet(a, 0);
+
+        let loc = Location { y: 0, x: 0 };
+        let cmd = command::Move::new(a_id, loc, mk_droplet(1, 0, 0).id).unwrap();
+        let cmd_plan = plan.plan(&cmd, &square_request(3), 0).unwrap();
+        check_routing(&cmd_plan);
+
+        assert!(cmd_plan.schedule >= blocker_end);
+        let path = &cmd_plan.routing[&a_id];
+        assert_eq!(path.len(), cmd_plan.schedule + 1);
+    }
 }
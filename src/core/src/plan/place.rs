@@ -1,7 +1,8 @@
 use std::collections::HashSet;
 
+use grid::grid::NEIGHBORS_8;
 use grid::{Grid, Location};
-use plan::{Plan, Tick};
+use plan::{Occupancy, Plan, Tick};
 use util::collections::Map;
 
 type PlacementResult = Result<Placement, PlacementError>;
@@ -10,6 +11,116 @@ type PlacementResult = Result<Placement, PlacementError>;
 pub struct Placement {
     // TODO idk if this should be pub
     pub mapping: Map<Location, Location>,
+    /// How the shape was turned around before being placed
+    pub transform: Transform,
+}
+
+/// One of the eight ways to rotate and/or mirror a shape, as a matrix
+/// acting on `Location`s.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transform {
+    yy: i32,
+    yx: i32,
+    xy: i32,
+    xx: i32,
+}
+
+#[cfg_attr(rustfmt, rustfmt_skip)]
+const TRANSFORMS: [Transform; 8] = [
+    // these keep the axes where they are
+    Transform { yy:  1, yx:  0, xy:  0, xx:  1 },
+    Transform { yy:  1, yx:  0, xy:  0, xx: -1 },
+    Transform { yy: -1, yx:  0, xy:  0, xx:  1 },
+    Transform { yy: -1, yx:  0, xy:  0, xx: -1 },
+    // and these swap them
+    Transform { yy:  0, yx:  1, xy:  1, xx:  0 },
+    Transform { yy:  0, yx:  1, xy: -1, xx:  0 },
+    Transform { yy:  0, yx: -1, xy:  1, xx:  0 },
+    Transform { yy:  0, yx: -1, xy: -1, xx:  0 },
+];
+
+impl Transform {
+    pub fn identity() -> Transform {
+        TRANSFORMS[0]
+    }
+
+    /// Every transform, starting with the identity
+    pub fn all() -> Vec<Transform> {
+        TRANSFORMS.to_vec()
+    }
+
+    /// The transforms that keep a droplet's dimensions the same
+    pub fn preserving_axes() -> Vec<Transform> {
+        TRANSFORMS
+            .iter()
+            .cloned()
+            .filter(|t| !t.swaps_axes())
+            .collect()
+    }
+
+    pub fn swaps_axes(&self) -> bool {
+        self.yy == 0
+    }
+
+    pub fn inverse(&self) -> Transform {
+        Transform {
+            yy: self.yy,
+            yx: self.xy,
+            xy: self.yx,
+            xx: self.xx,
+        }
+    }
+
+    pub fn apply(&self, loc: &Location) -> Location {
+        Location {
+            y: self.yy * loc.y + self.yx * loc.x,
+            x: self.xy * loc.y + self.xx * loc.x,
+        }
+    }
+
+    /// Dimensions only get swapped, never negated
+    pub fn apply_dimensions(&self, dim: &Location) -> Location {
+        let loc = self.apply(dim);
+        Location {
+            y: loc.y.abs(),
+            x: loc.x.abs(),
+        }
+    }
+
+    /// Where the box of size `dim` at `loc` ends up, given that the cell `loc`
+    /// itself is sent to `mapped_loc`. Returns the new top-left corner.
+    pub fn apply_box(&self, mapped_loc: &Location, dim: &Location) -> Location {
+        let far_corner = mapped_loc + &self.apply(&(dim - &Location { y: 1, x: 1 }));
+        Location {
+            y: mapped_loc.y.min(far_corner.y),
+            x: mapped_loc.x.min(far_corner.x),
+        }
+    }
+
+    /// Turns `shape` around, shifting it back so its top-left corner is at the
+    /// origin. Also returns that shift, which `apply` doesn't include.
+    pub fn apply_shape(&self, shape: &Grid) -> (Grid, Location) {
+        let height = shape.max_height();
+        let width = shape.max_width();
+        let far = Location {
+            y: height as i32 - 1,
+            x: width as i32 - 1,
+        };
+        let shift = self.apply_box(&Location { y: 0, x: 0 }, &(&far + &Location { y: 1, x: 1 }));
+
+        let inverse = self.inverse();
+        let (new_height, new_width) = if self.swaps_axes() {
+            (width, height)
+        } else {
+            (height, width)
+        };
+        let new_shape = Grid::from_function(
+            |loc| shape.get_cell(&inverse.apply(&(&loc + &shift))).cloned(),
+            new_height,
+            new_width,
+        );
+        (new_shape, shift)
+    }
 }
 
 #[derive(Debug)]
@@ -17,34 +128,314 @@ pub enum PlacementError {
     Bad,
 }
 
-impl Plan {
-    fn place(&self, shape: &Grid, start_tick: Tick, _end_tick: Option<Tick>) -> PlacementResult {
-        let snapshot = self.snapshot_at(start_tick);
+/// An offset at which `shape` would fit into `grid`, waiting to be scored.
+pub struct Candidate<'a> {
+    pub grid: &'a Grid,
+    pub shape: &'a Grid,
+    pub offset: Location,
+    /// For each input droplet, where it is now and where it has to be in
+    /// `shape`'s coordinates
+    pub inputs: &'a [(Location, Location)],
+    /// Where the droplets this makes will be used next, if that's known
+    pub target: Option<Location>,
+    /// Cells in `grid` that someone else is using
+    pub blocked: &'a HashSet<Location>,
+    /// Cells in `grid` that have a peripheral
+    pub peripherals: &'a [Location],
+    /// Cells in `grid` that are set aside for parking idle droplets
+    pub storage: &'a HashSet<Location>,
+    /// The cells of `shape`, and the cells bordering them, in `shape`'s
+    /// coordinates
+    pub cells: &'a [Location],
+    pub border: &'a [Location],
+}
+
+impl<'a> Candidate<'a> {
+    /// The cells of `grid` that the shape would cover
+    pub fn footprint(&self) -> impl Iterator<Item = Location> + 'a {
+        let offset = self.offset;
+        self.cells.iter().map(move |loc| loc + &offset)
+    }
+}
+
+/// Decides which of the compatible offsets a shape gets placed at.
+pub trait PlacementStrategy: Send {
+    /// Lower is better; ties go to the first offset in row-major order.
+    /// Only compatible offsets are scored, so don't check that here.
+    fn cost(&self, candidate: &Candidate) -> u32;
+}
+
+/// Takes the first offset that fits, scanning in row-major order.
+#[derive(Debug, Clone, Copy)]
+pub struct FirstFit;
+
+impl PlacementStrategy for FirstFit {
+    fn cost(&self, _candidate: &Candidate) -> u32 {
+        0
+    }
+}
 
-        let bad_locs: HashSet<Location> = snapshot
-            .cmd_shapes
+/// Scores offsets by a weighted sum of how far the inputs have to travel, how
+/// far the outputs would be from where they're used next, how close the shape
+/// gets to peripherals it doesn't need, how many busy cells it would border,
+/// and how much of the storage zones it would take up.
+#[derive(Debug, Clone, Copy)]
+pub struct WeightedCost {
+    pub route: u32,
+    pub target: u32,
+    pub peripheral: u32,
+    pub congestion: u32,
+    pub storage: u32,
+}
+
+/// peripherals further than this from a placement don't cost anything
+const PERIPHERAL_RADIUS: u32 = 2;
+
+impl Default for WeightedCost {
+    fn default() -> Self {
+        WeightedCost {
+            route: 1,
+            target: 1,
+            peripheral: 2,
+            congestion: 1,
+            storage: 2,
+        }
+    }
+}
+
+impl WeightedCost {
+    fn route_cost(&self, c: &Candidate) -> u32 {
+        c.inputs
             .iter()
-            // should refer to placement, but that's not what command gives you right now
-            .flat_map(|placement| placement.mapping.values())
-            .cloned()
+            .map(|(from, to)| from.distance_to(&(to + &c.offset)))
+            .sum()
+    }
+
+    fn target_cost(&self, c: &Candidate) -> u32 {
+        c.target.map_or(0, |target| {
+            c.footprint()
+                .map(|loc| loc.distance_to(&target))
+                .min()
+                .unwrap_or(0)
+        })
+    }
+
+    fn peripheral_cost(&self, c: &Candidate) -> u32 {
+        c.peripherals
+            .iter()
+            .filter(|loc| {
+                let in_shape = *loc - &c.offset;
+                c.shape
+                    .get_cell(&in_shape)
+                    .map_or(true, |cell| cell.peripheral.is_none())
+            })
+            .filter_map(|loc| c.footprint().map(|f| f.distance_to(loc)).min())
+            .map(|dist| (PERIPHERAL_RADIUS + 1).saturating_sub(dist))
+            .sum()
+    }
+
+    // droplets can't be parked right next to the shape either
+    fn storage_cost(&self, c: &Candidate) -> u32 {
+        let border = c.border.iter().map(|loc| loc + &c.offset);
+        c.footprint()
+            .chain(border)
+            .filter(|loc| c.storage.contains(loc))
+            .count() as u32
+    }
+
+    fn congestion_cost(&self, c: &Candidate) -> u32 {
+        c.border
+            .iter()
+            .filter(|loc| c.blocked.contains(&(*loc + &c.offset)))
+            .count() as u32
+    }
+}
+
+impl PlacementStrategy for WeightedCost {
+    fn cost(&self, c: &Candidate) -> u32 {
+        self.route * self.route_cost(c)
+            + self.target * self.target_cost(c)
+            + self.peripheral * self.peripheral_cost(c)
+            + self.congestion * self.congestion_cost(c)
+            + self.storage * self.storage_cost(c)
+    }
+}
+
+/// A shape to place, and what decides where it goes
+#[derive(Clone, Copy)]
+pub struct PlaceRequest<'a> {
+    pub shape: &'a Grid,
+    /// The kind of command being placed, which decides which keep-out
+    /// regions it may go in
+    pub name: Option<&'a str>,
+    /// The shape may be turned around by any of these
+    pub transforms: &'a [Transform],
+    /// For each input droplet, where it is now and where it has to be in
+    /// `shape`'s coordinates, so the strategy can keep their routes short
+    pub inputs: &'a [(Location, Location)],
+    /// Where the droplets the command makes are headed afterwards
+    pub target: Option<Location>,
+    pub strategy: &'a dyn PlacementStrategy,
+}
+
+/// Scores every offset of `grid` that passes `fits` and returns the cheapest,
+/// along with its cost. The shape isn't turned around, so `req.transforms`
+/// doesn't matter here.
+pub fn best_offset<F>(
+    grid: &Grid,
+    req: &PlaceRequest,
+    blocked: &HashSet<Location>,
+    fits: F,
+) -> Option<(Location, u32)>
+where
+    F: Fn(Location) -> bool,
+{
+    let shape = req.shape;
+    let peripherals: Vec<Location> = grid
+        .locations()
+        .filter(|(_, cell)| cell.peripheral.is_some())
+        .map(|(loc, _)| loc)
+        .collect();
+    let storage = grid.storage_cells();
+
+    let cells: Vec<Location> = shape.locations().map(|(loc, _)| loc).collect();
+    let cell_set: HashSet<Location> = cells.iter().cloned().collect();
+    let border_set: HashSet<Location> = cells
+        .iter()
+        .flat_map(|loc| NEIGHBORS_8.iter().map(move |n| loc + n))
+        .filter(|loc| !cell_set.contains(loc))
+        .collect();
+    let border: Vec<Location> = border_set.into_iter().collect();
+
+    grid.locations()
+        .map(|(offset, _cell)| offset)
+        .filter(|&offset| fits(offset))
+        .map(|offset| {
+            let cost = req.strategy.cost(&Candidate {
+                grid,
+                shape,
+                offset,
+                inputs: req.inputs,
+                target: req.target,
+                blocked,
+                peripherals: &peripherals,
+                storage: &storage,
+                cells: &cells,
+                border: &border,
+            });
+            (offset, cost)
+        })
+        .min_by_key(|&(_, cost)| cost)
+}
+
+/// Tries the shape turned around by each of `req.transforms`, returning the
+/// cheapest placement that passes `fits`. Ties go to the earlier transform.
+pub fn best_placement<F>(
+    grid: &Grid,
+    req: &PlaceRequest,
+    blocked: &HashSet<Location>,
+    fits: F,
+) -> Option<Placement>
+where
+    F: Fn(&Grid, Location) -> bool,
+{
+    let mut best: Option<(u32, Placement)> = None;
+    let mut tried: Vec<Grid> = Vec::new();
+
+    for &transform in req.transforms {
+        let (turned, shift) = transform.apply_shape(req.shape);
+        // symmetric shapes look the same under many transforms
+        if tried.contains(&turned) {
+            continue;
+        }
+
+        let turned_inputs: Vec<(Location, Location)> = req
+            .inputs
+            .iter()
+            .map(|(from, to)| (*from, &transform.apply(to) - &shift))
             .collect();
+        let turned_req = PlaceRequest {
+            shape: &turned,
+            inputs: &turned_inputs,
+            ..*req
+        };
+        let found = best_offset(grid, &turned_req, blocked, |offset| fits(&turned, offset));
+
+        if let Some((offset, cost)) = found {
+            if best
+                .as_ref()
+                .map_or(true, |(best_cost, _)| cost < *best_cost)
+            {
+                let mapping = req
+                    .shape
+                    .locations()
+                    .map(|(loc, _)| (loc, &(&transform.apply(&loc) - &shift) + &offset))
+                    .collect();
+                best = Some((cost, Placement { mapping, transform }));
+            }
+        }
+        tried.push(turned);
+    }
+
+    best.map(|(_, placement)| placement)
+}
+
+impl Placement {
+    pub fn identity(grid: &Grid) -> Placement {
+        let mapping = grid.locations().map(|(loc, _)| (loc, loc)).collect();
+        Placement {
+            mapping,
+            transform: Transform::identity(),
+        }
+    }
 
-        let offset = self
+    /// Where a droplet with its top-left corner at `loc` in the command's
+    /// coordinates, and dimensions `dim` on the grid, has its top-left
+    /// corner on the grid.
+    pub fn droplet_location(&self, loc: &Location, dim: &Location) -> Option<Location> {
+        let mapped = self.mapping.get(loc)?;
+        let command_dim = self.transform.inverse().apply_dimensions(dim);
+        Some(self.transform.apply_box(mapped, &command_dim))
+    }
+}
+
+impl Plan {
+    /// Places `shape` somewhere that is free from `start_tick` until
+    /// `end_tick`, or forever if `end_tick` is `None`.
+    pub fn place(&self, shape: &Grid, start_tick: Tick, end_tick: Option<Tick>) -> PlacementResult {
+        let occ = self.occupancy(&[]);
+        let req = PlaceRequest {
+            shape,
+            name: None,
+            transforms: &Transform::all(),
+            inputs: &[],
+            target: None,
+            strategy: &*self.strategy,
+        };
+        self.place_in(&occ, &req, start_tick, end_tick)
+    }
+
+    /// Like `place`, but for a whole `PlaceRequest`, around the cells
+    /// blocked in `occ`.
+    pub(super) fn place_in(
+        &self,
+        occ: &Occupancy,
+        req: &PlaceRequest,
+        start_tick: Tick,
+        end_tick: Option<Tick>,
+    ) -> PlacementResult {
+        let mut bad_locs: HashSet<Location> = self
             .grid
             .locations()
             .map(|(loc, _cell)| loc)
-            .find(|loc| is_compatible(&self.grid, shape, *loc, &bad_locs));
-
-        if let Some(offset) = offset {
-            let mapping = self
-                .grid
-                .locations()
-                .map(|(loc, _)| (loc, &loc + &offset))
-                .collect();
-            Ok(Placement { mapping })
-        } else {
-            Err(PlacementError::Bad)
-        }
+            .filter(|loc| occ.is_blocked_during(loc, start_tick, end_tick))
+            .collect();
+        bad_locs.extend(self.grid.kept_out(req.name));
+
+        best_placement(&self.grid, req, &bad_locs, |turned, loc| {
+            is_compatible(&self.grid, turned, loc, &bad_locs)
+        })
+        .ok_or(PlacementError::Bad)
     }
 }
 
@@ -73,6 +464,21 @@ mod tests {
     use super::*;
     use grid::Peripheral;
 
+    fn request<'a>(
+        plan: &'a Plan,
+        shape: &'a Grid,
+        transforms: &'a [Transform],
+    ) -> PlaceRequest<'a> {
+        PlaceRequest {
+            shape,
+            name: None,
+            transforms,
+            inputs: &[],
+            target: None,
+            strategy: &*plan.strategy,
+        }
+    }
+
     #[test]
     fn grid_self_compatible() {
         let grid = Grid::rectangle(5, 4);
@@ -128,4 +534,128 @@ mod tests {
         );
     }
 
+    #[test]
+    fn place_near_inputs() {
+        let grid = Grid::rectangle(10, 10);
+        let shape = Grid::rectangle(2, 2);
+        let plan = Plan::new(grid.clone());
+        let occ = plan.occupancy(&[]);
+
+        let droplet_loc = Location { y: 7, x: 8 };
+        let inputs = vec![(droplet_loc, Location { y: 0, x: 0 })];
+        let transforms = Transform::all();
+        let req = PlaceRequest {
+            inputs: &inputs,
+            ..request(&plan, &shape, &transforms)
+        };
+        let placement = plan.place_in(&occ, &req, 0, None).unwrap();
+
+        assert_eq!(placement.mapping[&Location { y: 0, x: 0 }], droplet_loc);
+    }
+
+    #[test]
+    fn place_near_target() {
+        let grid = Grid::rectangle(10, 10);
+        let shape = Grid::rectangle(2, 2);
+        let plan = Plan::new(grid.clone());
+        let occ = plan.occupancy(&[]);
+
+        let target = Some(Location { y: 6, x: 3 });
+        let transforms = Transform::all();
+        let req = PlaceRequest {
+            target,
+            ..request(&plan, &shape, &transforms)
+        };
+        let placement = plan.place_in(&occ, &req, 0, None).unwrap();
+
+        let distance = placement
+            .mapping
+            .values()
+            .map(|loc| loc.distance_to(&target.unwrap()))
+            .min();
+        assert_eq!(distance, Some(0));
+    }
+
+    #[test]
+    fn place_away_from_peripherals() {
+        let mut grid = Grid::rectangle(1, 8);
+        grid.get_cell_mut(&Location { y: 0, x: 1 })
+            .unwrap()
+            .peripheral = Some(Peripheral::Heater {
+            pwm_channel: 0,
+            spi_channel: 0,
+        });
+        let shape = Grid::rectangle(1, 1);
+
+        let mut plan = Plan::new(grid.clone());
+        let placement = plan.place(&shape, 0, None).unwrap();
+        assert_eq!(placement.mapping[&Location { y: 0, x: 0 }].x, 4);
+
+        plan.strategy = Box::new(FirstFit);
+        let placement = plan.place(&shape, 0, None).unwrap();
+        assert_eq!(placement.mapping[&Location { y: 0, x: 0 }].x, 0);
+    }
+
+    #[test]
+    fn transforms_invert() {
+        let loc = Location { y: 2, x: -3 };
+        for t in Transform::all() {
+            assert_eq!(t.inverse().apply(&t.apply(&loc)), loc);
+        }
+    }
+
+    #[test]
+    fn place_mirrored() {
+        let heater = Some(Peripheral::Heater {
+            pwm_channel: 0,
+            spi_channel: 0,
+        });
+
+        // the heater is on the left of the board, but the right of the shape
+        let mut grid = Grid::rectangle(1, 4);
+        grid.get_cell_mut(&Location { y: 0, x: 0 })
+            .unwrap()
+            .peripheral = heater.clone();
+        let mut shape = Grid::rectangle(1, 2);
+        shape
+            .get_cell_mut(&Location { y: 0, x: 1 })
+            .unwrap()
+            .peripheral = heater;
+
+        let plan = Plan::new(grid);
+        let placement = plan.place(&shape, 0, None).unwrap();
+        assert_eq!(
+            placement.mapping[&Location { y: 0, x: 1 }],
+            Location { y: 0, x: 0 }
+        );
+        assert_eq!(
+            placement.mapping[&Location { y: 0, x: 0 }],
+            Location { y: 0, x: 1 }
+        );
+
+        // a droplet at the shape's origin ends up at the same place
+        let dim = Location { y: 1, x: 1 };
+        assert_eq!(
+            placement.droplet_location(&Location { y: 0, x: 0 }, &dim),
+            Some(Location { y: 0, x: 1 })
+        );
+    }
+
+    #[test]
+    fn place_rotated() {
+        let shape = Grid::rectangle(1, 3);
+        let plan = Plan::new(Grid::rectangle(3, 1));
+
+        let transforms = Transform::preserving_axes();
+        let req = request(&plan, &shape, &transforms);
+        assert!(plan.place_in(&plan.occupancy(&[]), &req, 0, None).is_err());
+
+        let placement = plan.place(&shape, 0, None).unwrap();
+        assert!(placement.transform.swaps_axes());
+        let dim = Location { y: 1, x: 3 };
+        assert_eq!(
+            placement.transform.apply_dimensions(&dim),
+            Location { y: 3, x: 1 }
+        );
+    }
 }
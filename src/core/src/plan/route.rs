@@ -1,7 +1,8 @@
 use std::collections::HashSet;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use grid::{Droplet, DropletId, Grid, GridView, Location};
+use plan::Tick;
 
 use util::collections::Entry::*;
 use util::collections::{Map, Set};
@@ -12,6 +13,98 @@ use rand::Rng;
 
 pub type Path = Vec<Location>;
 
+/// Why a droplet couldn't be routed to its destination.
+#[derive(Debug, Clone, Serialize)]
+pub struct RouteFailure {
+    pub droplet: DropletId,
+    pub from: Location,
+    pub to: Location,
+    /// droplets sitting or moving right next to everywhere it could get to
+    pub blocked_by: Vec<DropletId>,
+    /// bad edges leading out of everywhere it could get to
+    pub bad_edges: Vec<(Location, Location)>,
+    /// how many nodes A* looked at before giving up
+    pub nodes_explored: usize,
+}
+
+impl RouteFailure {
+    /// `obstacles` are the (id, location, dimensions) of anything the droplet
+    /// had to stay away from.
+    fn new(
+        droplet: &Droplet,
+        exhausted: &Exhausted,
+        grid: &Grid,
+        bad_edges: &Set<(Location, Location)>,
+        obstacles: &[(DropletId, Location, Location)],
+    ) -> RouteFailure {
+        // the droplet got stuck somewhere around the edge of where it reached
+        let around: Set<Location> = exhausted
+            .reached
+            .iter()
+            .flat_map(|loc| grid.neighbors9(loc))
+            .collect();
+
+        let mut blocked_by: Vec<DropletId> = obstacles
+            .iter()
+            .filter(|&&(id, loc, dim)| {
+                id != droplet.id
+                    && around
+                        .iter()
+                        .any(|&l| boxes_touch(l, droplet.dimensions, loc, dim))
+            })
+            .map(|&(id, _, _)| id)
+            .collect();
+        blocked_by.sort();
+        blocked_by.dedup();
+
+        RouteFailure {
+            droplet: droplet.id,
+            from: droplet.location,
+            to: droplet.destination.unwrap_or(droplet.location),
+            blocked_by,
+            bad_edges: bad_edges
+                .iter()
+                .filter(|(l1, _)| exhausted.reached.contains(l1))
+                .cloned()
+                .collect(),
+            nodes_explored: exhausted.n_explored,
+        }
+    }
+}
+
+/// What `route_one` saw before it ran out of places to go
+struct Exhausted {
+    n_explored: usize,
+    reached: Set<Location>,
+}
+
+/// Limits on the conflict-based search that `GridView::route` falls back on
+/// when prioritized routing fails.
+#[derive(Debug, Clone)]
+pub struct RouteBudget {
+    /// how many sets of constraints to try before giving up
+    pub max_nodes: usize,
+    pub max_duration: Duration,
+}
+
+impl Default for RouteBudget {
+    fn default() -> Self {
+        RouteBudget {
+            max_nodes: 1000,
+            max_duration: Duration::from_secs(1),
+        }
+    }
+}
+
+/// Everything `route_in_time` routes a droplet across besides its time
+/// window: the board, the edges that can't be crossed, and what else is on
+/// the board, which is only used to explain a failure.
+pub struct RouteContext<'a> {
+    pub grid: &'a Grid,
+    pub bad_edges: &'a Set<(Location, Location)>,
+    pub obstacles: &'a [(DropletId, Location, Location)],
+}
+
 fn build_path(mut came_from: Map<Node, Node>, end_node: Node) -> Path {
     let mut path = Vec::new();
     let mut current = end_node;
@@ -43,6 +136,8 @@ type Time = u32;
 type Cost = u32;
 const MOVE_COST: Cost = 100;
 const STAY_COST: Cost = 1;
+// conflict-based search would rather not get in the way of other droplets
+const CONFLICT_COST: Cost = MOVE_COST;
 
 #[derive(Default)]
 struct AvoidanceSet {
@@ -219,27 +314,284 @@ impl Node {
 }
 
 impl GridView {
-    pub fn route(&self) -> Option<Map<DropletId, Path>> {
+    /// Routes every droplet to its destination. If that can't be done, the
+    /// error describes the last droplet that got stuck. `heading_to` names the
+    /// command each droplet is going to, which decides the keep-out regions
+    /// it may enter.
+    pub fn route(
+        &self,
+        heading_to: &Map<DropletId, &str>,
+    ) -> Result<Map<DropletId, Path>, RouteFailure> {
         let mut droplets = self.snapshot().droplets.iter().collect::<Vec<_>>();
         let mut rng = mk_rng();
+        let mut failure = None;
         for i in 1..20 {
             rng.shuffle(&mut droplets);
-            let result = route_many(&droplets, &self.grid, &self.bad_edges);
-            if result.is_some() {
-                return result;
+            match route_many(&droplets, &self.grid, &self.bad_edges, heading_to) {
+                Ok(paths) => return Ok(paths),
+                Err(f) => failure = Some(f),
             }
             trace!("route failed, trying iteration {}", i);
         }
 
-        None
+        debug!("Prioritized routing failed, trying conflict-based search");
+        route_conflicts(
+            &droplets,
+            &self.grid,
+            &self.bad_edges,
+            heading_to,
+            &self.route_budget,
+        )
+        .ok_or_else(|| failure.unwrap())
     }
 }
 
-fn route_many(
+/// Whether two droplets are close enough to touch (including diagonally)
+fn boxes_touch(loc1: Location, dim1: Location, loc2: Location, dim2: Location) -> bool {
+    loc2.y <= loc1.y + dim1.y
+        && loc1.y <= loc2.y + dim2.y
+        && loc2.x <= loc1.x + dim1.x
+        && loc1.x <= loc2.x + dim2.x
+}
+
+/// Forbids a droplet from having its top-left corner at `location` at `time`
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+struct Constraint {
+    id: DropletId,
+    location: Location,
+    time: Time,
+}
+
+#[derive(PartialEq, Eq)]
+struct ConstraintNode {
+    constraints: Vec<Constraint>,
+    paths: Map<DropletId, Path>,
+}
+
+fn total_cost(paths: &Map<DropletId, Path>) -> Cost {
+    paths.values().map(|path| path.len() as Cost).sum()
+}
+
+/// Finds every time two droplets get too close, returning a constraint for
+/// each that would keep them apart, earliest first. Droplets stay at the end
+/// of their path once they get there.
+fn conflicts(
+    droplets: &[(&DropletId, &Droplet)],
+    paths: &Map<DropletId, Path>,
+) -> Vec<(Constraint, Constraint)> {
+    let horizon = paths.values().map(|path| path.len()).max().unwrap_or(0);
+    let at = |path: &Path, t: usize| path[t.min(path.len() - 1)];
+    let mut conflicts = Vec::new();
+
+    for t in 0..horizon {
+        for (i, &(&id_a, a)) in droplets.iter().enumerate() {
+            let path_a = &paths[&id_a];
+            for &(&id_b, b) in &droplets[i + 1..] {
+                let path_b = &paths[&id_b];
+                // droplets take a tick to move, so look at the ticks around t too
+                for t_b in t.saturating_sub(1)..t + 2 {
+                    // droplets that are meant to combine may touch once one is done
+                    let done = t + 1 >= path_a.len() || t_b + 1 >= path_b.len();
+                    if a.collision_group == b.collision_group && done {
+                        continue;
+                    }
+                    let (loc_a, loc_b) = (at(path_a, t), at(path_b, t_b));
+                    if boxes_touch(loc_a, a.dimensions, loc_b, b.dimensions) {
+                        let constraint_a = Constraint {
+                            id: id_a,
+                            location: loc_a,
+                            time: t as Time,
+                        };
+                        let constraint_b = Constraint {
+                            id: id_b,
+                            location: loc_b,
+                            time: t_b as Time,
+                        };
+                        conflicts.push((constraint_a, constraint_b));
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    conflicts
+}
+
+fn first_conflict(
+    droplets: &[(&DropletId, &Droplet)],
+    paths: &Map<DropletId, Path>,
+) -> Option<(Constraint, Constraint)> {
+    conflicts(droplets, paths).into_iter().next()
+}
+
+/// Conflict-based search: route every droplet on its own, and whenever two
+/// routes conflict, try again with each droplet in turn forbidden from
+/// being where it was. Unlike `route_many`, this finds a routing whenever
+/// there is one, budget permitting.
+fn route_conflicts(
     droplets: &[(&DropletId, &Droplet)],
     grid: &Grid,
     bad_edges: &Set<(Location, Location)>,
+    heading_to: &Map<DropletId, &str>,
+    budget: &RouteBudget,
 ) -> Option<Map<DropletId, Path>> {
+    let start_time = Instant::now();
+    let num_cells = grid.locations().count() as Time;
+    let dest = |d: &Droplet| d.destination.unwrap_or(d.location);
+
+    // no amount of waiting helps if the droplets have to end up too close
+    for (i, &(_, a)) in droplets.iter().enumerate() {
+        for &(_, b) in &droplets[i + 1..] {
+            if a.collision_group != b.collision_group
+                && boxes_touch(dest(a), a.dimensions, dest(b), b.dimensions)
+            {
+                return None;
+            }
+        }
+    }
+
+    // pinned droplets won't move, so everyone else can just avoid them
+    let pinned: Vec<&Droplet> = droplets
+        .iter()
+        .map(|&(_, d)| d)
+        .filter(|d| d.pinned)
+        .collect();
+
+    let route_with = |droplet: &Droplet,
+                      constraints: &[Constraint],
+                      paths: &Map<DropletId, Path>| {
+        let mine: Vec<&Constraint> = constraints.iter().filter(|c| c.id == droplet.id).collect();
+        // leave enough time to wait out everyone else and then still get there
+        let max_time = num_cells
+            + paths.values().map(|p| p.len() as Time).max().unwrap_or(0)
+            + mine.iter().map(|c| c.time).max().unwrap_or(0);
+        let dest = dest(droplet);
+        let name = heading_to.get(&droplet.id).cloned();
+
+        let conflicts = |n: &Node| {
+            droplets.iter().any(|&(id, other)| {
+                let path = match paths.get(id) {
+                    Some(path) if *id != droplet.id => path,
+                    _ => return false,
+                };
+                let t = n.time as usize;
+                (t.saturating_sub(1)..t + 2).any(|t| {
+                    let done = t + 1 >= path.len();
+                    let loc = path[t.min(path.len() - 1)];
+                    !(other.collision_group == droplet.collision_group && done)
+                        && boxes_touch(loc, other.dimensions, n.location, n.dimensions)
+                })
+            })
+        };
+
+        let next_fn = |node: &Node| {
+            let nodes = if droplet.pinned {
+                node.stay()
+            } else {
+                node.expand(grid)
+            };
+            nodes
+                .into_iter()
+                .filter(|(_cost, n)| {
+                    !bad_edges.contains(&(node.location, n.location))
+                        && grid.may_route(droplet, n.location, name)
+                        && !mine
+                            .iter()
+                            .any(|c| c.time == n.time && c.location == n.location)
+                        && !pinned.iter().any(|p| {
+                            p.id != droplet.id
+                                && p.collision_group != droplet.collision_group
+                                && boxes_touch(p.location, p.dimensions, n.location, n.dimensions)
+                        })
+                })
+                .map(|(cost, n)| {
+                    if conflicts(&n) {
+                        (cost + CONFLICT_COST, n)
+                    } else {
+                        (cost, n)
+                    }
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // the droplet stays put once it's done, so it can't be forbidden
+        // from its destination any later than that
+        let done_fn = |node: &Node| {
+            node.location == dest
+                && !mine
+                    .iter()
+                    .any(|c| c.location == dest && c.time >= node.time)
+        };
+
+        route_one(droplet, max_time, next_fn, done_fn).ok()
+    };
+
+    let mut paths = Map::new();
+    for &(&id, droplet) in droplets {
+        let path = route_with(droplet, &[], &paths)?;
+        paths.insert(id, path);
+    }
+
+    // look at the candidates with the fewest conflicts first; that gives up
+    // on finding the shortest routing, but we only need one that works
+    let key = |paths: &Map<DropletId, Path>| (conflicts(droplets, paths).len(), total_cost(paths));
+
+    let mut todo = MinHeap::new();
+    todo.push(
+        key(&paths),
+        ConstraintNode {
+            constraints: vec![],
+            paths,
+        },
+    );
+
+    let mut n_explored = 0;
+    while let Some((_, node)) = todo.pop() {
+        n_explored += 1;
+        if n_explored > budget.max_nodes || start_time.elapsed() > budget.max_duration {
+            debug!(
+                "Conflict-based search gave up after {} nodes in {:?}",
+                n_explored,
+                start_time.elapsed()
+            );
+            return None;
+        }
+
+        let (c1, c2) = match first_conflict(droplets, &node.paths) {
+            Some(conflict) => conflict,
+            None => {
+                debug!("Conflict-based search succeeded after {} nodes", n_explored);
+                return Some(node.paths);
+            }
+        };
+
+        for &constraint in &[c1, c2] {
+            let droplet = droplets
+                .iter()
+                .map(|&(_, d)| d)
+                .find(|d| d.id == constraint.id)
+                .unwrap();
+            let mut constraints = node.constraints.clone();
+            constraints.push(constraint);
+
+            if let Some(path) = route_with(droplet, &constraints, &node.paths) {
+                let mut paths = node.paths.clone();
+                paths.insert(droplet.id, path);
+                todo.push(key(&paths), ConstraintNode { constraints, paths });
+            }
+        }
+    }
+
+    None
+}
+
+fn route_many(
+    droplets: &[(&DropletId, &Droplet)],
+    grid: &Grid,
+    bad_edges: &Set<(Location, Location)>,
+    heading_to: &Map<DropletId, &str>,
+) -> Result<Map<DropletId, Path>, RouteFailure> {
     let mut av_set = AvoidanceSet::default();
     let num_cells = grid.locations().count();
 
@@ -261,6 +613,7 @@ fn route_many(
         );
         let result = {
             let max_time = num_cells as Time + max_t;
+            let name = heading_to.get(&id).cloned();
 
             let next_fn = |node: &Node| {
                 let nodes = if droplet.pinned {
@@ -273,7 +626,9 @@ fn route_many(
                     .filter(|(_cost, n)| {
                         let l1 = node.location;
                         let l2 = n.location;
-                        !av_set.should_avoid(n) && !bad_edges.contains(&(l1, l2))
+                        !av_set.should_avoid(n)
+                            && !bad_edges.contains(&(l1, l2))
+                            && grid.may_route(droplet, l2, name)
                     }).cloned()
                     .collect::<Vec<_>>()
             };
@@ -288,8 +643,20 @@ fn route_many(
             route_one(&droplet, max_time, next_fn, done_fn)
         };
         let path = match result {
-            None => return None,
-            Some(path) => path,
+            Ok(path) => path,
+            Err(exhausted) => {
+                // only the droplets that already have a path were in the way
+                let obstacles: Vec<_> = droplets
+                    .iter()
+                    .filter_map(|&(id, d)| paths.get(id).map(|path| (d, path)))
+                    .flat_map(|(d, path): (&Droplet, &Path)| {
+                        path.iter().map(move |loc| (d.id, *loc, d.dimensions))
+                    })
+                    .collect();
+                return Err(RouteFailure::new(
+                    droplet, &exhausted, grid, bad_edges, &obstacles,
+                ));
+            }
         };
 
         max_t = max_t.max(path.len() as Time);
@@ -299,7 +666,68 @@ fn route_many(
         paths.insert(id, path);
     }
 
-    Some(paths)
+    Ok(paths)
+}
+
+/// Routes a single droplet through time, from wherever it is at `start_tick`
+/// to its destination at *exactly* `end_tick`.
+///
+/// Unlike `route_many`, this doesn't know about any other droplets. Instead,
+/// `is_free` gets asked whether the droplet may sit at a location (its top-left
+/// corner) at a given tick. The returned path has one location per tick,
+/// starting at `start_tick`. `name` is the command the droplet is headed for.
+pub fn route_in_time<F>(
+    droplet: &Droplet,
+    name: &str,
+    ctx: &RouteContext,
+    start_tick: Tick,
+    end_tick: Tick,
+    is_free: F,
+) -> Result<Path, RouteFailure>
+where
+    F: Fn(Location, Tick) -> bool,
+{
+    let RouteContext {
+        grid,
+        bad_edges,
+        obstacles,
+    } = *ctx;
+    if end_tick < start_tick || !is_free(droplet.location, start_tick) {
+        let exhausted = Exhausted {
+            n_explored: 0,
+            reached: Some(droplet.location).into_iter().collect(),
+        };
+        return Err(RouteFailure::new(
+            droplet, &exhausted, grid, bad_edges, obstacles,
+        ));
+    }
+
+    let dest = droplet.destination.unwrap_or(droplet.location);
+    let max_time = (end_tick - start_tick) as Time;
+
+    let next_fn = |node: &Node| {
+        let nodes = if droplet.pinned {
+            node.stay()
+        } else {
+            node.expand(grid)
+        };
+        nodes
+            .iter()
+            .filter(|(_cost, n)| {
+                let tick = start_tick + n.time as Tick;
+                n.time <= max_time
+                    && !bad_edges.contains(&(node.location, n.location))
+                    && grid.may_route(droplet, n.location, Some(name))
+                    && is_free(n.location, tick)
+            })
+            .cloned()
+            .collect::<Vec<_>>()
+    };
+
+    let done_fn = |node: &Node| node.location == dest && node.time == max_time;
+
+    route_one(droplet, max_time, next_fn, done_fn)
+        .map_err(|exhausted| RouteFailure::new(droplet, &exhausted, grid, bad_edges, obstacles))
 }
 
 fn route_one<FNext, FDone>(
@@ -307,7 +735,7 @@ fn route_one<FNext, FDone>(
     max_time: Time,
     mut next_fn: FNext,
     mut done_fn: FDone,
-) -> Option<Path>
+) -> Result<Path, Exhausted>
 where
     FNext: FnMut(&Node) -> Vec<(Cost, Node)>,
     FDone: FnMut(&Node) -> bool,
@@ -320,6 +748,7 @@ where
     // TODO remove done in favor of came_from
     let mut done: HashSet<Node> = HashSet::new();
     let mut n_explored = 0;
+    let mut reached = Set::new();
 
     let start_node = Node {
         location: droplet.location,
@@ -343,7 +772,10 @@ where
             Some((_, node)) => node,
             _ => {
                 trace!("Routing failed!");
-                break None;
+                break Err(Exhausted {
+                    n_explored,
+                    reached,
+                });
             }
         };
 
@@ -351,13 +783,14 @@ where
 
         if done_fn(&node) {
             let path = build_path(came_from, node);
-            break Some(path);
+            break Ok(path);
         }
 
         // insert returns false if value was already there
         if !done.insert(node) || node.time > max_time {
             continue;
         }
+        reached.insert(node.location);
 
         // node must be in best_so_far because it was inserted when we put it in
         // the minheap
@@ -415,6 +848,7 @@ mod tests {
 
     use super::*;
     use grid::gridview::tests::{c2id, parse_gridview};
+    use grid::KeepOut;
 
     // TODO make some tests
 
@@ -440,14 +874,14 @@ mod tests {
         get_droplet(&mut gv, 'b').destination = Some(dest);
 
         // this should fail because the droplets aren't allow to collide
-        assert!(gv.route().is_none());
+        assert!(gv.route(&Map::new()).is_err());
 
         get_droplet(&mut gv, 'a').collision_group = 42;
         get_droplet(&mut gv, 'b').collision_group = 42;
 
         // this should work, as the droplets are allowed to collide now
         // but, we check to make sure that they collide at the end of the path
-        let paths = gv.route().unwrap();
+        let paths = gv.route(&Map::new()).unwrap();
 
         assert_eq!(
             paths[&c2id('a')],
@@ -484,7 +918,7 @@ mod tests {
         get_droplet(&mut gv, 'b').destination = Some(Location { y: 0, x: 0 });
 
         // 'a' moved out of the way
-        let paths = gv.route().unwrap();
+        let paths = gv.route(&Map::new()).unwrap();
         assert_eq!(
             paths[&c2id('a')],
             path(&[(1, 2), (2, 2), (2, 2), (2, 2), (2, 2), (1, 2)])
@@ -493,6 +927,100 @@ mod tests {
         // once you pin 'a', 'b' no longer has a path
         get_droplet(&mut gv, 'a').pinned = true;
 
-        assert!(gv.route().is_none());
+        // whoever got stuck, it was the other one's fault
+        let failure = gv.route(&Map::new()).unwrap_err();
+        let other = if failure.droplet == c2id('a') {
+            c2id('b')
+        } else {
+            c2id('a')
+        };
+        assert_eq!(failure.blocked_by, vec![other]);
+    }
+
+    #[test]
+    fn test_keep_out() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let mut gv = parse_gridview(&[
+            "a..",
+            "...",
+        ]);
+
+        let kept_out = Location { y: 0, x: 1 };
+        gv.grid.keep_out.push(KeepOut {
+            location: kept_out,
+            dimensions: Location { y: 1, x: 1 },
+            allow: vec!["heat".into()],
+        });
+        get_droplet(&mut gv, 'a').destination = Some(Location { y: 0, x: 2 });
+
+        // the droplet has to go around
+        let paths = gv.route(&Map::new()).unwrap();
+        let around = &paths[&c2id('a')];
+        assert_eq!(around.last(), Some(&Location { y: 0, x: 2 }));
+        assert!(!around.contains(&kept_out));
+
+        // it can only go in there for a command that's allowed in
+        get_droplet(&mut gv, 'a').destination = Some(kept_out);
+        assert!(gv.route(&Map::new()).is_err());
+        let mut heading_to = Map::new();
+        heading_to.insert(c2id('a'), "move");
+        assert!(gv.route(&heading_to).is_err());
+
+        heading_to.insert(c2id('a'), "heat");
+        let paths = gv.route(&heading_to).unwrap();
+        assert_eq!(paths[&c2id('a')], path(&[(0, 0), (0, 1)]));
+    }
+
+    #[test]
+    fn test_route_failure() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let mut gv = parse_gridview(&[
+            "a..",
+        ]);
+
+        let (l0, l1, l2) = (
+            Location { y: 0, x: 0 },
+            Location { y: 0, x: 1 },
+            Location { y: 0, x: 2 },
+        );
+        get_droplet(&mut gv, 'a').destination = Some(l2);
+        gv.bad_edges.insert((l1, l2));
+        gv.bad_edges.insert((l2, l1));
+
+        let failure = gv.route(&Map::new()).unwrap_err();
+        assert_eq!(failure.droplet, c2id('a'));
+        assert_eq!((failure.from, failure.to), (l0, l2));
+        assert_eq!(failure.bad_edges, vec![(l1, l2)]);
+        assert!(failure.blocked_by.is_empty());
+        assert!(failure.nodes_explored > 0);
+    }
+
+    #[test]
+    fn test_conflict_search() {
+        #[cfg_attr(rustfmt, rustfmt_skip)]
+        let mut gv = parse_gridview(&[
+            "a...b",
+            "  .  ",
+            "  .  "
+        ]);
+
+        // the droplets have to swap, so one has to wait in the pocket
+        get_droplet(&mut gv, 'a').destination = Some(Location { y: 0, x: 4 });
+        get_droplet(&mut gv, 'b').destination = Some(Location { y: 0, x: 0 });
+
+        // no matter who goes first, prioritized routing can't do it
+        let droplets: Vec<_> = gv.snapshot().droplets.iter().collect();
+        let reversed: Vec<_> = droplets.iter().cloned().rev().collect();
+        assert!(route_many(&droplets, &gv.grid, &gv.bad_edges, &Map::new()).is_err());
+        assert!(route_many(&reversed, &gv.grid, &gv.bad_edges, &Map::new()).is_err());
+
+        let paths = gv.route(&Map::new()).unwrap();
+        assert!(first_conflict(&droplets, &paths).is_none());
+        assert_eq!(paths[&c2id('a')].last(), Some(&Location { y: 0, x: 4 }));
+        assert_eq!(paths[&c2id('b')].last(), Some(&Location { y: 0, x: 0 }));
+
+        // but it still can't do it without enough budget
+        gv.route_budget.max_nodes = 1;
+        assert!(gv.route(&Map::new()).is_err());
     }
 }
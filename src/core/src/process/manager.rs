@@ -1,10 +1,11 @@
 use std::ops::{Deref, DerefMut, Drop};
 use std::sync::{Arc, Mutex, MutexGuard};
 use std::thread;
+use std::time::Duration;
 
 use exec::Executor;
 use grid::{DropletInfo, Grid, GridView};
-use process::{Process, ProcessId, PuddleError, PuddleResult};
+use process::{PlaceQueue, Process, ProcessId, PuddleError, PuddleResult};
 
 use util::collections::Map;
 use util::endpoint::Endpoint;
@@ -44,10 +45,12 @@ impl<'a> DerefMut for ProcessHandle<'a> {
 #[allow(dead_code)]
 pub struct Manager {
     gridview: Arc<Mutex<GridView>>,
-    processes: Mutex<Map<ProcessId, Process>>,
+    // a process is taken out of its slot while a request is using it
+    processes: Mutex<Map<ProcessId, Option<Process>>>,
     exec_endpoint: Mutex<Endpoint<(), Vec<DropletInfo>>>,
     exec_thread: thread::JoinHandle<()>,
     blocking: bool,
+    queue: Arc<PlaceQueue>,
 }
 
 impl Manager {
@@ -69,9 +72,21 @@ impl Manager {
             exec_endpoint: Mutex::new(mine),
             gridview: gv_lock,
             blocking: blocking,
+            queue: Arc::default(),
         }
     }
 
+    /// Make commands that can't be placed wait up to `timeout` for room
+    /// instead of failing right away. `None` (the default) disables queuing.
+    pub fn set_queue_timeout(&self, timeout: Option<Duration>) {
+        self.queue.set_timeout(timeout)
+    }
+
+    /// Blocks until at least `n` commands are queued waiting for room
+    pub fn wait_for_queued(&self, n: usize) {
+        self.queue.wait_for_waiting(n)
+    }
+
     pub fn gridview(&self) -> MutexGuard<GridView> {
         self.gridview.lock().unwrap()
     }
@@ -80,13 +95,17 @@ impl Manager {
         self.processes
             .lock()
             .unwrap()
-            .remove(&pid)
+            .get_mut(&pid)
+            .and_then(|slot| slot.take())
             .ok_or_else(|| PuddleError::NonExistentProcess(pid))
     }
 
     fn put_process(&self, process: Process) {
-        let old = self.processes.lock().unwrap().insert(process.id(), process);
-        assert!(old.is_none());
+        // the slot is gone if the process was killed while it was out
+        if let Some(slot) = self.processes.lock().unwrap().get_mut(&process.id()) {
+            assert!(slot.is_none());
+            *slot = Some(process);
+        }
     }
 
     pub fn get_process(&self, pid: ProcessId) -> PuddleResult<ProcessHandle> {
@@ -102,19 +121,47 @@ impl Manager {
         S: Into<String>,
     {
         let gridview = Arc::clone(&self.gridview);
-        let process = Process::new(name.into(), gridview);
+        let queue = Arc::clone(&self.queue);
+        let process = Process::new(name.into(), gridview, queue);
         let pid = process.id();
         let mut procs = self.processes.lock().unwrap();
-        procs.insert(pid, process);
+        procs.insert(pid, Some(process));
         Ok(pid)
     }
 
-    pub fn close_process(&self, pid: ProcessId) -> PuddleResult<()> {
+    /// Closes the process once its commands are done, first discarding
+    /// whatever droplets it has left if `discard` is set
+    pub fn close_process(&self, pid: ProcessId, discard: bool) -> PuddleResult<()> {
         let p = self.take_process(pid)?;
+        if discard {
+            if let Err(err) = p.discard_all() {
+                // leave it open, so whatever is left can still be cleaned up
+                self.put_process(p);
+                return Err(err);
+            }
+        }
+        self.processes.lock().unwrap().remove(&pid);
         p.flush()?;
         Ok(())
     }
 
+    /// Stops a process right away, even in the middle of a request. Its
+    /// planned commands are aborted and its droplets discarded, while every
+    /// other process carries on.
+    pub fn kill_process(&self, pid: ProcessId) -> PuddleResult<()> {
+        let slot = self.processes.lock().unwrap().remove(&pid);
+        if slot.is_none() {
+            return Err(PuddleError::NonExistentProcess(pid));
+        }
+        // keep the process around until its flushes have been aborted,
+        // they need someone to tell
+        let mut gv = self.gridview();
+        gv.kill(pid);
+        // its droplets are on their way out, so queued commands may fit now
+        self.queue.notify_consumed();
+        Ok(())
+    }
+
     pub fn get_new_process<S>(&self, name: S) -> ProcessHandle
     where
         S: Into<String>,
@@ -131,3 +178,14 @@ impl Manager {
         Ok(info)
     }
 }
+
+impl Drop for Manager {
+    fn drop(&mut self) {
+        // the executor runs until the whole manager is done, not just one
+        // of its processes
+        match self.gridview.lock() {
+            Ok(mut gv) => gv.close(),
+            Err(e) => error!("Error while closing! {:?}", e),
+        }
+    }
+}
@@ -1,7 +1,9 @@
 mod manager;
 mod process;
+mod protocol;
 mod rpc;
 
 pub use self::manager::*;
 pub use self::process::*;
+pub use self::protocol::*;
 pub use self::rpc::*;
@@ -1,22 +1,31 @@
 use std::sync::atomic::AtomicUsize;
 use std::sync::atomic::Ordering::Relaxed;
-use std::sync::mpsc::channel;
-use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{channel, Receiver};
+use std::sync::{Arc, Condvar, Mutex};
+use std::time::{Duration, Instant};
 
+use util::collections::Map;
 use util::seconds_duration;
 
-use grid::{DropletId, DropletInfo, GridView, Location};
+use grid::{Droplet, DropletId, DropletInfo, GridView, HoldResult, Location, Peripheral};
 
 use command;
-use command::Command;
+use command::FlushResult;
+use command::{Command, MixOptions, Orientation, SenseReport, ThermocycleReport, ThermocycleStage};
 
 use plan::PlanError;
 
+use super::{Protocol, ProtocolHandle, ProtocolReport, Step};
+
 #[derive(Debug)]
 pub enum PuddleError {
     PlanError(PlanError),
     NonExistentDropletId(usize),
+    // the droplet belongs to some other process
+    DropletNotOwned(DropletId),
     NonExistentProcess(ProcessId),
+    InvalidArgument(String),
+    MissingPeripheral(String),
 }
 
 use PuddleError::*;
@@ -25,12 +34,63 @@ pub type PuddleResult<T> = Result<T, PuddleError>;
 
 pub type ProcessId = usize;
 
+/// Where commands wait when there's no room to place them yet.
+///
+/// With a timeout set, a command that fails with `PlaceError` is retried
+/// whenever some process consumes droplets, until the timeout runs out.
+#[derive(Default)]
+pub struct PlaceQueue {
+    timeout: Mutex<Option<Duration>>,
+    consumed: Condvar,
+    waiting: Mutex<usize>,
+    waiting_changed: Condvar,
+}
+
+impl PlaceQueue {
+    pub fn timeout(&self) -> Option<Duration> {
+        *self.timeout.lock().unwrap()
+    }
+
+    /// `None` turns queuing off, failing unplaceable commands immediately
+    pub fn set_timeout(&self, timeout: Option<Duration>) {
+        *self.timeout.lock().unwrap() = timeout;
+    }
+
+    /// Blocks until at least `n` commands are waiting for room
+    pub fn wait_for_waiting(&self, n: usize) {
+        let mut waiting = self.waiting.lock().unwrap();
+        while *waiting < n {
+            waiting = self.waiting_changed.wait(waiting).unwrap();
+        }
+    }
+
+    /// Wakes queued commands to retry, now that there may be room
+    pub(crate) fn notify_consumed(&self) {
+        self.consumed.notify_all();
+    }
+
+    fn start_waiting(&self) {
+        *self.waiting.lock().unwrap() += 1;
+        self.waiting_changed.notify_all();
+    }
+
+    fn stop_waiting(&self) {
+        *self.waiting.lock().unwrap() -= 1;
+    }
+}
+
 pub struct Process {
     id: ProcessId,
     #[allow(dead_code)]
     name: String,
     next_droplet_id: AtomicUsize,
     gridview: Arc<Mutex<GridView>>,
+    queue: Arc<PlaceQueue>,
+    // each submitted protocol ends with a flush, which says when it's done
+    protocols: Mutex<Map<ProtocolHandle, Receiver<FlushResult>>>,
+    // holds that have to be over before anything else gets planned
+    holds: Mutex<Vec<Receiver<HoldResult>>>,
+    next_protocol: AtomicUsize,
     // TODO we probably want something like this for more precise flushing
     // unresolved_droplet_ids: Mutex<Set<DropletId>>,
 }
@@ -38,12 +98,16 @@ pub struct Process {
 static NEXT_PROCESS_ID: AtomicUsize = AtomicUsize::new(0);
 
 impl Process {
-    pub fn new(name: String, gridview: Arc<Mutex<GridView>>) -> Process {
+    pub fn new(name: String, gridview: Arc<Mutex<GridView>>, queue: Arc<PlaceQueue>) -> Process {
         Process {
             id: NEXT_PROCESS_ID.fetch_add(1, Relaxed),
             name: name,
             next_droplet_id: AtomicUsize::new(0),
             gridview,
+            queue,
+            protocols: Mutex::default(),
+            holds: Mutex::default(),
+            next_protocol: AtomicUsize::new(0),
         }
     }
 
@@ -58,15 +122,70 @@ impl Process {
         }
     }
 
-    fn plan(&self, cmd: Box<dyn Command>) -> PuddleResult<()> {
+    /// Waits for this process's droplets to be let go, so its commands still
+    /// run in order even though the executor times the holds
+    fn wait_for_holds(&self) -> PuddleResult<()> {
+        let holds: Vec<_> = self.holds.lock().unwrap().drain(..).collect();
+        for done in holds {
+            // a hold that was dropped without a word can't keep anyone waiting
+            if let Ok(result) = done.recv() {
+                result.map_err(PlanError)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn plan(&self, mut cmd: Box<dyn Command>) -> PuddleResult<()> {
+        self.wait_for_holds()?;
+        let consumes = !cmd.input_droplets().is_empty();
+        let deadline = self.queue.timeout().map(|t| Instant::now() + t);
         let mut gv = self.gridview.lock().unwrap();
-        gv.plan(cmd).map_err(|(_cmd, err)| PlanError(err))
+        gv.snapshot_ensure();
+        self.check_droplets(&gv, &cmd.input_droplets())?;
+
+        loop {
+            match gv.plan(cmd) {
+                Ok(()) => {
+                    if consumes {
+                        self.queue.notify_consumed();
+                    }
+                    return Ok(());
+                }
+                Err((queued, PlanError::PlaceError)) => {
+                    let now = Instant::now();
+                    let remaining = match deadline {
+                        Some(deadline) if now < deadline => deadline - now,
+                        _ => return Err(PlanError(PlanError::PlaceError)),
+                    };
+                    debug!("Queueing {:?} until droplets are consumed", queued);
+                    // the gridview lock is only given up inside wait_timeout,
+                    // so no one can plan anything before we're really waiting
+                    self.queue.start_waiting();
+                    gv = self.queue.consumed.wait_timeout(gv, remaining).unwrap().0;
+                    self.queue.stop_waiting();
+                    cmd = queued;
+                }
+                Err((_cmd, err)) => return Err(PlanError(err)),
+            }
+        }
     }
-}
 
-impl Drop for Process {
-    fn drop(&mut self) {
-        self.close()
+    /// Makes sure the droplets are still around and belong to this process,
+    /// so a bad id from a client is an error instead of a planner panic
+    fn check_droplets(&self, gv: &GridView, ids: &[DropletId]) -> PuddleResult<()> {
+        for (i, id) in ids.iter().enumerate() {
+            if id.process_id != self.id {
+                return Err(DropletNotOwned(*id));
+            }
+            if !gv.snapshot().droplets.contains_key(id) {
+                return Err(NonExistentDropletId(id.id));
+            }
+            if ids[..i].contains(id) {
+                let msg = format!("droplet {} is used more than once", id.id);
+                return Err(InvalidArgument(msg));
+            }
+        }
+        Ok(())
     }
 }
 
@@ -84,9 +203,23 @@ impl Process {
         loc: Option<Location>,
         vol: f64,
         dim: Option<Location>,
+    ) -> PuddleResult<DropletId> {
+        self.create_labeled(loc, vol, dim, None)
+    }
+
+    /// Like `create`, but a label says what substance the droplet is made of
+    pub fn create_labeled(
+        &self,
+        loc: Option<Location>,
+        vol: f64,
+        dim: Option<Location>,
+        substance: Option<String>,
     ) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
-        let create_cmd = command::Create::new(loc, vol, dim, output)?;
+        let mut create_cmd = command::Create::new(loc, vol, dim, output)?;
+        if let Some(substance) = substance {
+            create_cmd = create_cmd.labeled(substance);
+        }
         self.plan(Box::new(create_cmd))?;
         Ok(output)
     }
@@ -116,6 +249,15 @@ impl Process {
         Ok(output)
     }
 
+    /// Stretches or squeezes `d` into a `dim`-sized rectangle, which the
+    /// planner is free to place anywhere on the board
+    pub fn reshape(&self, d: DropletId, dim: Location) -> PuddleResult<DropletId> {
+        let output = self.new_droplet_id();
+        let reshape_cmd = command::Reshape::new(d, dim, output)?;
+        self.plan(Box::new(reshape_cmd))?;
+        Ok(output)
+    }
+
     pub fn mix(&self, d1: DropletId, d2: DropletId) -> PuddleResult<DropletId> {
         let combine_out = self.new_droplet_id();
         let combine_cmd = command::Combine::new(d1, d2, combine_out)?;
@@ -128,6 +270,32 @@ impl Process {
         Ok(agitate_out)
     }
 
+    /// Combines the droplets, then agitates the result as `options` says
+    pub fn mix_with(
+        &self,
+        d1: DropletId,
+        d2: DropletId,
+        options: MixOptions,
+    ) -> PuddleResult<DropletId> {
+        let combine_out = self.new_droplet_id();
+        let agitate_out = self.new_droplet_id();
+        // check the options before combining anything
+        let agitate_cmd = command::Agitate::with_options(combine_out, agitate_out, options)?;
+
+        let combine_cmd = command::Combine::new(d1, d2, combine_out)?;
+        self.plan(Box::new(combine_cmd))?;
+        self.plan(Box::new(agitate_cmd))?;
+
+        Ok(agitate_out)
+    }
+
+    pub fn agitate(&self, d: DropletId, options: MixOptions) -> PuddleResult<DropletId> {
+        let out = self.new_droplet_id();
+        let agitate_cmd = command::Agitate::with_options(d, out, options)?;
+        self.plan(Box::new(agitate_cmd))?;
+        Ok(out)
+    }
+
     pub fn combine_into(&self, d1: DropletId, d2: DropletId) -> PuddleResult<DropletId> {
         let output = self.new_droplet_id();
         let combine_cmd = command::Combine::combine_into(d1, d2, output)?;
@@ -143,27 +311,404 @@ impl Process {
         Ok((out1, out2))
     }
 
+    /// Splits `d` into one droplet per ratio, each getting that share of
+    /// the volume, in the same order as `ratios`. Without an `orientation`,
+    /// the planner may pick either one for square droplets.
+    pub fn split_into(
+        &self,
+        d: DropletId,
+        ratios: &[f64],
+        orientation: Option<Orientation>,
+    ) -> PuddleResult<Vec<DropletId>> {
+        let outs: Vec<_> = ratios.iter().map(|_| self.new_droplet_id()).collect();
+        let split_cmd = command::Split::with_ratios(d, outs.clone(), ratios.to_vec(), orientation)?;
+        self.plan(Box::new(split_cmd))?;
+        Ok(outs)
+    }
+
     pub fn heat(&self, d: DropletId, temperature: f32, seconds: f64) -> PuddleResult<DropletId> {
+        let (d, dim) = self.fit_heater(d)?;
         let out = self.new_droplet_id();
         let duration = seconds_duration(seconds);
-        let heat_cmd = command::Heat::new(d, out, temperature, duration)?;
+        let heat_cmd = command::Heat::new(d, &dim, out, temperature, duration)?;
         self.plan(Box::new(heat_cmd))?;
         Ok(out)
     }
 
-    pub fn close(&mut self) {
-        let mut gv = match self.gridview.lock() {
-            Ok(gv) => gv,
-            Err(e) => {
-                error!("Error while closing! {:?}", e);
-                return;
-            }
+    /// Parks `d` on a heater and runs it through `stages` in order,
+    /// `cycles` times over. This waits until the program is done to report
+    /// the temperature measured at the end of every stage.
+    pub fn thermocycle(
+        &self,
+        d: DropletId,
+        stages: Vec<ThermocycleStage>,
+        cycles: u32,
+    ) -> PuddleResult<ThermocycleReport> {
+        let (d, dim) = self.fit_heater(d)?;
+        let out = self.new_droplet_id();
+        let (tx, rx) = channel();
+        // without a heater, pretend every stage hit its temperature exactly
+        let simulated: Vec<_> = stages.iter().map(|s| Some(s.temperature)).collect();
+        let thermocycle_cmd = command::Thermocycle::new(d, &dim, out, stages, cycles, tx)?;
+        self.plan(Box::new(thermocycle_cmd))?;
+
+        let n_readings = simulated.len() * cycles as usize;
+        let readings: Vec<Option<f32>> = rx
+            .iter()
+            .take(n_readings)
+            .collect::<Result<_, _>>()
+            .map_err(PlanError)?;
+        let temperatures = if self.gridview.lock().unwrap().on_hardware() {
+            readings
+                .chunks(simulated.len())
+                .map(|cycle| cycle.to_vec())
+                .collect()
+        } else {
+            vec![simulated; cycles as usize]
+        };
+        info!("Thermocycle measured {:?}", temperatures);
+        Ok(ThermocycleReport {
+            droplet: out,
+            temperatures,
+        })
+    }
+
+    /// Takes `d` to the sensor called `sensor` and waits for its reading
+    pub fn sense(&self, d: DropletId, sensor: &str) -> PuddleResult<SenseReport> {
+        let sensor = self.find_sensor(sensor)?;
+        let out = self.new_droplet_id();
+        let (tx, rx) = channel();
+        let sense_cmd = command::Sense::new(d, out, sensor, tx)?;
+        self.plan(Box::new(sense_cmd))?;
+        let reading = rx.recv().unwrap().map_err(PlanError)?;
+        Ok(SenseReport {
+            droplet: out,
+            reading,
+        })
+    }
+
+    /// Holds `d` where it is for `seconds` while the rest of the board
+    /// keeps going, the result is available once the time is up. Anything
+    /// else this process asks for waits until then.
+    pub fn incubate(&self, d: DropletId, seconds: f64) -> PuddleResult<DropletId> {
+        let out = self.new_droplet_id();
+        let (tx, rx) = channel();
+        let incubate_cmd = command::Incubate::new(d, out, seconds, tx)?;
+        self.plan(Box::new(incubate_cmd))?;
+        self.holds.lock().unwrap().push(rx);
+        Ok(out)
+    }
+
+    /// Gets rid of `d`, by sending it to the waste output if the board has
+    /// one, or else by just taking it off the board.
+    pub fn discard(&self, d: DropletId) -> PuddleResult<()> {
+        let discard_cmd = command::discard(&self.gridview.lock().unwrap(), d)?;
+        self.plan(discard_cmd)
+    }
+
+    /// Discards every droplet this process still has
+    pub fn discard_all(&self) -> PuddleResult<()> {
+        let ids: Vec<DropletId> = {
+            let mut gv = self.gridview.lock().unwrap();
+            gv.snapshot_ensure();
+            gv.snapshot()
+                .droplets
+                .keys()
+                .filter(|id| id.process_id == self.id)
+                .cloned()
+                .collect()
         };
-        gv.close();
+        for id in ids {
+            self.discard(id)?;
+        }
+        Ok(())
+    }
+
+    /// Plans every step of `protocol` at once, or none of them if any step
+    /// can't be planned. See `Protocol::order` for the order they go in.
+    ///
+    /// The protocol gets planned twice. The first time finds out where each
+    /// step ends up, and the second places each step near the one that uses
+    /// its droplets. If the second doesn't fit, the first is used after all.
+    pub fn submit_protocol(&self, protocol: Protocol) -> PuddleResult<ProtocolReport> {
+        let order = protocol.order()?;
+        self.wait_for_holds()?;
+        let droplets: Map<String, DropletId> = protocol
+            .steps
+            .iter()
+            .flat_map(|step| step.outputs())
+            .map(|name| (name.to_string(), self.new_droplet_id()))
+            .collect();
+        let (tx, rx) = channel();
+
+        let mut gv = self.gridview.lock().unwrap();
+        gv.snapshot_ensure();
+        let checkpoint = gv.checkpoint();
+
+        let steps: Vec<&Step> = order.iter().map(|&i| &protocol.steps[i]).collect();
+        let mut planned = self.plan_steps(&mut gv, &steps, &droplets);
+        let targets = match planned {
+            Ok(ref landed) => protocol.targets(&droplets, landed),
+            Err(_) => Map::new(),
+        };
+        if !targets.is_empty() {
+            gv.restore(checkpoint.clone());
+            gv.reset_plan();
+            gv.plan.targets = targets;
+            planned = self.plan_steps(&mut gv, &steps, &droplets);
+            gv.plan.targets.clear();
+            if planned.is_err() {
+                debug!("Protocol doesn't fit next to its consumers, planning it as before");
+                gv.restore(checkpoint.clone());
+                gv.reset_plan();
+                planned = self.plan_steps(&mut gv, &steps, &droplets);
+            }
+        }
+
+        let flush_cmd = command::Flush::new(self.id, tx);
+        let planned = planned.and_then(|_landed| {
+            gv.plan(Box::new(flush_cmd))
+                .map_err(|(_cmd, err)| PlanError(err))
+        });
+        if let Err(err) = planned {
+            debug!("Undoing protocol, it failed with {:?}", err);
+            gv.restore(checkpoint);
+            gv.reset_plan();
+            return Err(err);
+        }
+        if protocol.steps.iter().any(|step| !step.inputs().is_empty()) {
+            self.queue.notify_consumed();
+        }
+
+        let handle = self.next_protocol.fetch_add(1, Relaxed);
+        self.protocols.lock().unwrap().insert(handle, rx);
+        Ok(ProtocolReport { droplets, handle })
+    }
+
+    /// Plans `steps` in order, giving back where each named droplet was
+    /// when its step was done
+    fn plan_steps(
+        &self,
+        gv: &mut GridView,
+        steps: &[&Step],
+        droplets: &Map<String, DropletId>,
+    ) -> PuddleResult<Map<String, Location>> {
+        let mut new_id = || self.new_droplet_id();
+        let mut landed = Map::new();
+        for step in steps {
+            for cmd in step.commands(droplets, &mut new_id, gv)? {
+                gv.plan(cmd).map_err(|(_cmd, err)| PlanError(err))?;
+            }
+            for name in step.outputs() {
+                if let Some(droplet) = gv.snapshot().droplets.get(&droplets[name]) {
+                    landed.insert(name.to_string(), droplet.location);
+                }
+            }
+        }
+        Ok(landed)
+    }
+
+    /// Waits until the protocol is done, then gets the droplets like `flush`
+    pub fn wait_protocol(&self, handle: ProtocolHandle) -> PuddleResult<Vec<DropletInfo>> {
+        let rx = self
+            .protocols
+            .lock()
+            .unwrap()
+            .remove(&handle)
+            .ok_or_else(|| {
+                let msg = format!("no protocol with handle {} to wait on", handle);
+                InvalidArgument(msg)
+            })?;
+        rx.recv().unwrap().map_err(PlanError)
+    }
+
+    /// Plans the steps one at a time in the order they're listed, the way a
+    /// client calling each command would. Unlike `submit_protocol`, the steps
+    /// before one that fails stay planned.
+    pub fn run_protocol(&self, protocol: &Protocol) -> PuddleResult<Map<String, DropletId>> {
+        protocol.listed_order()?;
+        let droplets: Map<String, DropletId> = protocol
+            .steps
+            .iter()
+            .flat_map(|step| step.outputs())
+            .map(|name| (name.to_string(), self.new_droplet_id()))
+            .collect();
+
+        let mut new_id = || self.new_droplet_id();
+        for step in &protocol.steps {
+            let cmds = {
+                let mut gv = self.gridview.lock().unwrap();
+                gv.snapshot_ensure();
+                step.commands(&droplets, &mut new_id, &gv)?
+            };
+            for cmd in cmds {
+                self.plan(cmd)?;
+            }
+        }
+        Ok(droplets)
+    }
+
+    /// Dilutes `d` with `buffer` until the fraction of the result that came
+    /// from `d` is `target_fraction`, give or take `tolerance`.
+    ///
+    /// This is the bit-scanning method: the target gets rounded to a binary
+    /// fraction, then starting from a buffer droplet, each bit (least
+    /// significant first) mixes in a piece of `d` for a 1 or more buffer
+    /// for a 0, and half of the mixture is sent to waste. Every mix is 1:1,
+    /// so `d` is first split into a piece for each 1 bit, and buffer is
+    /// input in droplets the same size as those pieces.
+    pub fn dilute(
+        &self,
+        d: DropletId,
+        buffer: impl Into<String>,
+        target_fraction: f64,
+        tolerance: f64,
+    ) -> PuddleResult<DropletId> {
+        let buffer = buffer.into();
+        let (numerator, n_bits) = binary_fraction(target_fraction, tolerance)?;
+        if n_bits == 0 {
+            // it's already as dilute as it needs to be
+            return Ok(d);
+        }
+
+        let volume = self.get_droplet(d)?.volume;
+        self.check_input(&buffer)?;
+
+        let n_ones = numerator.count_ones() as usize;
+        let mut pieces = vec![d];
+        while pieces.len() < n_ones {
+            let mut halves = Vec::with_capacity(pieces.len() * 2);
+            for piece in pieces {
+                let (half1, half2) = self.split(piece)?;
+                halves.push(half1);
+                halves.push(half2);
+            }
+            pieces = halves;
+        }
+        let piece_volume = volume / pieces.len() as f64;
+        let piece_dim = self.get_droplet(pieces[0])?.dimensions;
+
+        for extra in pieces.split_off(n_ones) {
+            self.discard(extra)?;
+        }
+
+        let mut result = self.input(buffer.clone(), piece_volume, piece_dim)?;
+        for bit in 0..n_bits {
+            let other = if numerator & (1 << bit) != 0 {
+                pieces.pop().unwrap()
+            } else {
+                self.input(buffer.clone(), piece_volume, piece_dim)?
+            };
+            let mixed = self.mix(result, other)?;
+            let (keep, spent) = self.split(mixed)?;
+            self.discard(spent)?;
+            result = keep;
+        }
+
+        assert!(pieces.is_empty());
+        Ok(result)
+    }
+
+    fn get_droplet(&self, d: DropletId) -> PuddleResult<Droplet> {
+        let mut gv = self.gridview.lock().unwrap();
+        gv.snapshot_ensure();
+        self.check_droplets(&gv, &[d])?;
+        Ok(gv.snapshot().droplets[&d].clone())
+    }
+
+    // reshapes `d` into something a heater takes, if it isn't already,
+    // giving back the droplet to heat and its shape
+    fn fit_heater(&self, d: DropletId) -> PuddleResult<(DropletId, Location)> {
+        let droplet = self.get_droplet(d)?;
+        let shape = command::heater_shape(&droplet, &self.gridview.lock().unwrap().grid)?;
+        if shape == droplet.dimensions {
+            return Ok((d, shape));
+        }
+        Ok((self.reshape(d, shape)?, shape))
+    }
+
+    fn check_input(&self, name: &str) -> PuddleResult<()> {
+        let gv = self.gridview.lock().unwrap();
+        let found = gv
+            .grid
+            .locations()
+            .any(|(_, electrode)| match electrode.peripheral {
+                Some(Peripheral::Input { name: ref n, .. }) => n == name,
+                _ => false,
+            });
+        if found {
+            Ok(())
+        } else {
+            Err(MissingPeripheral(format!("an input for {}", name)))
+        }
+    }
+
+    fn find_sensor(&self, name: &str) -> PuddleResult<Peripheral> {
+        let gv = self.gridview.lock().unwrap();
+        let sensor = gv
+            .grid
+            .locations()
+            .filter_map(|(_, electrode)| electrode.peripheral)
+            .find(|p| match p {
+                Peripheral::Sensor { name: n, .. } => n == name,
+                _ => false,
+            });
+        sensor.ok_or_else(|| MissingPeripheral(format!("a sensor called {}", name)))
     }
 }
 
+// Most bits a dilution can use, any more and the droplets get too small
+const MAX_DILUTION_BITS: u32 = 10;
+
+/// Rounds `x` to `numerator / 2^n_bits` with as few bits as it takes to be
+/// within `tolerance`. It never rounds all the way down to 0, since that's
+/// just buffer, so small enough `x` take extra bits.
+fn binary_fraction(x: f64, tolerance: f64) -> PuddleResult<(u64, u32)> {
+    if !(0.0 < x && x <= 1.0) {
+        let msg = format!("target fraction must be in (0, 1], not {}", x);
+        return Err(InvalidArgument(msg));
+    }
+    if !(tolerance > 0.0) {
+        let msg = format!("tolerance must be positive, not {}", tolerance);
+        return Err(InvalidArgument(msg));
+    }
+
+    let round = |n_bits: u32| (x * (1u64 << n_bits) as f64).round() as u64;
+
+    // rounding is off by at most half of the last bit
+    let mut n_bits = 0;
+    while 0.5f64.powi(n_bits as i32 + 1) > tolerance || round(n_bits) == 0 {
+        n_bits += 1;
+        if n_bits > MAX_DILUTION_BITS {
+            let msg = format!("can't dilute to {} within {}", x, tolerance);
+            return Err(InvalidArgument(msg));
+        }
+    }
+
+    let mut numerator = round(n_bits);
+    while n_bits > 0 && numerator % 2 == 0 {
+        numerator /= 2;
+        n_bits -= 1;
+    }
+    Ok((numerator, n_bits))
+}
+
 #[cfg(test)]
 pub mod tests {
-    // TODO do we need tests here?
+    use super::*;
+
+    #[test]
+    fn test_binary_fraction() {
+        assert_matches!(binary_fraction(0.37, 0.1), Ok((3, 3)));
+        assert_matches!(binary_fraction(0.37, 0.001), Ok((189, 9)));
+        assert_matches!(binary_fraction(0.5, 0.001), Ok((1, 1)));
+        assert_matches!(binary_fraction(0.99, 0.1), Ok((1, 0)));
+        assert_matches!(binary_fraction(0.01, 0.1), Ok((1, 6)));
+        assert_matches!(binary_fraction(0.0001, 0.1), Err(InvalidArgument(_)));
+
+        assert_matches!(binary_fraction(0.0, 0.1), Err(InvalidArgument(_)));
+        assert_matches!(binary_fraction(1.5, 0.1), Err(InvalidArgument(_)));
+        assert_matches!(binary_fraction(0.5, 0.0), Err(InvalidArgument(_)));
+        assert_matches!(binary_fraction(0.5, 1e-9), Err(InvalidArgument(_)));
+    }
 }
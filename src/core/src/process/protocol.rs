@@ -0,0 +1,431 @@
+use command;
+use command::{BoxedCommand, MixOptions, Orientation};
+use grid::{DropletId, GridView, Location};
+use util::collections::{Map, Set};
+use util::seconds_duration;
+
+use super::{PuddleError, PuddleResult};
+
+/// A whole protocol to plan at once. Droplets are named, and steps refer to
+/// the droplets made by other steps by those names, so the names are the
+/// edges between steps. Every droplet is made by exactly one step and used
+/// by at most one.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Protocol {
+    pub steps: Vec<Step>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum Step {
+    Create {
+        output: String,
+        volume: f64,
+        #[serde(default)]
+        location: Option<Location>,
+        #[serde(default)]
+        dimensions: Option<Location>,
+        #[serde(default)]
+        substance: Option<String>,
+    },
+    Input {
+        output: String,
+        substance: String,
+        volume: f64,
+        dimensions: Location,
+    },
+    Mix {
+        inputs: Vec<String>,
+        output: String,
+        #[serde(default)]
+        options: MixOptions,
+    },
+    Split {
+        input: String,
+        outputs: Vec<String>,
+        // split evenly if these aren't given
+        #[serde(default)]
+        ratios: Option<Vec<f64>>,
+        #[serde(default)]
+        orientation: Option<Orientation>,
+    },
+    Heat {
+        input: String,
+        output: String,
+        temperature: f32,
+        seconds: f64,
+    },
+    Output {
+        input: String,
+        name: String,
+    },
+}
+
+/// Pass this to `wait_protocol` to find out when the protocol is done
+pub type ProtocolHandle = usize;
+
+/// What the client gets back from submitting a protocol: the id that each
+/// named droplet will have, and a handle to wait on
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ProtocolReport {
+    pub droplets: Map<String, DropletId>,
+    pub handle: ProtocolHandle,
+}
+
+fn invalid<T>(msg: String) -> PuddleResult<T> {
+    Err(PuddleError::InvalidArgument(msg))
+}
+
+impl Step {
+    /// The names of the droplets this step uses up
+    pub fn inputs(&self) -> Vec<&str> {
+        match self {
+            Step::Create { .. } | Step::Input { .. } => vec![],
+            Step::Mix { inputs, .. } => inputs.iter().map(|s| s.as_str()).collect(),
+            Step::Split { input, .. } | Step::Heat { input, .. } | Step::Output { input, .. } => {
+                vec![input]
+            }
+        }
+    }
+
+    /// The names of the droplets this step makes
+    pub fn outputs(&self) -> Vec<&str> {
+        match self {
+            Step::Create { output, .. }
+            | Step::Input { output, .. }
+            | Step::Mix { output, .. }
+            | Step::Heat { output, .. } => vec![output],
+            Step::Split { outputs, .. } => outputs.iter().map(|s| s.as_str()).collect(),
+            Step::Output { .. } => vec![],
+        }
+    }
+
+    fn check(&self) -> PuddleResult<()> {
+        match self {
+            Step::Mix { inputs, .. } if inputs.len() != 2 => {
+                invalid(format!("mix takes 2 droplets, not {}", inputs.len()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The commands that carry out this step. `ids` has the id for every
+    /// name in the protocol, and `gridview` has to have planned the steps
+    /// this one depends on already.
+    pub fn commands(
+        &self,
+        ids: &Map<String, DropletId>,
+        new_id: &mut dyn FnMut() -> DropletId,
+        gridview: &GridView,
+    ) -> PuddleResult<Vec<BoxedCommand>> {
+        let cmds: Vec<BoxedCommand> = match self {
+            Step::Create {
+                output,
+                volume,
+                location,
+                dimensions,
+                substance,
+            } => {
+                let mut cmd = command::Create::new(*location, *volume, *dimensions, ids[output])?;
+                if let Some(substance) = substance {
+                    cmd = cmd.labeled(substance.clone());
+                }
+                vec![Box::new(cmd)]
+            }
+            Step::Input {
+                output,
+                substance,
+                volume,
+                dimensions,
+            } => {
+                let cmd =
+                    command::Input::new(substance.clone(), *volume, *dimensions, ids[output])?;
+                vec![Box::new(cmd)]
+            }
+            Step::Mix {
+                inputs,
+                output,
+                options,
+            } => {
+                let combined = new_id();
+                let agitate =
+                    command::Agitate::with_options(combined, ids[output], options.clone())?;
+                let combine = command::Combine::new(ids[&inputs[0]], ids[&inputs[1]], combined)?;
+                vec![Box::new(combine), Box::new(agitate)]
+            }
+            Step::Split {
+                input,
+                outputs,
+                ratios,
+                orientation,
+            } => {
+                let out_ids = outputs.iter().map(|name| ids[name]).collect();
+                let ratios = ratios.clone().unwrap_or_else(|| vec![1.0; outputs.len()]);
+                let cmd = command::Split::with_ratios(ids[input], out_ids, ratios, *orientation)?;
+                vec![Box::new(cmd)]
+            }
+            Step::Heat {
+                input,
+                output,
+                temperature,
+                seconds,
+            } => {
+                let mut cmds: Vec<BoxedCommand> = vec![];
+                let mut id = ids[input];
+                let droplet = &gridview.snapshot().droplets[&id];
+                let shape = command::heater_shape(droplet, &gridview.grid)?;
+                if shape != droplet.dimensions {
+                    let reshaped = new_id();
+                    cmds.push(Box::new(command::Reshape::new(id, shape, reshaped)?));
+                    id = reshaped;
+                }
+                let duration = seconds_duration(*seconds);
+                let heat = command::Heat::new(id, &shape, ids[output], *temperature, duration)?;
+                cmds.push(Box::new(heat));
+                cmds
+            }
+            Step::Output { input, name } => {
+                vec![Box::new(command::Output::new(name.clone(), ids[input])?)]
+            }
+        };
+        Ok(cmds)
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Visit {
+    Unvisited,
+    InProgress,
+    Done,
+}
+
+impl Protocol {
+    /// The order to plan the steps in, or what's wrong with the protocol.
+    ///
+    /// The order is depth first from the steps whose droplets nobody uses,
+    /// so a step is planned as late as it can be, right before whatever
+    /// uses its droplets. That way intermediate droplets don't sit around
+    /// in the way while unrelated steps get planned.
+    pub fn order(&self) -> PuddleResult<Vec<usize>> {
+        let mut producer: Map<&str, usize> = Map::new();
+        for (i, step) in self.steps.iter().enumerate() {
+            step.check()?;
+            for name in step.outputs() {
+                if producer.insert(name, i).is_some() {
+                    return invalid(format!("droplet {} is made more than once", name));
+                }
+            }
+        }
+
+        let mut consumed = Set::new();
+        for step in &self.steps {
+            for name in step.inputs() {
+                if !producer.contains_key(name) {
+                    return invalid(format!("nothing makes droplet {}", name));
+                }
+                if !consumed.insert(name) {
+                    return invalid(format!("droplet {} is used more than once", name));
+                }
+            }
+        }
+
+        let mut order = Vec::with_capacity(self.steps.len());
+        let mut visits = vec![Visit::Unvisited; self.steps.len()];
+        for (i, step) in self.steps.iter().enumerate() {
+            let is_last = step.outputs().iter().all(|name| !consumed.contains(name));
+            if is_last {
+                self.visit(i, &producer, &mut visits, &mut order)?;
+            }
+        }
+
+        // anything left over only feeds into itself
+        if order.len() != self.steps.len() {
+            return invalid("some steps depend on each other in a cycle".into());
+        }
+        Ok(order)
+    }
+
+    /// Where each droplet should be made so it's close to the step that
+    /// uses it, given where the droplets `landed` when planned without this.
+    /// The first droplet a step makes stands in for where the step went.
+    pub fn targets(
+        &self,
+        ids: &Map<String, DropletId>,
+        landed: &Map<String, Location>,
+    ) -> Map<DropletId, Location> {
+        let mut targets = Map::new();
+        for step in &self.steps {
+            let went = step
+                .outputs()
+                .iter()
+                .filter_map(|name| landed.get(*name))
+                .next();
+            if let Some(&went) = went {
+                for name in step.inputs() {
+                    targets.insert(ids[name], went);
+                }
+            }
+        }
+        targets
+    }
+
+    /// Like `order`, but keeps the steps in the order they're listed. A
+    /// step can only use droplets that the steps before it make.
+    pub fn listed_order(&self) -> PuddleResult<Vec<usize>> {
+        self.order()?;
+        let mut made = Set::new();
+        for step in &self.steps {
+            if let Some(name) = step.inputs().into_iter().find(|name| !made.contains(name)) {
+                return invalid(format!("droplet {} is used before it is made", name));
+            }
+            made.extend(step.outputs());
+        }
+        Ok((0..self.steps.len()).collect())
+    }
+
+    fn visit(
+        &self,
+        i: usize,
+        producer: &Map<&str, usize>,
+        visits: &mut Vec<Visit>,
+        order: &mut Vec<usize>,
+    ) -> PuddleResult<()> {
+        match visits[i] {
+            Visit::Done => return Ok(()),
+            Visit::InProgress => {
+                return invalid("some steps depend on each other in a cycle".into());
+            }
+            Visit::Unvisited => {}
+        }
+
+        visits[i] = Visit::InProgress;
+        for name in self.steps[i].inputs() {
+            self.visit(producer[name], producer, visits, order)?;
+        }
+        visits[i] = Visit::Done;
+        order.push(i);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json;
+
+    fn create(output: &str) -> Step {
+        Step::Create {
+            output: output.into(),
+            volume: 1.0,
+            location: None,
+            dimensions: None,
+            substance: None,
+        }
+    }
+
+    fn mix(a: &str, b: &str, output: &str) -> Step {
+        Step::Mix {
+            inputs: vec![a.into(), b.into()],
+            output: output.into(),
+            options: MixOptions::default(),
+        }
+    }
+
+    #[test]
+    fn test_order_plans_steps_right_before_they_are_needed() {
+        let steps = vec![
+            create("a"),
+            create("b"),
+            create("c"),
+            mix("a", "b", "ab"),
+            mix("ab", "c", "abc"),
+        ];
+        let protocol = Protocol { steps };
+        assert_eq!(protocol.order().unwrap(), vec![0, 1, 3, 2, 4]);
+    }
+
+    #[test]
+    fn test_order_rejects_bad_protocols() {
+        let bad = vec![
+            // nothing makes c
+            vec![create("a"), mix("a", "c", "ac")],
+            // a is used twice
+            vec![
+                create("a"),
+                create("b"),
+                mix("a", "b", "ab"),
+                mix("a", "ab", "aab"),
+            ],
+            // a is made twice
+            vec![create("a"), create("a")],
+            // x and y are made from each other
+            vec![
+                create("a"),
+                create("b"),
+                mix("a", "y", "x"),
+                mix("b", "x", "y"),
+            ],
+        ];
+        for steps in bad {
+            let protocol = Protocol { steps };
+            assert_matches!(protocol.order(), Err(PuddleError::InvalidArgument(_)));
+        }
+    }
+
+    #[test]
+    fn test_listed_order() {
+        let steps = vec![create("a"), create("b"), mix("a", "b", "ab")];
+        let protocol = Protocol { steps };
+        assert_eq!(protocol.listed_order().unwrap(), vec![0, 1, 2]);
+
+        let steps = vec![create("a"), mix("a", "b", "ab"), create("b")];
+        let protocol = Protocol { steps };
+        assert_matches!(
+            protocol.listed_order(),
+            Err(PuddleError::InvalidArgument(_))
+        );
+    }
+
+    #[test]
+    fn test_targets_follow_consumers() {
+        let steps = vec![create("a"), create("b"), mix("a", "b", "ab")];
+        let protocol = Protocol { steps };
+        let ids: Map<String, DropletId> = ["a", "b", "ab"]
+            .iter()
+            .enumerate()
+            .map(|(id, name)| {
+                let id = DropletId { id, process_id: 0 };
+                (name.to_string(), id)
+            })
+            .collect();
+        let mixed = Location { y: 4, x: 2 };
+        let landed: Map<String, Location> = vec![
+            ("a".to_string(), Location { y: 0, x: 0 }),
+            ("b".to_string(), Location { y: 8, x: 8 }),
+            ("ab".to_string(), mixed),
+        ]
+        .into_iter()
+        .collect();
+
+        let targets = protocol.targets(&ids, &landed);
+        assert_eq!(targets.len(), 2);
+        assert_eq!(targets[&ids["a"]], mixed);
+        assert_eq!(targets[&ids["b"]], mixed);
+    }
+
+    #[test]
+    fn test_parse_protocol() {
+        let json = r#"{
+            "steps": [
+                { "op": "input", "output": "a", "substance": "water",
+                  "volume": 1.0, "dimensions": { "y": 1, "x": 1 } },
+                { "op": "split", "input": "a", "outputs": ["b", "c"] },
+                { "op": "output", "input": "b", "name": "out" }
+            ]
+        }"#;
+        let protocol: Protocol = serde_json::from_str(json).unwrap();
+        assert_eq!(protocol.steps.len(), 3);
+        assert_eq!(protocol.steps[1].outputs(), vec!["b", "c"]);
+        assert_eq!(protocol.order().unwrap(), vec![0, 1, 2]);
+    }
+}
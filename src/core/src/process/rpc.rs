@@ -1,14 +1,108 @@
 use jsonrpc_core as rpc;
+use jsonrpc_macros::Trailing;
 use std::sync::Arc;
 
+use plan::PlanError;
 use *;
 
+/// The optional last argument to `split`, by default it splits in half,
+/// horizontally unless a square droplet fits better the other way
+#[derive(Debug, Deserialize)]
+#[serde(default)]
+pub struct SplitOptions {
+    pub ratios: Vec<f64>,
+    pub orientation: Option<Orientation>,
+}
+
+impl Default for SplitOptions {
+    fn default() -> Self {
+        SplitOptions {
+            ratios: vec![1.0, 1.0],
+            orientation: None,
+        }
+    }
+}
+
+/// Every kind of error gets its own JSON-RPC error code, so clients can tell
+/// them apart without reading the message. Clients depend on these, so only
+/// ever add new ones.
+pub mod error_code {
+    // the command couldn't be planned
+    pub const PLACE_ERROR: i64 = -32000;
+    pub const ROUTE_ERROR: i64 = -32001;
+    pub const INVALID_PLAN: i64 = -32002;
+    pub const PROCESS_KILLED: i64 = -32003;
+
+    // something was wrong with the request itself
+    pub const NON_EXISTENT_DROPLET: i64 = -32010;
+    pub const DROPLET_NOT_OWNED: i64 = -32011;
+    pub const NON_EXISTENT_PROCESS: i64 = -32012;
+    pub const INVALID_ARGUMENT: i64 = -32013;
+    pub const MISSING_PERIPHERAL: i64 = -32014;
+}
+
 impl From<PuddleError> for rpc::Error {
     fn from(p_err: PuddleError) -> Self {
-        let code = rpc::ErrorCode::ServerError(0);
-        let mut err = rpc::Error::new(code);
-        err.message = format!("PuddleError: {:?}", p_err);
-        err
+        use self::error_code::*;
+
+        let (code, message, data) = match p_err {
+            PuddleError::PlanError(PlanError::PlaceError) => {
+                (PLACE_ERROR, "couldn't find room on the board".into(), None)
+            }
+            // the placement and droplets are too much to read, the failure
+            // says what actually went wrong
+            PuddleError::PlanError(PlanError::RouteError {
+                placement,
+                droplets,
+                failure,
+            }) => {
+                let message = format!(
+                    "couldn't route droplet {:?} from {} to {}",
+                    failure.droplet, failure.from, failure.to
+                );
+                let cells: Vec<Location> = placement.mapping.values().cloned().collect();
+                let droplets: Vec<DropletInfo> = droplets.iter().map(|d| d.info()).collect();
+                let data = json!({
+                    "failure": failure,
+                    "placement": cells,
+                    "droplets": droplets,
+                });
+                (ROUTE_ERROR, message, Some(data))
+            }
+            PuddleError::PlanError(PlanError::InvalidPlan(violation)) => {
+                let message = format!("plan was invalid: {:?}", violation);
+                (INVALID_PLAN, message, Some(json!(violation)))
+            }
+            PuddleError::PlanError(PlanError::ProcessKilled(pid)) => {
+                let message = format!("process {} was killed", pid);
+                (PROCESS_KILLED, message, Some(json!({ "process_id": pid })))
+            }
+            PuddleError::NonExistentDropletId(id) => {
+                let message = format!("there's no droplet {}", id);
+                (NON_EXISTENT_DROPLET, message, Some(json!({ "id": id })))
+            }
+            PuddleError::DropletNotOwned(id) => {
+                let message = format!("droplet {} belongs to process {}", id.id, id.process_id);
+                (DROPLET_NOT_OWNED, message, Some(json!({ "droplet": id })))
+            }
+            PuddleError::NonExistentProcess(pid) => {
+                let message = format!("there's no process {}", pid);
+                let data = json!({ "process_id": pid });
+                (NON_EXISTENT_PROCESS, message, Some(data))
+            }
+            PuddleError::InvalidArgument(message) => (INVALID_ARGUMENT, message, None),
+            PuddleError::MissingPeripheral(what) => {
+                let message = format!("the board doesn't have {}", what);
+                let data = json!({ "peripheral": what });
+                (MISSING_PERIPHERAL, message, Some(data))
+            }
+        };
+
+        rpc::Error {
+            code: rpc::ErrorCode::ServerError(code),
+            message: format!("PuddleError: {}", message),
+            data,
+        }
     }
 }
 
@@ -23,6 +117,13 @@ build_rpc_trait! {
 
         #[rpc(name = "close_process")]
         fn close_process(
+            &self,
+            ProcessId,
+            Trailing<bool>
+        ) -> PuddleResult<()>;
+
+        #[rpc(name = "kill_process")]
+        fn kill_process(
             &self,
             ProcessId
         ) -> PuddleResult<()>;
@@ -44,13 +145,28 @@ build_rpc_trait! {
             ProcessId
         ) -> PuddleResult<()>;
 
+        #[rpc(name = "submit_protocol")]
+        fn submit_protocol(
+            &self,
+            ProcessId,
+            Protocol
+        ) -> PuddleResult<ProtocolReport>;
+
+        #[rpc(name = "wait_protocol")]
+        fn wait_protocol(
+            &self,
+            ProcessId,
+            ProtocolHandle
+        ) -> PuddleResult<Vec<DropletInfo>>;
+
         #[rpc(name = "create")]
         fn create(
             &self,
             ProcessId,
             Option<Location>,
             f64,
-            Option<Location>
+            Option<Location>,
+            Trailing<String>
         ) -> PuddleResult<DropletId>;
 
         #[rpc(name = "input")]
@@ -78,12 +194,29 @@ build_rpc_trait! {
             Location
         ) -> PuddleResult<DropletId>;
 
+        #[rpc(name = "reshape")]
+        fn reshape(
+            &self,
+            ProcessId,
+            DropletId,
+            Location
+        ) -> PuddleResult<DropletId>;
+
         #[rpc(name = "mix")]
         fn mix(
             &self,
             ProcessId,
             DropletId,
-            DropletId
+            DropletId,
+            Trailing<MixOptions>
+        ) -> PuddleResult<DropletId>;
+
+        #[rpc(name = "agitate")]
+        fn agitate(
+            &self,
+            ProcessId,
+            DropletId,
+            Trailing<MixOptions>
         ) -> PuddleResult<DropletId>;
 
         #[rpc(name = "combine_into")]
@@ -98,8 +231,9 @@ build_rpc_trait! {
         fn split(
             &self,
             ProcessId,
-            DropletId
-        ) -> PuddleResult<(DropletId, DropletId)>;
+            DropletId,
+            Trailing<SplitOptions>
+        ) -> PuddleResult<Vec<DropletId>>;
 
         #[rpc(name = "heat")]
         fn heat(
@@ -109,6 +243,48 @@ build_rpc_trait! {
             f32,
             f64
         ) -> PuddleResult<DropletId>;
+
+        #[rpc(name = "thermocycle")]
+        fn thermocycle(
+            &self,
+            ProcessId,
+            DropletId,
+            Vec<ThermocycleStage>,
+            u32
+        ) -> PuddleResult<ThermocycleReport>;
+
+        #[rpc(name = "sense")]
+        fn sense(
+            &self,
+            ProcessId,
+            DropletId,
+            String
+        ) -> PuddleResult<SenseReport>;
+
+        #[rpc(name = "incubate")]
+        fn incubate(
+            &self,
+            ProcessId,
+            DropletId,
+            f64
+        ) -> PuddleResult<DropletId>;
+
+        #[rpc(name = "discard")]
+        fn discard(
+            &self,
+            ProcessId,
+            DropletId
+        ) -> PuddleResult<()>;
+
+        #[rpc(name = "dilute")]
+        fn dilute(
+            &self,
+            ProcessId,
+            DropletId,
+            String,
+            f64,
+            f64
+        ) -> PuddleResult<DropletId>;
     }
 }
 
@@ -122,9 +298,13 @@ impl Rpc for Arc<Manager> {
         Manager::new_process(&self, name)
     }
 
-    fn close_process(&self, pid: ProcessId) -> PuddleResult<()> {
+    fn close_process(&self, pid: ProcessId, discard: Trailing<bool>) -> PuddleResult<()> {
         // can't the function being implemented, use fully qualified name
-        Manager::close_process(&self, pid)
+        Manager::close_process(&self, pid, discard.unwrap_or_default())
+    }
+
+    fn kill_process(&self, pid: ProcessId) -> PuddleResult<()> {
+        Manager::kill_process(&self, pid)
     }
 
     //
@@ -151,15 +331,30 @@ impl Rpc for Arc<Manager> {
         p.flush().map(|_result| ())
     }
 
+    fn submit_protocol(&self, pid: ProcessId, protocol: Protocol) -> PuddleResult<ProtocolReport> {
+        let p = self.get_process(pid)?;
+        p.submit_protocol(protocol)
+    }
+
+    fn wait_protocol(
+        &self,
+        pid: ProcessId,
+        handle: ProtocolHandle,
+    ) -> PuddleResult<Vec<DropletInfo>> {
+        let p = self.get_process(pid)?;
+        p.wait_protocol(handle)
+    }
+
     fn create(
         &self,
         pid: ProcessId,
         loc: Option<Location>,
         vol: f64,
         dim: Option<Location>,
+        substance: Trailing<String>,
     ) -> PuddleResult<DropletId> {
         let p = self.get_process(pid)?;
-        p.create(loc, vol, dim)
+        p.create_labeled(loc, vol, dim, substance.into())
     }
 
     fn input(
@@ -183,9 +378,30 @@ impl Rpc for Arc<Manager> {
         p.move_droplet(d, loc)
     }
 
-    fn mix(&self, pid: ProcessId, d1: DropletId, d2: DropletId) -> PuddleResult<DropletId> {
+    fn reshape(&self, pid: ProcessId, d: DropletId, dim: Location) -> PuddleResult<DropletId> {
+        let p = self.get_process(pid)?;
+        p.reshape(d, dim)
+    }
+
+    fn mix(
+        &self,
+        pid: ProcessId,
+        d1: DropletId,
+        d2: DropletId,
+        options: Trailing<MixOptions>,
+    ) -> PuddleResult<DropletId> {
+        let p = self.get_process(pid)?;
+        p.mix_with(d1, d2, options.unwrap_or_default())
+    }
+
+    fn agitate(
+        &self,
+        pid: ProcessId,
+        d: DropletId,
+        options: Trailing<MixOptions>,
+    ) -> PuddleResult<DropletId> {
         let p = self.get_process(pid)?;
-        p.mix(d1, d2)
+        p.agitate(d, options.unwrap_or_default())
     }
 
     fn combine_into(
@@ -198,9 +414,15 @@ impl Rpc for Arc<Manager> {
         p.combine_into(d1, d2)
     }
 
-    fn split(&self, pid: ProcessId, d: DropletId) -> PuddleResult<(DropletId, DropletId)> {
+    fn split(
+        &self,
+        pid: ProcessId,
+        d: DropletId,
+        options: Trailing<SplitOptions>,
+    ) -> PuddleResult<Vec<DropletId>> {
         let p = self.get_process(pid)?;
-        p.split(d)
+        let options = options.unwrap_or_default();
+        p.split_into(d, &options.ratios, options.orientation)
     }
 
     fn heat(
@@ -213,4 +435,71 @@ impl Rpc for Arc<Manager> {
         let p = self.get_process(pid)?;
         p.heat(d, temperature, seconds)
     }
+
+    fn thermocycle(
+        &self,
+        pid: ProcessId,
+        d: DropletId,
+        stages: Vec<ThermocycleStage>,
+        cycles: u32,
+    ) -> PuddleResult<ThermocycleReport> {
+        let p = self.get_process(pid)?;
+        p.thermocycle(d, stages, cycles)
+    }
+
+    fn sense(&self, pid: ProcessId, d: DropletId, sensor: String) -> PuddleResult<SenseReport> {
+        let p = self.get_process(pid)?;
+        p.sense(d, &sensor)
+    }
+
+    fn incubate(&self, pid: ProcessId, d: DropletId, seconds: f64) -> PuddleResult<DropletId> {
+        let p = self.get_process(pid)?;
+        p.incubate(d, seconds)
+    }
+
+    fn discard(&self, pid: ProcessId, d: DropletId) -> PuddleResult<()> {
+        let p = self.get_process(pid)?;
+        p.discard(d)
+    }
+
+    fn dilute(
+        &self,
+        pid: ProcessId,
+        d: DropletId,
+        buffer: String,
+        target_fraction: f64,
+        tolerance: f64,
+    ) -> PuddleResult<DropletId> {
+        let p = self.get_process(pid)?;
+        p.dilute(d, buffer, target_fraction, tolerance)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_codes() {
+        let id = DropletId {
+            id: 3,
+            process_id: 1,
+        };
+        let err: rpc::Error = PuddleError::DropletNotOwned(id).into();
+        assert_eq!(
+            err.code,
+            rpc::ErrorCode::ServerError(error_code::DROPLET_NOT_OWNED)
+        );
+        assert_eq!(
+            err.data,
+            Some(json!({ "droplet": { "id": 3, "process_id": 1 } }))
+        );
+
+        let err: rpc::Error = PuddleError::PlanError(PlanError::PlaceError).into();
+        assert_eq!(
+            err.code,
+            rpc::ErrorCode::ServerError(error_code::PLACE_ERROR)
+        );
+        assert_eq!(err.data, None);
+    }
 }
@@ -0,0 +1,73 @@
+use std::env;
+use std::fs::{self, File};
+use std::path::PathBuf;
+use std::process::{Command, Output};
+
+extern crate serde_json;
+
+use serde_json::Value;
+
+// cargo builds the binaries next to the directory the tests run from
+fn puddle_run() -> Command {
+    let mut path = env::current_exe().unwrap();
+    path.pop();
+    if path.ends_with("deps") {
+        path.pop();
+    }
+    path.push(format!("puddle-run{}", env::consts::EXE_SUFFIX));
+    Command::new(path)
+}
+
+fn project_path(s: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+        .join("../..")
+        .join(s)
+}
+
+/// Runs `protocol` on arch01, giving back how it went and the trace it wrote
+fn run(protocol: &str) -> (Output, Vec<Vec<Value>>) {
+    let trace_path = env::temp_dir().join(format!(
+        "puddle-run-{}-{}.json",
+        protocol,
+        std::process::id()
+    ));
+    let output = puddle_run()
+        .env("PUDDLE_STEP_DELAY_MS", "1")
+        .arg("--arch")
+        .arg(project_path("tests/arches/arch01.json"))
+        .arg("--trace")
+        .arg(&trace_path)
+        .arg(project_path(&format!("tests/protocols/{}.json", protocol)))
+        .output()
+        .unwrap();
+
+    let trace = serde_json::from_reader(File::open(&trace_path).unwrap()).unwrap();
+    fs::remove_file(&trace_path).unwrap();
+    (output, trace)
+}
+
+#[test]
+fn run_protocol_file() {
+    let (output, trace) = run("dilute");
+    assert!(output.status.success(), "{:?}", output);
+
+    // the sample and buffer get mixed, then split back into two
+    let last = trace.last().unwrap();
+    assert_eq!(last.len(), 2);
+    for droplet in last {
+        assert_eq!(droplet["volume"], 1.0);
+        assert_eq!(droplet["composition"]["dye"], 0.5);
+    }
+}
+
+#[test]
+fn run_failing_protocol_file() {
+    let (output, trace) = run("too-big");
+    assert_eq!(output.status.code(), Some(1), "{:?}", output);
+    assert!(String::from_utf8_lossy(&output.stderr).contains("error"));
+
+    // the trace still shows the step that worked
+    let last = trace.last().unwrap();
+    assert_eq!(last.len(), 1);
+    assert_eq!(last[0]["volume"], 1.0);
+}
@@ -1,5 +1,6 @@
 use std::collections::{HashMap, HashSet};
 use std::env;
+use std::time::{Duration, Instant};
 
 extern crate puddle_core;
 
@@ -8,6 +9,8 @@ use puddle_core::PuddleError;
 
 extern crate crossbeam;
 
+extern crate serde_json;
+
 extern crate env_logger;
 
 #[macro_use]
@@ -94,6 +97,30 @@ fn move_droplet() {
     assert!(float_epsilon_equal(droplets[&id2].volume, 1.0));
 }
 
+#[test]
+fn reshape_droplet() {
+    let man = manager_from_rect(8, 8);
+    let p = man.get_new_process("test");
+
+    let loc = Location { y: 2, x: 2 };
+    let square = Location { y: 2, x: 2 };
+    let mut id = p.create(Some(loc), 4.0, Some(square)).unwrap();
+
+    for &(y, x) in &[(1, 4), (4, 1), (3, 3), (2, 2)] {
+        let dim = Location { y, x };
+        id = p.reshape(id, dim).unwrap();
+        let droplets = info_dict(&p);
+        assert_eq!(droplets[&id].dimensions, dim);
+        assert!(float_epsilon_equal(droplets[&id].volume, 4.0));
+        id = p.reshape(id, square).unwrap();
+    }
+
+    assert_matches!(
+        p.reshape(id, Location { y: 0, x: 2 }),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
 #[test]
 fn mix3() {
     let man = manager_from_rect(20, 20);
@@ -113,6 +140,73 @@ fn mix3() {
     assert!(float_epsilon_equal(droplets[&id123].volume, 3.0));
 }
 
+#[test]
+fn agitate_patterns() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 2, x: 2 };
+    let mut id = p
+        .create_labeled(None, 2.0, Some(dim), Some("dye".into()))
+        .unwrap();
+
+    let patterns = [
+        MixPattern::Square,
+        MixPattern::Shuttle,
+        MixPattern::FigureEight,
+        MixPattern::SplitMerge,
+    ];
+    for &pattern in &patterns {
+        let options = MixOptions {
+            pattern,
+            loops: Some(2),
+            seconds: None,
+        };
+        id = p.agitate(id, options).unwrap();
+    }
+
+    let options = MixOptions {
+        pattern: MixPattern::Shuttle,
+        loops: None,
+        seconds: Some(0.01),
+    };
+    id = p.agitate(id, options).unwrap();
+
+    // mixing shouldn't change anything about the droplet itself
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 1);
+    assert_eq!(droplets[&id].dimensions, dim);
+    assert!(float_epsilon_equal(droplets[&id].volume, 2.0));
+    assert!(float_epsilon_equal(droplets[&id].composition["dye"], 2.0));
+
+    let options = MixOptions {
+        pattern: MixPattern::Square,
+        loops: Some(2),
+        seconds: Some(1.0),
+    };
+    assert_matches!(p.agitate(id, options), Err(PuddleError::InvalidArgument(_)));
+}
+
+#[test]
+fn mix_with_pattern() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let id1 = p.create(None, 1.0, None).unwrap();
+    let id2 = p.create(None, 1.0, None).unwrap();
+
+    let options = MixOptions {
+        pattern: MixPattern::FigureEight,
+        loops: Some(3),
+        seconds: None,
+    };
+    let id12 = p.mix_with(id1, id2, options).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 1);
+    assert!(float_epsilon_equal(droplets[&id12].volume, 2.0));
+}
+
 #[test]
 fn mix_split() {
     let man = manager_from_rect(9, 9);
@@ -137,6 +231,40 @@ fn mix_split() {
     assert!(float_epsilon_equal(droplets[&id5].volume, 0.5));
 }
 
+#[test]
+fn mix_split_composition() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let dye = p
+        .create_labeled(None, 1.0, None, Some("dye".into()))
+        .unwrap();
+    let water = p
+        .create_labeled(None, 3.0, None, Some("water".into()))
+        .unwrap();
+    // nothing is known about what's in this one
+    let unknown = p.create(None, 2.0, None).unwrap();
+
+    let mixed = p.mix(dye, water).unwrap();
+    let (half1, half2) = p.split(mixed).unwrap();
+    let diluted = p.mix(half1, unknown).unwrap();
+
+    let droplets = info_dict(&p);
+
+    let half = &droplets[&half2].composition;
+    assert_eq!(half.len(), 2);
+    assert!(float_epsilon_equal(half["dye"], 0.5));
+    assert!(float_epsilon_equal(half["water"], 1.5));
+
+    let d = &droplets[&diluted];
+    assert!(float_epsilon_equal(d.volume, 4.0));
+    assert!(float_epsilon_equal(d.composition["dye"] / d.volume, 0.125));
+    assert!(float_epsilon_equal(
+        d.composition["water"] / d.volume,
+        0.375
+    ));
+}
+
 // #[test]
 // fn split_with_error() {
 //     let man = manager_from_rect_with_error(10, 10, 0.1);
@@ -183,37 +311,245 @@ fn create_does_not_fit() {
     );
 }
 
-fn check_mix_dimensions(dim1: Location, dim2: Location, dim_result: Location) {
+#[test]
+fn create_queues_until_room() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a" ],
+            [ "a", "a" ]
+        ],
+        "peripherals": {
+            "(0, 0)": {
+                "type": "Output",
+                "pwm_channel": 0,
+                "name": "out"
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    man.set_queue_timeout(Some(Duration::from_secs(10)));
+    let p1 = man.get_new_process("test1");
+    let p2 = man.get_new_process("test2");
+
+    let id1 = p1.create(None, 1.0, None).unwrap();
+
+    crossbeam::scope(|scope| {
+        let queued = scope.spawn(|| p2.create(None, 1.0, None));
+        man.wait_for_queued(1);
+        p1.output("out", id1).unwrap();
+        assert!(queued.join().is_ok());
+    });
+
+    assert_eq!(info_dict(&p2).len(), 1);
+}
+
+#[test]
+fn kill_makes_room_for_queued() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a" ],
+            [ "a", "a" ]
+        ],
+        "peripherals": {
+            "(0, 0)": {
+                "type": "Output",
+                "pwm_channel": 0,
+                "name": "out"
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let timeout = Duration::from_secs(10);
+    man.set_queue_timeout(Some(timeout));
+    let p1 = man.get_new_process("killed");
+    let p2 = man.get_new_process("queued");
+    let pid1 = p1.id();
+
+    let id1 = p1.create(None, 1.0, None).unwrap();
+    p1.incubate(id1, 60.0).unwrap();
+
+    let start = Instant::now();
+    crossbeam::scope(|scope| {
+        let queued = scope.spawn(|| p2.create(None, 1.0, None));
+        man.wait_for_queued(1);
+        man.kill_process(pid1).unwrap();
+        assert!(queued.join().is_ok());
+    });
+    // the kill woke the queued create, it didn't just time out into room
+    assert!(start.elapsed() < timeout / 2);
+
+    assert_eq!(info_dict(&p2).len(), 1);
+    assert!(man
+        .gridview()
+        .snapshot()
+        .holds
+        .iter()
+        .all(|hold| hold.droplet.process_id != pid1));
+}
+
+#[test]
+fn create_queue_times_out() {
+    let man = manager_from_rect(2, 2);
+    man.set_queue_timeout(Some(Duration::from_millis(50)));
+    let p = man.get_new_process("test");
+
+    let _id1 = p.create(None, 1.0, None).unwrap();
+    let id2 = p.create(None, 1.0, None);
+
+    assert_matches!(
+        id2,
+        Err(PuddleError::PlanError(plan::PlanError::PlaceError))
+    );
+}
+
+#[test]
+fn bad_droplet_ids() {
+    let man = manager_from_rect(9, 9);
+    let p0 = man.get_new_process("test0");
+    let p1 = man.get_new_process("test1");
+
+    let a = p0.create(None, 1.0, None).unwrap();
+    let b = p0.create(None, 1.0, None).unwrap();
+    let theirs = p1.create(None, 1.0, None).unwrap();
+    let ab = p0.mix(a, b).unwrap();
+
+    // a was used up by the mix
+    assert_matches!(
+        p0.move_droplet(a, Location { y: 0, x: 0 }),
+        Err(PuddleError::NonExistentDropletId(_))
+    );
+    assert_matches!(p0.mix(ab, theirs), Err(PuddleError::DropletNotOwned(_)));
+    assert_matches!(
+        p0.heat(theirs, 50.0, 1.0),
+        Err(PuddleError::DropletNotOwned(_))
+    );
+    assert_matches!(p0.mix(ab, ab), Err(PuddleError::InvalidArgument(_)));
+
+    // none of that got in the way of using the droplets properly
+    let (c, d) = p0.split(ab).unwrap();
+    assert_eq!(info_dict(&p0).len(), 2);
+    p0.mix(c, d).unwrap();
+    assert_eq!(info_dict(&p1).len(), 1);
+}
+
+#[test]
+fn close_process_keeps_others_running() {
+    let man = manager_from_rect(4, 4);
+    let pid = man.new_process("closed").unwrap();
+    let p = man.get_new_process("kept");
+
+    man.get_process(pid)
+        .unwrap()
+        .create(None, 1.0, None)
+        .unwrap();
+    man.close_process(pid, false).unwrap();
+
+    p.create(None, 1.0, None).unwrap();
+    assert_eq!(info_dict(&p).len(), 1);
+}
+
+#[test]
+fn kill_process() {
+    let man = manager_from_rect(6, 6);
+    let p0 = man.get_new_process("killed");
+    let p1 = man.get_new_process("kept");
+    let pid0 = p0.id();
+
+    let id0 = p0.create(None, 1.0, None).unwrap();
+    let id1 = p1.create(None, 1.0, None).unwrap();
+    p0.flush().unwrap();
+    p0.incubate(id0, 1.0).unwrap();
+
+    crossbeam::scope(|scope| {
+        // the flush waits on the incubate, until the process is killed. If
+        // the kill gets there first, the flush can't be planned at all.
+        let flushing = scope.spawn(|| p0.flush());
+        man.kill_process(pid0).unwrap();
+        assert_matches!(
+            flushing.join(),
+            Err(PuddleError::PlanError(plan::PlanError::ProcessKilled(_)))
+        );
+    });
+
+    // the other process carries on without waiting
+    let id1 = p1.agitate(id1, MixOptions::default()).unwrap();
+    assert!(info_dict(&p1).contains_key(&id1));
+
+    // but the killed one is gone, droplets and all
+    assert!(man
+        .gridview()
+        .snapshot()
+        .droplets
+        .keys()
+        .all(|id| id.process_id != pid0));
+    assert_matches!(
+        p0.create(None, 1.0, None),
+        Err(PuddleError::PlanError(plan::PlanError::ProcessKilled(_)))
+    );
+    assert_matches!(
+        man.kill_process(pid0),
+        Err(PuddleError::NonExistentProcess(_))
+    );
+}
+
+// which way they get combined depends on where they were created, so there
+// may be a couple of right answers
+fn check_mix_volumes(
+    (vol1, dim1): (f64, Location),
+    (vol2, dim2): (f64, Location),
+    dim_results: &[Location],
+) {
     let man = manager_from_rect(20, 20);
     let p = man.get_new_process("test");
 
-    let id1 = p.create(None, 1.0, Some(dim1)).unwrap();
-    let id2 = p.create(None, 1.0, Some(dim2)).unwrap();
+    let id1 = p.create(None, vol1, Some(dim1)).unwrap();
+    let id2 = p.create(None, vol2, Some(dim2)).unwrap();
 
     let id12 = p.mix(id1, id2).unwrap();
 
     let droplets = info_dict(&p);
 
     assert_eq!(droplets.len(), 1);
-    assert_eq!(droplets[&id12].dimensions, dim_result);
+    assert!(dim_results.contains(&droplets[&id12].dimensions));
+    assert!(float_epsilon_equal(droplets[&id12].volume, vol1 + vol2));
+}
+
+// droplets with a volume of one per cell
+fn check_mix_dimensions(dim1: Location, dim2: Location, dim_results: &[Location]) {
+    let volume = |dim: Location| (dim.y * dim.x) as f64;
+    check_mix_volumes((volume(dim1), dim1), (volume(dim2), dim2), dim_results);
 }
 
 #[test]
 fn mix_dimensions_size() {
+    // 3 cells stacked, or spread out over the 2 rows of the taller one
     check_mix_dimensions(
         Location { y: 1, x: 1 },
         Location { y: 2, x: 1 },
-        Location { y: 3, x: 1 },
+        &[Location { y: 3, x: 1 }, Location { y: 2, x: 2 }],
     );
+    // 4 cells make a square either way
     check_mix_dimensions(
         Location { y: 1, x: 2 },
         Location { y: 2, x: 1 },
-        Location { y: 3, x: 2 },
+        &[Location { y: 2, x: 2 }],
+    );
+}
+
+#[test]
+fn mix_dimensions_follow_volume() {
+    // the 2x2 one only holds as much as the 1x1 one, so together they need
+    // 2 cells, not 5
+    check_mix_volumes(
+        (1.0, Location { y: 1, x: 1 }),
+        (1.0, Location { y: 2, x: 2 }),
+        &[Location { y: 1, x: 2 }, Location { y: 2, x: 1 }],
     );
 }
 
 #[test]
-#[ignore]
 fn mix_dimensions_too_large_to_combine() {
     // recall, this is on 20x20 board
 
@@ -221,14 +557,14 @@ fn mix_dimensions_too_large_to_combine() {
     check_mix_dimensions(
         Location { y: 11, x: 3 },
         Location { y: 11, x: 3 },
-        Location { y: 11, x: 6 },
+        &[Location { y: 11, x: 6 }],
     );
 
     // too wide to fit horizontally
     check_mix_dimensions(
         Location { y: 3, x: 11 },
         Location { y: 3, x: 11 },
-        Location { y: 6, x: 11 },
+        &[Location { y: 6, x: 11 }],
     );
 }
 
@@ -275,7 +611,89 @@ fn split_dimensions_size() {
 }
 
 #[test]
-#[ignore]
+fn split_unequal() {
+    let man = manager_from_rect(9, 9);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 1, x: 4 };
+    let id = p.create(None, 2.0, Some(dim)).unwrap();
+    let ids = p
+        .split_into(id, &[1.0, 3.0], Some(Orientation::Horizontal))
+        .unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 2);
+    assert!(float_epsilon_equal(droplets[&ids[0]].volume, 0.5));
+    assert!(float_epsilon_equal(droplets[&ids[1]].volume, 1.5));
+    assert_eq!(droplets[&ids[0]].dimensions, Location { y: 1, x: 1 });
+    assert_eq!(droplets[&ids[1]].dimensions, Location { y: 1, x: 3 });
+}
+
+#[test]
+fn split_sizes_follow_volume() {
+    let man = manager_from_rect(9, 15);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 3, x: 3 };
+    let id = p.create(None, 9.0, Some(dim)).unwrap();
+    let ids = p
+        .split_into(id, &[1.0, 8.0], Some(Orientation::Horizontal))
+        .unwrap();
+
+    // the small one only needs a single cell, not a whole column
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&ids[0]].dimensions, Location { y: 1, x: 1 });
+    assert_eq!(droplets[&ids[1]].dimensions, Location { y: 3, x: 3 });
+}
+
+#[test]
+fn split_vertical() {
+    // too narrow to split horizontally
+    let man = manager_from_rect(9, 3);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 3, x: 2 };
+    let id = p.create(None, 1.0, Some(dim)).unwrap();
+    let ids = p
+        .split_into(id, &[1.0, 1.0], Some(Orientation::Vertical))
+        .unwrap();
+
+    let droplets = info_dict(&p);
+    let (d0, d1) = (&droplets[&ids[0]], &droplets[&ids[1]]);
+    assert_eq!(d0.dimensions, Location { y: 2, x: 2 });
+    assert_eq!(d1.dimensions, Location { y: 2, x: 2 });
+    assert_eq!(d0.location.x, d1.location.x);
+    assert!(d0.location.y.max(d1.location.y) - d0.location.y.min(d1.location.y) >= 4);
+}
+
+#[test]
+fn split_three_ways() {
+    let man = manager_from_rect(9, 15);
+    let p = man.get_new_process("test");
+
+    let id = p.create(None, 3.0, Some(Location { y: 1, x: 3 })).unwrap();
+    let ids = p
+        .split_into(id, &[1.0, 1.0, 1.0], Some(Orientation::Horizontal))
+        .unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 3);
+    for id in &ids {
+        assert!(float_epsilon_equal(droplets[id].volume, 1.0));
+        assert_eq!(droplets[id].dimensions, Location { y: 1, x: 1 });
+    }
+
+    assert_matches!(
+        p.split_into(ids[0], &[1.0], Some(Orientation::Horizontal)),
+        Err(PuddleError::InvalidArgument(_))
+    );
+    assert_matches!(
+        p.split_into(ids[0], &[1.0, -1.0], Some(Orientation::Horizontal)),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
+#[test]
 fn create_dimensions_failure_overlap() {
     let man = manager_from_rect(9, 9);
     let p = man.get_new_process("test");
@@ -286,9 +704,21 @@ fn create_dimensions_failure_overlap() {
     let loc1 = Location { y: 0, x: 1 };
     let loc2 = Location { y: 1, x: 3 };
 
-    let _id1 = p.create(Some(loc1), 1.0, Some(dim1)).unwrap();
+    let id1 = p.create(Some(loc1), 1.0, Some(dim1)).unwrap();
     let id2 = p.create(Some(loc2), 1.0, Some(dim2));
-    assert!(id2.is_err())
+    assert_matches!(
+        id2,
+        Err(PuddleError::PlanError(plan::PlanError::InvalidPlan(
+            grid::Violation::Collision { .. }
+        )))
+    );
+
+    // the bad create didn't leave anything behind, and the process goes on
+    let id3 = p.create(None, 1.0, None).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 2);
+    assert_eq!(droplets[&id1].location, loc1);
+    assert!(droplets.contains_key(&id3));
 }
 
 #[test]
@@ -370,6 +800,506 @@ fn heat_droplet() {
     assert_eq!(droplets[&id1].location, header_loc);
 }
 
+#[test]
+fn heat_wide_droplet() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(4, 2)": {
+                "type": "Heater",
+                "pwm_channel": 0,
+                "spi_channel": 0
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    // heaters only take droplets one cell wide, so this gets reshaped
+    let dim = Location { y: 2, x: 2 };
+    let id0 = p.create(None, 1.0, Some(dim)).unwrap();
+    let id1 = p.heat(id0, 60.0, 1.0).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id1].dimensions, Location { y: 4, x: 1 });
+    assert_eq!(droplets[&id1].location, Location { y: 1, x: 2 });
+}
+
+#[test]
+fn heat_droplet_too_big_for_heater() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(2, 2)": {
+                "type": "Heater",
+                "pwm_channel": 0,
+                "spi_channel": 0
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    // a 2x2 droplet would have to become 4 tall, but the board is only 3
+    let dim = Location { y: 2, x: 2 };
+    let id0 = p.create(None, 1.0, Some(dim)).unwrap();
+    assert_matches!(p.heat(id0, 60.0, 1.0), Err(PuddleError::InvalidArgument(_)));
+
+    // protocols reshape for the heater the same way
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+    let steps: Vec<Step> = serde_json::from_str(
+        r#"[
+            { "op": "create", "output": "a", "volume": 1.0,
+              "dimensions": { "y": 2, "x": 2 } },
+            { "op": "heat", "input": "a", "output": "b",
+              "temperature": 60.0, "seconds": 1.0 }
+        ]"#,
+    )
+    .unwrap();
+    assert_matches!(
+        p.submit_protocol(Protocol { steps }),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
+#[test]
+fn thermocycle_droplet() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(3, 2)": {
+                "type": "Heater",
+                "pwm_channel": 0,
+                "spi_channel": 0
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 1, x: 1 };
+    let id0 = p.create(None, 1.0, Some(dim)).unwrap();
+    let stage = |temperature, seconds| ThermocycleStage {
+        temperature,
+        seconds,
+    };
+    let stages = vec![stage(95.0, 0.01), stage(60.0, 0.01), stage(72.0, 0.01)];
+    let start = Instant::now();
+    let report = p.thermocycle(id0, stages, 2).unwrap();
+
+    // there's no heater in the simulation, so every stage hits its mark
+    let cycle = vec![Some(95.0), Some(60.0), Some(72.0)];
+    assert_eq!(report.temperatures, vec![cycle.clone(), cycle]);
+
+    let droplets = info_dict(&p);
+    let heater_loc = Location { y: 3, x: 2 };
+    assert_eq!(droplets.len(), 1);
+    assert_eq!(droplets[&report.droplet].location, heater_loc);
+
+    // the droplet sat on the heater for every stage of the program, and
+    // that took a single planned snapshot, not one per tick
+    assert!(start.elapsed() >= Duration::from_millis(2 * 3 * 10));
+    let held_for = man
+        .gridview()
+        .completed()
+        .iter()
+        .filter(|snapshot| snapshot.droplets.contains_key(&report.droplet))
+        .count();
+    assert!(held_for < 10);
+
+    assert_matches!(
+        p.thermocycle(report.droplet, vec![], 2),
+        Err(PuddleError::InvalidArgument(_))
+    );
+    assert_matches!(
+        p.thermocycle(report.droplet, vec![stage(95.0, 1.0)], 0),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
+#[test]
+fn sense_droplet() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(0, 4)": {
+                "type": "Sensor",
+                "name": "thermometer",
+                "kind": "temperature"
+            },
+            "(3, 0)": {
+                "type": "Sensor",
+                "name": "camera",
+                "kind": "area"
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let id0 = p.create(None, 1.0, None).unwrap();
+    let report = p.sense(id0, "thermometer").unwrap();
+    // the simulated thermometer is always at room temperature
+    let celsius = 25.0;
+    assert_eq!(report.reading, Some(Reading::Temperature { celsius }));
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&report.droplet].location, Location { y: 0, x: 4 });
+
+    let dim = Location { y: 1, x: 2 };
+    let id1 = p.create(None, 2.0, Some(dim)).unwrap();
+    let report = p.sense(id1, "camera").unwrap();
+    assert_eq!(report.reading, Some(Reading::Area { cells: 2.0 }));
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&report.droplet].location, Location { y: 3, x: 0 });
+
+    assert_matches!(
+        p.sense(report.droplet, "microscope"),
+        Err(PuddleError::MissingPeripheral(_))
+    );
+}
+
+#[test]
+fn incubate_does_not_block() {
+    let man = manager_from_rect(6, 6);
+    let p0 = man.get_new_process("incubating");
+    let p1 = man.get_new_process("moving");
+
+    let loc0 = Location { y: 1, x: 1 };
+    let id0 = p0.create(Some(loc0), 1.0, None).unwrap();
+    let id1 = p1.create(Some(Location { y: 4, x: 4 }), 1.0, None).unwrap();
+    p0.flush().unwrap();
+    p1.flush().unwrap();
+
+    let wait = Duration::from_millis(500);
+    let start = Instant::now();
+    let id0 = p0.incubate(id0, 0.5).unwrap();
+
+    // the other process gets to mix while the droplet waits
+    let options = MixOptions {
+        loops: Some(3),
+        ..MixOptions::default()
+    };
+    let id1 = p1.agitate(id1, options).unwrap();
+    p1.flush().unwrap();
+    assert!(start.elapsed() < wait);
+    assert!(info_dict(&p1).contains_key(&id1));
+
+    // but this process has to wait for the incubated droplet
+    let droplets = info_dict(&p0);
+    assert!(start.elapsed() >= wait);
+    assert_eq!(droplets[&id0].location, loc0);
+    assert!(float_epsilon_equal(droplets[&id0].volume, 1.0));
+
+    assert_matches!(p0.incubate(id0, -1.0), Err(PuddleError::InvalidArgument(_)));
+}
+
+#[test]
+fn incubate_for_a_minute() {
+    let man = manager_from_rect(6, 6);
+    let p0 = man.get_new_process("incubating");
+    let p1 = man.get_new_process("moving");
+
+    let id0 = p0.create(None, 1.0, None).unwrap();
+    p0.flush().unwrap();
+    p0.incubate(id0, 60.0).unwrap();
+
+    // the wait isn't planned out a tick at a time, even as others keep
+    // planning around the droplet
+    let planned = || {
+        let gv = man.gridview();
+        gv.last_tick() + 1 - gv.now()
+    };
+    assert!(planned() < 10);
+    let id1 = p1.create(None, 1.0, None).unwrap();
+    p1.agitate(id1, MixOptions::default()).unwrap();
+    p1.flush().unwrap();
+    assert!(planned() < 10);
+
+    // there's no need to wait out the minute
+    man.kill_process(p0.id()).unwrap();
+}
+
+#[test]
+fn input_on_either_side() {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(0, 0)": {
+                "type": "Input",
+                "pwm_channel": 0,
+                "name": "water"
+            }
+        }
+    }"#;
+
+    let man = manager_from_str(board_str);
+    let p = man.get_new_process("test");
+
+    let dim = Location { y: 1, x: 1 };
+    let id = p.input("water", 1.0, dim).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&id].location, Location { y: 0, x: 1 });
+    assert_eq!(droplets[&id].composition.len(), 1);
+    assert!(float_epsilon_equal(droplets[&id].composition["water"], 1.0));
+}
+
+fn dilution_board() -> Manager {
+    let board_str = r#"{
+        "board": [
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ],
+            [ "a", "a", "a", "a", "a", "a", "a", "a", "a", "a" ]
+        ],
+        "peripherals": {
+            "(0, 0)": {
+                "type": "Input",
+                "pwm_channel": 0,
+                "name": "buffer"
+            },
+            "(4, 9)": {
+                "type": "Output",
+                "pwm_channel": 1,
+                "name": "waste"
+            }
+        },
+        "waste": "waste"
+    }"#;
+    manager_from_str(board_str)
+}
+
+#[test]
+fn dilute() {
+    let man = dilution_board();
+    let p = man.get_new_process("test");
+
+    let loc = Location { y: 4, x: 4 };
+    let sample = p
+        .create_labeled(Some(loc), 2.0, None, Some("dye".into()))
+        .unwrap();
+
+    // rounds to 3/8, which needs 2 pieces of the sample and 2 of buffer
+    let diluted = p.dilute(sample, "buffer", 0.37, 0.1).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.keys().collect::<Vec<_>>(), vec![&diluted]);
+
+    let d = &droplets[&diluted];
+    assert!(float_epsilon_equal(d.volume, 1.0));
+    assert!(float_epsilon_equal(d.composition["dye"] / d.volume, 0.375));
+    assert!(float_epsilon_equal(
+        d.composition["buffer"] / d.volume,
+        0.625
+    ));
+}
+
+#[test]
+fn discard() {
+    // with a waste output, and without one
+    for man in vec![dilution_board(), manager_from_rect(8, 10)] {
+        let p = man.get_new_process("test");
+        let loc = Location { y: 4, x: 4 };
+        let id0 = p.create(Some(loc), 1.0, None).unwrap();
+        let id1 = p.create(None, 1.0, None).unwrap();
+
+        p.discard(id0).unwrap();
+        let droplets = info_dict(&p);
+        assert_eq!(droplets.keys().collect::<Vec<_>>(), vec![&id1]);
+
+        let pid = p.id();
+        drop(p);
+        man.close_process(pid, true).unwrap();
+        assert!(man.gridview().exec_snapshot().droplets.is_empty());
+    }
+}
+
+#[test]
+fn close_process_discard_failure() {
+    let man = dilution_board();
+    let blocker = man.get_new_process("blocker");
+    let pid = man.new_process("closed").unwrap();
+
+    // nothing can get to the waste output past this
+    let on_waste = Location { y: 4, x: 8 };
+    blocker.create(Some(on_waste), 1.0, None).unwrap();
+    man.get_process(pid)
+        .unwrap()
+        .create(None, 1.0, None)
+        .unwrap();
+
+    assert!(man.close_process(pid, true).is_err());
+
+    // the process is still there to try again
+    let p = man.get_process(pid).unwrap();
+    assert_eq!(info_dict(&p).len(), 1);
+    drop(p);
+    man.close_process(pid, false).unwrap();
+}
+
+#[test]
+fn submit_protocol() {
+    let man = dilution_board();
+    let p = man.get_new_process("test");
+
+    let protocol: Protocol = serde_json::from_str(
+        r#"{
+            "steps": [
+                { "op": "split", "input": "mixed", "outputs": ["kept", "spent"] },
+                { "op": "create", "output": "sample", "volume": 1.0, "substance": "dye" },
+                { "op": "input", "output": "buffer", "substance": "buffer",
+                  "volume": 1.0, "dimensions": { "y": 1, "x": 1 } },
+                { "op": "mix", "inputs": ["sample", "buffer"], "output": "mixed" },
+                { "op": "output", "input": "spent", "name": "waste" }
+            ]
+        }"#,
+    )
+    .unwrap();
+
+    let report = p.submit_protocol(protocol).unwrap();
+    let names: Vec<_> = report.droplets.keys().cloned().collect();
+    assert_eq!(names, vec!["buffer", "kept", "mixed", "sample", "spent"]);
+
+    let droplets: HashMap<_, _> = p
+        .wait_protocol(report.handle)
+        .unwrap()
+        .into_iter()
+        .map(|d| (d.id, d))
+        .collect();
+    let kept = report.droplets["kept"];
+    assert_eq!(droplets.keys().collect::<Vec<_>>(), vec![&kept]);
+    assert!(float_epsilon_equal(droplets[&kept].composition["dye"], 0.5));
+
+    assert_matches!(
+        p.wait_protocol(report.handle),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
+#[test]
+fn submit_protocol_all_or_nothing() {
+    let man = manager_from_rect(4, 4);
+    let p = man.get_new_process("test");
+
+    // the second droplet is too big for the board, so neither gets made
+    let big = Location { y: 5, x: 5 };
+    let step = |output: &str, dimensions| Step::Create {
+        output: output.into(),
+        volume: 1.0,
+        location: None,
+        dimensions: Some(dimensions),
+        substance: None,
+    };
+    let steps = vec![step("small", Location { y: 1, x: 1 }), step("big", big)];
+    assert!(p.submit_protocol(Protocol { steps }).is_err());
+    assert!(info_dict(&p).is_empty());
+
+    // and the board is still usable afterwards
+    p.create(None, 1.0, None).unwrap();
+    assert_eq!(info_dict(&p).len(), 1);
+}
+
+#[test]
+fn submit_protocol_bad_split_ratios() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    let steps: Vec<Step> = serde_json::from_str(
+        r#"[
+            { "op": "create", "output": "a", "volume": 1.0 },
+            { "op": "split", "input": "a", "outputs": ["x", "y"],
+              "ratios": [1.0, 1.0, 1.0] }
+        ]"#,
+    )
+    .unwrap();
+
+    assert_matches!(
+        p.submit_protocol(Protocol { steps }),
+        Err(PuddleError::InvalidArgument(_))
+    );
+    assert!(info_dict(&p).is_empty());
+}
+
+#[test]
+fn run_protocol_in_order() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    let steps: Vec<Step> = serde_json::from_str(
+        r#"[
+            { "op": "create", "output": "a", "volume": 1.0 },
+            { "op": "create", "output": "b", "volume": 1.0 },
+            { "op": "mix", "inputs": ["a", "b"], "output": "ab" },
+            { "op": "split", "input": "ab", "outputs": ["x", "y"] }
+        ]"#,
+    )
+    .unwrap();
+
+    let names = p.run_protocol(&Protocol { steps }).unwrap();
+    let droplets = info_dict(&p);
+    assert_eq!(droplets.len(), 2);
+    assert_eq!(droplets[&names["x"]].volume, 1.0);
+    assert_eq!(droplets[&names["y"]].volume, 1.0);
+
+    // a listed protocol can't use a droplet before it's made
+    let steps = vec![Step::Mix {
+        inputs: vec!["c".into(), "d".into()],
+        output: "cd".into(),
+        options: MixOptions::default(),
+    }];
+    assert_matches!(
+        p.run_protocol(&Protocol { steps }),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
+#[test]
+fn dilute_needs_peripherals() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    let sample = p.create(None, 1.0, None).unwrap();
+    assert_matches!(
+        p.dilute(sample, "buffer", 0.25, 0.1),
+        Err(PuddleError::MissingPeripheral(_))
+    );
+    assert_matches!(
+        p.dilute(sample, "buffer", 2.0, 0.1),
+        Err(PuddleError::InvalidArgument(_))
+    );
+}
+
 #[test]
 fn combine_into() {
     let man = manager_from_rect(10, 10);
@@ -388,8 +1318,44 @@ fn combine_into() {
     let ab = p.combine_into(a, b).unwrap();
     let cd = p.combine_into(d, c).unwrap();
 
+    // b and c are off to the side, so that's where they join from
+    let droplets = info_dict(&p);
+    let x1 = &Location { y: 0, x: 1 };
+    assert_eq!(droplets[&ab].location, loc_a);
+    assert_eq!(droplets[&ab].dimensions, Location { y: 1, x: 2 });
+    assert_eq!(droplets[&cd].location, &loc_d - x1);
+    assert_eq!(droplets[&cd].dimensions, Location { y: 1, x: 2 });
+
+    // e is right above f
+    let loc_e = Location { y: 5, x: 5 };
+    let e = p.create(Some(loc_e), 1.0, None).unwrap();
+    let loc_f = Location { y: 0, x: 5 };
+    let f = p.create(Some(loc_f), 1.0, None).unwrap();
+
+    let ef = p.combine_into(e, f).unwrap();
+
     let droplets = info_dict(&p);
     let y1 = &Location { y: 1, x: 0 };
-    assert_eq!(droplets[&ab].location, &loc_a - y1);
-    assert_eq!(droplets[&cd].location, &loc_d - y1);
+    assert_eq!(droplets[&ef].location, &loc_e - y1);
+    assert_eq!(droplets[&ef].dimensions, Location { y: 2, x: 1 });
+}
+
+#[test]
+fn combine_into_blocked_side() {
+    let man = manager_from_rect(10, 10);
+    let p = man.get_new_process("test");
+
+    // g is right above where h would join e, so it has to come another way
+    let loc_e = Location { y: 5, x: 5 };
+    let e = p.create(Some(loc_e), 1.0, None).unwrap();
+    let loc_g = Location { y: 3, x: 5 };
+    let g = p.create(Some(loc_g), 1.0, None).unwrap();
+    let loc_h = Location { y: 0, x: 5 };
+    let h = p.create(Some(loc_h), 1.0, None).unwrap();
+
+    let eh = p.combine_into(e, h).unwrap();
+
+    let droplets = info_dict(&p);
+    assert_eq!(droplets[&g].location, loc_g);
+    assert!(droplets[&eh].location.y >= loc_e.y);
 }
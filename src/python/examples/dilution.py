@@ -1,6 +1,6 @@
 import networkx as nx
 
-from puddle import mk_session, Droplet, project_path
+from puddle import mk_session, project_path
 
 min_volume = 1.0
 max_volume = 4.0
@@ -49,38 +49,32 @@ def plan(low, high, target, epsilon=0.01):
 def dilute(session, d_low_factory, d_high_factory, c_target,
            epsilon = 0.001):
 
-    def dilute_rec(d0, d1):
-        session._flush()
-        con0 = d0.concentration
-        con1 = d1.concentration
+    # the server keeps track of what's in each droplet
+    def conc(d):
+        return d.concentration('sample')
 
-        assert d0.concentration <= d1.concentration
+    def dilute_rec(d0, d1):
+        con0 = conc(d0)
+        con1 = conc(d1)
 
-        # print(len(session.arch.droplets),
-        #       d0.concentration, d1.concentration, c_target)
+        assert con0 <= con1
 
-        if abs(d0.concentration - c_target) < epsilon:
-            # session.arch.remove_droplet(d1)
+        if abs(con0 - c_target) < epsilon:
             return d0
-        if abs(d1.concentration - c_target) < epsilon:
-            # session.arch.remove_droplet(d0)
+        if abs(con1 - c_target) < epsilon:
             return d1
 
-        session._flush()
-
         d = session.mix(d0, d1)
 
         # FIXME account for volume when picking
         da, db = session.split(d)
-        session._flush()
         d_next = da
-        # session.arch.remove_droplet(db)
-        # print(d_next.concentration)
+        con_next = conc(d_next)
 
-        if abs(d_next.concentration - c_target) < epsilon:
+        if abs(con_next - c_target) < epsilon:
             return d_next
 
-        if d_next.concentration < c_target:
+        if con_next < c_target:
             d1_again = dilute(session, d_low_factory, d_high_factory,
                               con1, epsilon)
             return dilute_rec(d_next, d1_again)
@@ -92,49 +86,20 @@ def dilute(session, d_low_factory, d_high_factory, c_target,
     return dilute_rec(d_low_factory(), d_high_factory())
 
 
-class VolConcDroplet(Droplet):
-
-    def __init__(self, *args, **kwargs):
-        self.volume = kwargs.pop('volume', 1)
-        self.concentration = kwargs.pop('concentration', 0)
-        print(kwargs)
-        super().__init__(*args, **kwargs)
-
-    def mix(self, other):
-        result = super().mix(other)
-        result.volume = self.volume + other.volume
-        mass1 = self.concentration * self.volume
-        mass2 = other.concentration * other.volume
-        result.concentration = (mass1 + mass2) / result.volume
-        return result
-
-    def split(self):
-        d1, d2 = super().split()
-        d1.volume = self.volume / 2
-        d2.volume = self.volume / 2
-        d1.concentration = self.concentration
-        d2.concentration = self.concentration
-        return d1, d2
-
-
 arch_path = project_path('tests/arches/arch-big.json')
 with mk_session(arch_path) as session:
     # FIXME this needs arch big for now because place and route is bad
     # also, you just can't do that many iterations
 
-    c_low = 0
-    c_high = 1
-
     c_target = .37
     eps = 0.1
 
+    # buffer has none of the sample in it, so it doesn't need a label
     def d_low_factory():
         return session.create(
             location = None,
             volume = 1,
             dimensions = None,
-            concentration = c_low,
-            droplet_class = VolConcDroplet,
         )
 
     def d_high_factory():
@@ -142,11 +107,10 @@ with mk_session(arch_path) as session:
             location = None,
             volume = 1,
             dimensions = None,
-            concentration = c_high,
-            droplet_class = VolConcDroplet,
+            substance = 'sample',
         )
 
     d = dilute(session, d_low_factory, d_high_factory,
                 c_target, epsilon = eps)
 
-    assert abs(d.concentration - c_target) < eps
+    assert abs(d.concentration('sample') - c_target) < eps
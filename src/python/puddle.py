@@ -42,32 +42,106 @@ class Droplet:
         result_id = self.session._rpc("move", self.session.pid, self._use(), to_location(loc))
         self._renew(result_id)
 
-    def mix(self, other):
+    def reshape(self, dimensions):
+        result_id = self.session._rpc("reshape", self.session.pid, self._use(),
+                                      to_location(dimensions))
+        self._renew(result_id)
+
+    def mix(self, other, **mix_options):
         assert isinstance(other, type(self))
-        result_id = self.session._rpc("mix", self.session.pid, self._use(), other._use())
+        args = [self.session.pid, self._use(), other._use()]
+        args += mix_options_arg(**mix_options)
+        result_id = self.session._rpc("mix", *args)
         return self._new(result_id)
 
+    def agitate(self, **mix_options):
+        args = [self.session.pid, self._use()]
+        args += mix_options_arg(**mix_options)
+        result_id = self.session._rpc("agitate", *args)
+        self._renew(result_id)
+
+    def incubate(self, seconds):
+        result_id = self.session._rpc("incubate", self.session.pid, self._use(), seconds)
+        self._renew(result_id)
+
+    def thermocycle(self, stages, cycles):
+        """Runs the (temperature, seconds) stages `cycles` times, and returns
+        the temperature measured after each stage, one list per cycle."""
+        stages = [{'temperature': t, 'seconds': s} for t, s in stages]
+        report = self.session._rpc("thermocycle", self.session.pid, self._use(),
+                                   stages, cycles)
+        self._renew(report['droplet'])
+        return report['temperatures']
+
+    def sense(self, sensor):
+        """Takes the droplet to the named sensor, and returns the reading, a
+        dict with the 'kind' of reading and its value."""
+        report = self.session._rpc("sense", self.session.pid, self._use(), sensor)
+        self._renew(report['droplet'])
+        return report['reading']
+
     def combine_into(self, other):
         assert isinstance(other, type(self))
         result_id = self.session._rpc("combine_into", self.session.pid, self._use(), other._use())
         return self._new(result_id)
 
-    def split(self):
-        id1, id2 = self.session._rpc("split", self.session.pid, self._use())
-        return (self._new(id1), self._new(id2))
+    def split(self, ratios=None, orientation=None):
+        args = [self.session.pid, self._use()]
+        # leave off the options if they're all default, the server has them too
+        options = {}
+        if ratios is not None:
+            options['ratios'] = ratios
+        if orientation is not None:
+            options['orientation'] = orientation
+        if options:
+            args.append(options)
+        ids = self.session._rpc("split", *args)
+        return tuple(self._new(i) for i in ids)
 
     def output(self, substance):
         self.session._rpc("output", self.session.pid, substance, self._use())
 
+    def discard(self):
+        self.session._rpc("discard", self.session.pid, self._use())
+
+    def dilute(self, buffer, target_fraction, tolerance):
+        result_id = self.session._rpc("dilute", self.session.pid, self._use(),
+                                      buffer, target_fraction, tolerance)
+        return self._new(result_id)
+
     def volume(self):
         droplets = self.session.droplets()
         return droplets[self._id]['volume']
 
+    def composition(self):
+        droplets = self.session.droplets()
+        return droplets[self._id]['composition']
+
+    def concentration(self, substance):
+        d = self.session.droplets()[self._id]
+        return d['composition'].get(substance, 0) / d['volume']
+
 
 def to_location(loc):
     return {'y': loc[0], 'x': loc[1]}
 
 
+def mix_options_arg(pattern=None, loops=None, seconds=None):
+    """The optional last argument to mix and agitate.
+
+    pattern is one of 'square', 'shuttle', 'figure_eight' or 'split_merge'.
+    Give loops or seconds, or neither to let the server decide.
+    """
+    options = {}
+    if pattern is not None:
+        options['pattern'] = pattern
+    if loops is not None:
+        options['loops'] = loops
+    if seconds is not None:
+        options['seconds'] = seconds
+    return [options] if options else []
+
+
 class RPCError(Exception):
     pass
 
@@ -77,9 +151,70 @@ class RequestError(Exception):
 
 
 class SessionError(Exception):
+    """An error from the server. `code` says what kind, and `data` has the
+    details, like the droplets involved."""
+
+    def __init__(self, error):
+        super().__init__(error)
+        self.code = error.get('code')
+        self.message = error.get('message')
+        self.data = error.get('data')
+
+
+class PlanError(SessionError):
     pass
 
 
+class PlaceError(PlanError):
+    pass
+
+
+class RouteError(PlanError):
+    pass
+
+
+class InvalidPlanError(PlanError):
+    pass
+
+
+class ProcessKilledError(PlanError):
+    pass
+
+
+class NonExistentDropletError(SessionError):
+    pass
+
+
+class DropletNotOwnedError(SessionError):
+    pass
+
+
+class NonExistentProcessError(SessionError):
+    pass
+
+
+class InvalidArgumentError(SessionError):
+    pass
+
+
+class MissingPeripheralError(SessionError):
+    pass
+
+
+# the server's error codes, see error_code in rpc.rs
+error_classes = {
+    -32000: PlaceError,
+    -32001: RouteError,
+    -32002: InvalidPlanError,
+    -32003: ProcessKilledError,
+    -32010: NonExistentDropletError,
+    -32011: DropletNotOwnedError,
+    -32012: NonExistentProcessError,
+    -32013: InvalidArgumentError,
+    -32014: MissingPeripheralError,
+}
+
+
 class DropletConsumed(Exception):
     pass
 
@@ -147,7 +282,8 @@ class Session:
         if 'result' in resp_json:
             return resp_json['result']
         else:
-            raise SessionError(resp_json['error'])
+            error = resp_json['error']
+            raise error_classes.get(error.get('code'), SessionError)(error)
 
     def prelude(self, starting_dict = None):
         if starting_dict is None:
@@ -170,18 +306,44 @@ class Session:
     def _flush(self):
         self._rpc("flush", self.pid)
 
-    def close(self):
-        self._rpc("close_process", self.pid)
+    def close(self, discard=False):
+        # leave the flag off unless it's set, the server treats it as optional
+        args = [self.pid]
+        if discard:
+            args.append(True)
+        self._rpc("close_process", *args)
+
+    def kill(self, pid=None):
+        """Stops a process right away, this session's own by default,
+        aborting its commands and discarding its droplets."""
+        self._rpc("kill_process", self.pid if pid is None else pid)
 
-    def create(self, location, volume=1.0, dimensions=(1,1), **kwargs):
+    def create(self, location, volume=1.0, dimensions=(1,1), substance=None, **kwargs):
         droplet_class = kwargs.pop('droplet_class', Droplet)
-        result_id = self._rpc("create", self.pid, to_location(location) if location else None, volume, to_location(dimensions) if dimensions else None)
+        args = [to_location(location) if location else None, volume, to_location(dimensions) if dimensions else None]
+        # the label is optional on the server side, so leave it off if there is none
+        if substance is not None:
+            args.append(substance)
+        result_id = self._rpc("create", self.pid, *args)
         return droplet_class(self, result_id, **kwargs, i_know_what_im_doing=True)
 
     def input(self, substance, volume, dimensions, **kwargs):
         result_id = self._rpc("input", self.pid, substance, volume, dimensions)
         return Droplet(self, result_id, **kwargs, i_know_what_im_doing=True)
 
+    def submit_protocol(self, steps):
+        """Plans a whole protocol at once, or none of it if any step fails.
+
+        Each step is a dict with an 'op' and the names of the droplets it
+        uses and makes. Returns the droplet ids by name, and a handle to pass
+        to wait_protocol.
+        """
+        report = self._rpc("submit_protocol", self.pid, {'steps': steps})
+        return report['droplets'], report['handle']
+
+    def wait_protocol(self, handle):
+        return self._rpc("wait_protocol", self.pid, handle)
+
     def heat(self, droplet, temp, seconds, **kwargs):
         result_id = self._rpc("heat", self.pid, droplet._use(), temp, seconds)
         return Droplet(self, result_id, **kwargs, i_know_what_im_doing=True)
@@ -189,14 +351,28 @@ class Session:
     # just call the droplet methods
     def move (self, droplet, *args, **kwargs): return droplet.move (*args, **kwargs)
 
+    def reshape(self, droplet, *args, **kwargs): return droplet.reshape(*args, **kwargs)
+
     def mix  (self, droplet, *args, **kwargs): return droplet.mix  (*args, **kwargs)
 
+    def agitate(self, droplet, *args, **kwargs): return droplet.agitate(*args, **kwargs)
+
+    def thermocycle(self, droplet, *args, **kwargs): return droplet.thermocycle(*args, **kwargs)
+
+    def sense(self, droplet, *args, **kwargs): return droplet.sense(*args, **kwargs)
+
+    def incubate(self, droplet, *args, **kwargs): return droplet.incubate(*args, **kwargs)
+
     def combine_into  (self, droplet, *args, **kwargs): return droplet.combine_into  (*args, **kwargs)
 
     def split(self, droplet, *args, **kwargs): return droplet.split(*args, **kwargs)
 
+    def dilute(self, droplet, *args, **kwargs): return droplet.dilute(*args, **kwargs)
+
     def output (self, substance, droplet, *args, **kwargs): return droplet.output (substance, *args, **kwargs)
 
+    def discard(self, droplet, *args, **kwargs): return droplet.discard(*args, **kwargs)
+
 
 def call(cmd):
     args = shlex.split(cmd)
@@ -44,3 +44,106 @@ def test_volume(session):
 
     assert session.droplets()[a_split._id]['volume'] == 1.5
     assert session.droplets()[b_split._id]['volume'] == 1.5
+
+
+def test_composition(session):
+
+    a = session.create(None, 1.0, (1,1), substance='dye')
+    b = session.create(None, 3.0, (1,1), substance='water')
+
+    ab = session.mix(a, b)
+    (a_split, b_split) = session.split(ab)
+
+    assert a_split.composition() == {'dye': 0.5, 'water': 1.5}
+    assert b_split.concentration('dye') == 0.25
+
+
+def test_split_ratios(session):
+
+    a = session.create(None, 2.0, (1,4))
+
+    (small, big) = a.split(ratios=[1, 3])
+    assert small.volume() == 0.5
+    assert big.volume() == 1.5
+
+    (b, c) = big.split(orientation='vertical')
+    assert b.volume() == 0.75
+
+
+def test_mix_pattern(session):
+
+    a = session.create(None, 1.0, (1,1))
+    b = session.create(None, 2.0, (1,1))
+
+    ab = a.mix(b, pattern='shuttle', loops=3)
+    ab.agitate(pattern='figure_eight', seconds=0.5)
+
+    assert ab.volume() == 3.0
+
+
+def test_incubate(session):
+
+    a = session.create((1,1), 1.0, (1,1))
+    a.incubate(0.1)
+
+    assert a.volume() == 1.0
+
+
+def test_discard(session):
+
+    a = session.create(None, 1.0, (1,1))
+    b = session.create(None, 1.0, (1,1))
+    a.discard()
+
+    assert list(session.droplets()) == [b._id]
+
+
+def test_reshape(session):
+
+    a = session.create(None, 2.0, (1,2))
+    a.reshape((2,1))
+
+    assert a.volume() == 2.0
+    assert session.droplets()[a._id]['dimensions'] == {'y': 2, 'x': 1}
+
+
+def test_submit_protocol(session):
+
+    steps = [
+        {'op': 'create', 'output': 'a', 'volume': 1.0},
+        {'op': 'create', 'output': 'b', 'volume': 1.0},
+        {'op': 'mix', 'inputs': ['a', 'b'], 'output': 'ab'},
+    ]
+    ids, handle = session.submit_protocol(steps)
+    droplets = session.wait_protocol(handle)
+
+    assert [d['id'] for d in droplets] == [ids['ab']]
+    assert droplets[0]['volume'] == 2.0
+
+
+def test_kill(session):
+
+    other = puddle.Session(session.endpoint, 'other')
+    other.create(None, 1.0, (1,1))
+    a = session.create(None, 1.0, (1,1))
+
+    session.kill(other.pid)
+
+    assert list(session.droplets()) == [a._id]
+    with pytest.raises(puddle.NonExistentProcessError):
+        other.droplets()
+
+
+def test_errors(session):
+
+    with pytest.raises(puddle.PlaceError) as exn:
+        session.create(None, 1.0, (100,100))
+    assert exn.value.code == -32000
+
+    a = session.create(None, 1.0, (1,1))
+    with pytest.raises(puddle.InvalidArgumentError):
+        a.incubate(-1.0)
+
+    with pytest.raises(puddle.NonExistentProcessError) as exn:
+        session._rpc("flush", session.pid + 100)
+    assert exn.value.data == {'process_id': session.pid + 100}